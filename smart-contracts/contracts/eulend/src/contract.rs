@@ -1,30 +1,413 @@
+use crate::dex::{DexExecuteMsg, SwapStep};
+use crate::error::ContractError;
+use crate::math::{self, Rounding};
+use crate::msg::{
+    AccountResponse, BadDebtResponse, BorrowAssetConfig, ConfigResponse, ExecuteMsg, FeederPrice,
+    FeederPricesResponse, InstantiateMsg, LiquidationResponse, MarketResponse, MaxAmountResponse,
+    PriceResponse, QueryMsg, SimulationResponse, StakerResponse,
+};
+use crate::oracle;
+use crate::simulation::SimulatedStorage;
+use crate::state::{
+    Account, AssetPriceSource, BadDebt, BorrowAsset, Collateral, CollateralAsset, Config, Debt,
+    DexConfig, EModeCategory, FeeConfig, LiquidationConfig, LiquidationStart, LiquidityProvider,
+    Observations, OracleConfig, PendingLiquidation, PendingSwap, Pool, PoolUtilization, Price,
+    PriceFeed, PriceSource, SafetyModule, SafetyModuleConfig, Staker, UtilizationThresholds,
+    ACCOUNTS, BAD_DEBTS, BORROW_ASSETS, COLLATERALS, COLLATERAL_ASSETS, CONFIG, DEBTS,
+    EMODE_CATEGORIES, FEEDER_PRICES, INSURANCE_FUNDS, ISOLATED_ACCOUNTS, ISOLATED_DEBT,
+    LIQUIDATION_STARTS, LIQUIDITY_PROVIDERS, OBSERVATIONS, PENDING_LIQUIDATION, PENDING_SWAP,
+    POOLS, PRICES, QUARANTINED_PRICES, SAFETY_MODULES, STAKERS, TOTAL_BAD_DEBT, TOTAL_COLLATERALS,
+};
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    to_json_binary, Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult, 
-    Uint128, Decimal, StdError, Coin, BankMsg, Addr,
+    to_json_binary, Addr, BankMsg, Binary, Coin, CosmosMsg, Decimal, Deps, DepsMut, Env, Event,
+    MessageInfo, Order, Reply, Response, StdError, StdResult, Storage, SubMsg, Uint128, WasmMsg,
 };
 use cw2::set_contract_version;
-use cw_storage_plus::{Item, Map};
-use crate::error::ContractError;
-use crate::msg::{ExecuteMsg, InstantiateMsg, QueryMsg};
-use crate::state::{Account, ACCOUNTS, COLLATERAL, ESCROW, LIQUIDITY_PROVIDERS, LiquidityProvider, Collateral, Pool, POOLS, PoolUtilization};
+use liquidation_queue::msg::{
+    ConfigResponse as QueueConfigResponse, ExecuteMsg as QueueExecuteMsg, QueryMsg as QueueQueryMsg,
+};
 
 // version info for migration info
 const CONTRACT_NAME: &str = "crates.io:backend";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// highest number of decimals an asset or price may use
+const MAX_DECIMALS: u8 = 18;
+// longest safety module cooldown or unstake window, which keeps their deadlines far from overflow
+const MAX_UNSTAKE_PERIOD: u64 = math::SECONDS_PER_YEAR;
 
+const QUEUE_LIQUIDATION_REPLY_ID: u64 = 1;
+const LEVERAGE_REPLY_ID: u64 = 2;
+const COLLATERAL_SWAP_REPLY_ID: u64 = 3;
 
 #[cfg_attr(not(feature = "library"), entry_point)]
 pub fn instantiate(
     deps: DepsMut,
-    _env: Env,
+    env: Env,
     _info: MessageInfo,
-    _msg: InstantiateMsg,
-) -> StdResult<Response> {
+    msg: InstantiateMsg,
+) -> Result<Response, ContractError> {
     set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
-    Ok(Response::new().add_attribute("method", "instantiate"))
+
+    let config = Config {
+        owner: deps.api.addr_validate(&msg.owner)?,
+        oracle: validate_oracle(deps.as_ref(), msg.oracle)?,
+        fees: validate_fees(msg.fees)?,
+        utilization_thresholds: validate_utilization_thresholds(msg.utilization_thresholds)?,
+        liquidation: validate_liquidation(deps.as_ref(), msg.liquidation)?,
+        safety_module: validate_safety_module(msg.safety_module)?,
+        dex: msg
+            .dex
+            .map(|dex| validate_dex(deps.as_ref(), dex))
+            .transpose()?,
+    };
+    CONFIG.save(deps.storage, &config)?;
+
+    if msg.borrow_assets.is_empty() {
+        return Err(invalid_config("at least one borrow asset is required"));
+    }
+    for asset in msg.borrow_assets {
+        if BORROW_ASSETS.has(deps.storage, &asset.denom) {
+            return Err(invalid_config("borrow assets must be unique"));
+        }
+        save_borrow_asset(deps.storage, &env, asset)?;
+    }
+
+    for asset in msg.collateral_assets {
+        if COLLATERAL_ASSETS.has(deps.storage, &asset.denom) {
+            return Err(invalid_config("collateral assets must be unique"));
+        }
+        validate_collateral_asset(&asset)?;
+        COLLATERAL_ASSETS.save(deps.storage, &asset.denom, &asset)?;
+    }
+
+    Ok(Response::new()
+        .add_attribute("method", "instantiate")
+        .add_attribute("owner", config.owner))
+}
+
+fn invalid_config(reason: &str) -> ContractError {
+    ContractError::InvalidConfig {
+        reason: reason.to_string(),
+    }
+}
+
+fn validate_borrow_rate(borrow_rate: Decimal) -> Result<(), ContractError> {
+    if borrow_rate > Decimal::one() {
+        return Err(invalid_config("borrow rate cannot exceed 100%"));
+    }
+    Ok(())
+}
+
+// Registers a borrow asset, opening its pool or updating the rate of the existing one
+fn save_borrow_asset(
+    storage: &mut dyn Storage,
+    env: &Env,
+    asset: BorrowAssetConfig,
+) -> Result<(), ContractError> {
+    if asset.denom.is_empty() || asset.decimals > MAX_DECIMALS {
+        return Err(invalid_config(
+            "borrow asset needs a denom and at most 18 decimals",
+        ));
+    }
+    validate_borrow_rate(asset.borrow_rate)?;
+    if let Some(peg) = &asset.peg {
+        if peg.target.is_zero() || peg.max_deviation >= Decimal::one() {
+            return Err(invalid_config(
+                "a peg needs a target and a deviation below 100%",
+            ));
+        }
+    }
+
+    let mut pool = match BORROW_ASSETS.may_load(storage, &asset.denom)? {
+        // debts and shares are denominated in base units, so those cannot be redefined
+        Some(existing) if existing.decimals != asset.decimals => {
+            return Err(invalid_config("borrow asset decimals cannot change"));
+        }
+        // settle interest under the old rate before changing it
+        Some(_) => load_pool(storage, env, &asset.denom)?,
+        None => Pool {
+            id: asset.denom.clone(),
+            lend_token: asset.denom.clone(),
+            borrow_token: asset.denom.clone(),
+            utilization: PoolUtilization::Low,
+            utilization_rate: Decimal::zero(),
+            total_liquidity: Uint128::zero(),
+            total_shares: Uint128::zero(),
+            total_borrowed: Uint128::zero(),
+            total_reserves: Uint128::zero(),
+            borrow_rate: asset.borrow_rate,
+            borrow_index: Decimal::one(),
+            last_accrual: env.block.time.seconds(),
+        },
+    };
+    pool.borrow_rate = asset.borrow_rate;
+    save_pool(storage, &mut pool)?;
+
+    BORROW_ASSETS.save(
+        storage,
+        &asset.denom,
+        &BorrowAsset {
+            denom: asset.denom.clone(),
+            decimals: asset.decimals,
+            supply_cap: asset.supply_cap,
+            borrow_cap: asset.borrow_cap,
+            isolation_borrowable: asset.isolation_borrowable,
+            peg: asset.peg,
+        },
+    )?;
+    Ok(())
+}
+
+fn validate_collateral_asset(asset: &CollateralAsset) -> Result<(), ContractError> {
+    if asset.denom.is_empty() || asset.decimals > MAX_DECIMALS {
+        return Err(invalid_config(
+            "collateral asset needs a denom and at most 18 decimals",
+        ));
+    }
+    validate_risk_params(
+        asset.max_ltv,
+        asset.liquidation_threshold,
+        asset.liquidation_bonus,
+    )
+}
+
+// Updates a collateral asset's parameters or registers a new one
+fn save_collateral_asset(
+    storage: &mut dyn Storage,
+    asset: CollateralAsset,
+) -> Result<(), ContractError> {
+    validate_collateral_asset(&asset)?;
+    if let Some(existing) = COLLATERAL_ASSETS.may_load(storage, &asset.denom)? {
+        // deposits are held in base units, so those cannot be redefined
+        if existing.decimals != asset.decimals {
+            return Err(invalid_config("collateral asset decimals cannot change"));
+        }
+        // debt is counted against a ceiling only while it is taken out in isolation, so
+        // isolating deposits after the fact or releasing them would misstate it
+        let deposited = TOTAL_COLLATERALS
+            .may_load(storage, &asset.denom)?
+            .unwrap_or_default();
+        if existing.debt_ceiling.is_some() != asset.debt_ceiling.is_some() && !deposited.is_zero() {
+            return Err(invalid_config(
+                "isolation of a collateral asset with deposits cannot change",
+            ));
+        }
+    }
+    COLLATERAL_ASSETS.save(storage, &asset.denom, &asset)?;
+    Ok(())
+}
+
+fn validate_risk_params(
+    max_ltv: Decimal,
+    liquidation_threshold: Decimal,
+    liquidation_bonus: Decimal,
+) -> Result<(), ContractError> {
+    if max_ltv.is_zero()
+        || max_ltv >= liquidation_threshold
+        || liquidation_threshold >= Decimal::one()
+    {
+        return Err(invalid_config(
+            "collateral needs 0 < max_ltv < liquidation_threshold < 1",
+        ));
+    }
+    // a liquidation must not pay out more collateral than there is debt coverage for
+    if liquidation_threshold * (Decimal::one() + liquidation_bonus) > Decimal::one() {
+        return Err(invalid_config(
+            "liquidation bonus too high for the threshold",
+        ));
+    }
+    Ok(())
+}
+
+// Categories may only list assets the market already knows about
+fn validate_emode_category(
+    storage: &dyn Storage,
+    category: &EModeCategory,
+) -> Result<(), ContractError> {
+    validate_risk_params(
+        category.max_ltv,
+        category.liquidation_threshold,
+        category.liquidation_bonus,
+    )?;
+    if category.assets.is_empty() {
+        return Err(invalid_config("e-mode category needs assets"));
+    }
+    for denom in &category.assets {
+        if !COLLATERAL_ASSETS.has(storage, denom) && !BORROW_ASSETS.has(storage, denom) {
+            return Err(invalid_config("e-mode category lists an unknown asset"));
+        }
+    }
+    Ok(())
+}
+
+fn validate_oracle(deps: Deps, oracle: OracleConfig) -> Result<OracleConfig, ContractError> {
+    if oracle.price_decimals > MAX_DECIMALS {
+        return Err(invalid_config("price decimals cannot exceed 18"));
+    }
+    if oracle.max_age == 0 {
+        return Err(invalid_config("prices need a max age"));
+    }
+    if oracle.max_observations == 0 {
+        return Err(invalid_config(
+            "at least one price observation has to be kept",
+        ));
+    }
+    let feeders = validate_feeders(deps, &oracle.feeders, oracle.quorum)?;
+    let mut feeds: Vec<PriceFeed> = vec![];
+    for feed in oracle.feeds {
+        if feeds.iter().any(|listed| listed.denom == feed.denom) {
+            return Err(invalid_config("duplicate price feed"));
+        }
+        feeds.push(PriceFeed {
+            feeders: validate_feeders(deps, &feed.feeders, feed.quorum)?,
+            ..feed
+        });
+    }
+    let mut sources: Vec<AssetPriceSource> = vec![];
+    for asset in &oracle.sources {
+        if sources.iter().any(|listed| listed.denom == asset.denom) {
+            return Err(invalid_config("duplicate price source"));
+        }
+        let source = match &asset.source {
+            PriceSource::Pyth {
+                contract,
+                price_feed_id,
+            } => PriceSource::Pyth {
+                contract: deps.api.addr_validate(contract.as_str())?,
+                price_feed_id: price_feed_id.clone(),
+            },
+            PriceSource::Band { contract, symbol } => PriceSource::Band {
+                contract: deps.api.addr_validate(contract.as_str())?,
+                symbol: symbol.clone(),
+            },
+            PriceSource::DexTwap {
+                contract,
+                quote_denom,
+                window,
+            } => {
+                // the quote needs a price of its own that does not come from a pool again
+                let quoted_by_pool = oracle.sources.iter().any(|quote| {
+                    &quote.denom == quote_denom
+                        && matches!(quote.source, PriceSource::DexTwap { .. })
+                });
+                if *window == 0 || quoted_by_pool {
+                    return Err(invalid_config(
+                        "a twap needs a window and a quote asset priced outside a pool",
+                    ));
+                }
+                PriceSource::DexTwap {
+                    contract: deps.api.addr_validate(contract.as_str())?,
+                    quote_denom: quote_denom.clone(),
+                    window: *window,
+                }
+            }
+        };
+        sources.push(AssetPriceSource {
+            denom: asset.denom.clone(),
+            source,
+        });
+    }
+    Ok(OracleConfig {
+        feeders,
+        feeds,
+        sources,
+        ..oracle
+    })
+}
+
+// Feeders have to be distinct and enough of them to reach the quorum
+fn validate_feeders(deps: Deps, feeders: &[Addr], quorum: u32) -> Result<Vec<Addr>, ContractError> {
+    let mut validated: Vec<Addr> = Vec::with_capacity(feeders.len());
+    for feeder in feeders {
+        let feeder = deps.api.addr_validate(feeder.as_str())?;
+        if validated.contains(&feeder) {
+            return Err(invalid_config("duplicate feeder"));
+        }
+        validated.push(feeder);
+    }
+    if quorum == 0 || quorum as usize > validated.len() {
+        return Err(invalid_config(
+            "quorum has to be between one and the number of feeders",
+        ));
+    }
+    Ok(validated)
+}
+
+fn validate_utilization_thresholds(
+    thresholds: UtilizationThresholds,
+) -> Result<UtilizationThresholds, ContractError> {
+    if thresholds.medium.is_zero()
+        || thresholds.medium >= thresholds.high
+        || thresholds.high > Decimal::one()
+    {
+        return Err(invalid_config(
+            "utilization thresholds need 0 < medium < high <= 1",
+        ));
+    }
+    Ok(thresholds)
+}
+
+fn validate_liquidation(
+    deps: Deps,
+    liquidation: LiquidationConfig,
+) -> Result<LiquidationConfig, ContractError> {
+    if liquidation.auction_duration == 0 {
+        return Err(invalid_config("liquidation auction needs a duration"));
+    }
+    if liquidation.max_queue_slippage >= Decimal::one() {
+        return Err(invalid_config("max queue slippage has to be below 100%"));
+    }
+    Ok(LiquidationConfig {
+        queue: liquidation
+            .queue
+            .map(|queue| deps.api.addr_validate(queue.as_str()))
+            .transpose()?,
+        ..liquidation
+    })
+}
+
+fn validate_safety_module(
+    safety_module: SafetyModuleConfig,
+) -> Result<SafetyModuleConfig, ContractError> {
+    if safety_module.unstake_window == 0 {
+        return Err(invalid_config("unstake window cannot be empty"));
+    }
+    if safety_module.cooldown > MAX_UNSTAKE_PERIOD
+        || safety_module.unstake_window > MAX_UNSTAKE_PERIOD
+    {
+        return Err(invalid_config(
+            "cooldown and unstake window cannot exceed a year",
+        ));
+    }
+    if safety_module.max_slash >= Decimal::one() {
+        return Err(invalid_config("stakers cannot be slashed entirely"));
+    }
+    if safety_module.reserve_share > Decimal::one() {
+        return Err(invalid_config(
+            "stakers' share of reserves cannot exceed 100%",
+        ));
+    }
+    Ok(safety_module)
+}
+
+fn validate_dex(deps: Deps, dex: DexConfig) -> Result<DexConfig, ContractError> {
+    if dex.max_slippage >= Decimal::one() {
+        return Err(invalid_config("max slippage has to be below 100%"));
+    }
+    Ok(DexConfig {
+        contract: deps.api.addr_validate(dex.contract.as_str())?,
+        ..dex
+    })
+}
+
+fn validate_fees(fees: FeeConfig) -> Result<FeeConfig, ContractError> {
+    if fees.reserve_factor > Decimal::one() {
+        return Err(invalid_config("reserve factor cannot exceed 100%"));
+    }
+    Ok(fees)
 }
 
 #[cfg_attr(not(feature = "library"), entry_point)]
@@ -36,42 +419,128 @@ pub fn execute(
 ) -> Result<Response, ContractError> {
     match msg {
         ExecuteMsg::CreateAccount {} => execute::create_account(deps, info),
-        ExecuteMsg::Borrow { borrow_amount,collateral_denom} => {
-            execute::borrow(deps, env, info, borrow_amount, collateral_denom)
-        },
-        ExecuteMsg::Repay { withdraw_denom, withdraw_amount } => {
-            execute::repay(deps, env, info, withdraw_denom, withdraw_amount)
-        },
-        ExecuteMsg::DeleteAccount {} => {
-            execute::delete_account(deps, info)
-        },
-        ExecuteMsg::ProvideLiquidity {} => {
-            execute::provide_liquidity(deps, env, info)
+        ExecuteMsg::Borrow {
+            borrow_denom,
+            borrow_amount,
+            collateral_denom,
+        } => execute::borrow(
+            deps,
+            env,
+            info,
+            borrow_denom,
+            borrow_amount,
+            collateral_denom,
+        ),
+        ExecuteMsg::Repay {} => execute::repay(deps, env, info),
+        ExecuteMsg::RepayAll {} => execute::repay_all(deps, env, info),
+        ExecuteMsg::RepayFor { borrower } => execute::repay_for(deps, env, info, borrower),
+        ExecuteMsg::DeleteAccount {} => execute::delete_account(deps, env, info),
+        ExecuteMsg::ProvideLiquidity {} => execute::provide_liquidity(deps, env, info),
+        ExecuteMsg::FundInsurance {} => execute::fund_insurance(deps, info),
+        ExecuteMsg::Stake {} => execute::stake(deps, env, info),
+        ExecuteMsg::RequestUnstake { pool_id, shares } => {
+            execute::request_unstake(deps, env, info, pool_id, shares)
         }
-        ExecuteMsg::ChangePoolUtilization { id } => {
-            execute::change_pool_utilization(deps, id)
+        ExecuteMsg::Unstake { pool_id } => execute::unstake(deps, env, info, pool_id),
+        ExecuteMsg::DepositCollateral {} => execute::deposit_collateral(deps, env, info),
+        ExecuteMsg::WithdrawCollateral { denom, amount } => {
+            execute::withdraw_collateral(deps, env, info, denom, amount)
+        }
+        ExecuteMsg::UpdateConfig {
+            owner,
+            borrow_assets,
+            collateral_assets,
+            emode_categories,
+            oracle,
+            fees,
+            utilization_thresholds,
+            liquidation,
+            safety_module,
+            dex,
+        } => execute::update_config(
+            deps,
+            env,
+            info,
+            owner,
+            borrow_assets,
+            collateral_assets,
+            emode_categories,
+            oracle,
+            fees,
+            utilization_thresholds,
+            liquidation,
+            safety_module,
+            dex,
+        ),
+        ExecuteMsg::Liquidate {
+            borrower,
+            collateral_denom,
+        } => execute::liquidate(deps, env, info, borrower, collateral_denom),
+        ExecuteMsg::RefreshLiquidation { borrower } => {
+            execute::refresh_liquidation(deps, env, borrower)
+        }
+        ExecuteMsg::LiquidateWithQueue {
+            borrower,
+            collateral_denom,
+            debt_denom,
+            amount,
+        } => execute::liquidate_with_queue(
+            deps,
+            env,
+            info,
+            borrower,
+            collateral_denom,
+            debt_denom,
+            amount,
+        ),
+        ExecuteMsg::SetEMode { category } => execute::set_emode(deps, env, info, category),
+        ExecuteMsg::UpdatePrice { token, price } => {
+            execute::update_price(deps, env, info, token, price)
         }
+        ExecuteMsg::Leverage {
+            collateral_denom,
+            borrow_denom,
+            target_ltv,
+            swap_route,
+        } => execute::leverage(
+            deps,
+            env,
+            info,
+            collateral_denom,
+            borrow_denom,
+            target_ltv,
+            swap_route,
+        ),
+        ExecuteMsg::SwapCollateral {
+            from_denom,
+            to_denom,
+            amount,
+            min_out,
+            dex_route,
+        } => execute::swap_collateral(
+            deps, env, info, from_denom, to_denom, amount, min_out, dex_route,
+        ),
     }
 }
 
 pub mod execute {
     use super::*;
 
-    pub fn create_account(
-        deps: DepsMut,
-        info: MessageInfo,
-    ) -> Result<Response, ContractError> {
-        if ACCOUNTS.may_load(deps.storage, &info.sender.to_string())?.is_some() {
+    pub fn create_account(deps: DepsMut, info: MessageInfo) -> Result<Response, ContractError> {
+        if ACCOUNTS
+            .may_load(deps.storage, info.sender.as_str())?
+            .is_some()
+        {
             return Err(ContractError::AccountExists {});
         }
-    
+
         let account = Account {
             address: info.sender.to_string(),
-            borrowed_usdc: Uint128::zero(),
+            emode_category: None,
         };
-        
-        ACCOUNTS.save(deps.storage, &info.sender.to_string(), &account)?;
-    
+
+        ACCOUNTS.save(deps.storage, info.sender.as_str(), &account)?;
+
         Ok(Response::new()
             .add_attribute("method", "create_account")
             .add_attribute("address", info.sender))
@@ -79,15 +548,77 @@ pub mod execute {
 
     pub fn delete_account(
         deps: DepsMut,
+        env: Env,
         info: MessageInfo,
     ) -> Result<Response, ContractError> {
-        let account = ACCOUNTS.load(deps.storage, &info.sender.to_string()).unwrap();
-        if account.borrowed_usdc == Uint128::zero() && account.address.is_empty() {
+        let address = info.sender.as_str();
+        if !ACCOUNTS.has(deps.storage, address) {
             return Err(ContractError::AccountDoesNotExist {});
         }
-        
-        ACCOUNTS.remove(deps.storage, &info.sender.to_string());
-        Ok(Response::new().add_attribute("method", "delete_account"))
+
+        //debt has to be settled before anything is released
+        if !account_debts(deps.as_ref(), &env, address)?.is_empty() {
+            return Err(ContractError::OutstandingDebt {});
+        }
+
+        //every remaining collateral goes back to the owner
+        let collaterals = COLLATERALS
+            .prefix(address)
+            .range(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        let mut refund: Vec<Coin> = vec![];
+        for (denom, amount) in collaterals {
+            remove_collateral(deps.storage, address, &denom, amount)?;
+            refund.push(Coin { denom, amount });
+        }
+
+        //so do the liquidity positions, as long as the pools have the cash for them
+        let providers = LIQUIDITY_PROVIDERS
+            .prefix(address)
+            .range(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        for (pool_id, provider) in providers {
+            let mut pool = load_pool(deps.storage, &env, &pool_id)?;
+            let liquidity = math::shares_to_amount(
+                provider.shares,
+                pool.total_shares,
+                pool.total_liquidity,
+                Rounding::Down,
+            )?;
+            if pool_cash(&pool) < liquidity {
+                return Err(ContractError::InsufficientFunds {});
+            }
+            pool.total_liquidity -= liquidity;
+            pool.total_shares -= provider.shares;
+            save_pool(deps.storage, &mut pool)?;
+            LIQUIDITY_PROVIDERS.remove(deps.storage, (address, &pool_id));
+
+            match refund.iter_mut().find(|coin| coin.denom == pool.lend_token) {
+                Some(coin) => coin.amount += liquidity,
+                None => refund.push(Coin {
+                    denom: pool.lend_token.clone(),
+                    amount: liquidity,
+                }),
+            }
+        }
+        refund.retain(|coin| !coin.amount.is_zero());
+
+        ACCOUNTS.remove(deps.storage, address);
+        ISOLATED_ACCOUNTS.remove(deps.storage, address);
+
+        let mut res = Response::new()
+            .add_attribute("method", "delete_account")
+            .add_attribute("address", address);
+        for coin in &refund {
+            res = res.add_attribute("refunded", coin.to_string());
+        }
+        if !refund.is_empty() {
+            res = res.add_message(BankMsg::Send {
+                to_address: address.to_string(),
+                amount: refund,
+            });
+        }
+        Ok(res)
     }
 
     //address = archway1h28ghlz7vm8e5j8mge3r9hkym9d6ldx9s9k094llgmer7h6snvjqujqxke
@@ -96,129 +627,366 @@ pub mod execute {
         deps: DepsMut,
         env: Env,
         info: MessageInfo,
+        borrow_denom: String,
         borrow_amount: Uint128,
         collateral_denom: String,
-    
-        
     ) -> Result<Response, ContractError> {
-        //getting funds from user and checking if collateral denom is correct
-        let info_funds = info.funds
-            .iter()
-            .find(|coin| coin.denom == collateral_denom) ;
-            
         //getting account with address from info
-        let mut account = ACCOUNTS.may_load(deps.storage, &info.sender.to_string())?
-            .unwrap();
-        //checking if account do not exist
-        if account.borrowed_usdc == Uint128::zero() && account.address.is_empty() {
+        let address = info.sender.as_str();
+        if !ACCOUNTS.has(deps.storage, address) {
             return Err(ContractError::AccountDoesNotExist {});
         }
-        //entering values to the collateral struct
-        let collateral = Collateral {
-            address: info.sender.to_string(),
-            token_denom : collateral_denom.clone(),
-            amount : info_funds.unwrap().amount,
-        };
-
-        // Save collateral
-        COLLATERAL.save(deps.storage, &collateral)?;
-            
-        // Get current escrow balance
-        let mut escrow = ESCROW ;
-        
-        // Verify sufficient funds in escrow
-        if escrow < borrow_amount {
-            return Err(ContractError::InsufficientFunds {});
+        let borrow_asset = ensure_borrow_asset(deps.storage, &borrow_denom)?;
+        ensure_emode_asset(deps.storage, address, &borrow_denom)?;
+        ensure_pegged(deps.as_ref(), &env, &borrow_asset)?;
+
+        //collateral attached to the borrow is deposited before checking the limit
+        let collateral_amount = info
+            .funds
+            .iter()
+            .find(|coin| coin.denom == collateral_denom)
+            .map(|coin| coin.amount)
+            .unwrap_or_default();
+        if !collateral_amount.is_zero() {
+            ensure_collateral_asset(deps.storage, &collateral_denom)?;
+            add_collateral(deps.storage, address, &collateral_denom, collateral_amount)?;
+        }
+
+        let increase = debt_increase(deps.as_ref(), &env, address, &borrow_asset, borrow_amount)?;
+
+        // Every debt of the account counts against the limit, with interest accrued so far
+        let mut debts = account_debts(deps.as_ref(), &env, address)?;
+        debts.retain(|coin| coin.denom != borrow_denom);
+        debts.push(Coin {
+            denom: borrow_denom.clone(),
+            amount: increase.debt,
+        });
+        if debts_value(deps.as_ref(), &env, address, &debts)?
+            > borrow_limit(deps.as_ref(), &env, address)?
+        {
+            return Err(ContractError::InsufficientCollateral {});
         }
-        
-        // Update escrow balance
-        escrow = escrow.checked_sub(borrow_amount)
-            .map_err(|_| ContractError::MathError {})?;
-        
-        
-        // Update borrowed amount
-        account.borrowed_usdc += borrow_amount;
-        
-        // Save updated account
-        ACCOUNTS.save(deps.storage, &info.sender.to_string(), &account)?;
-        
-        // Send USDC to borrower from escrow
+        let pool = save_debt_increase(deps.storage, address, increase)?;
+
+        // Send the borrowed asset from the pool
         let send_msg = BankMsg::Send {
             to_address: info.sender.to_string(),
             amount: vec![Coin {
-                denom: "usdc".to_string(),
+                denom: pool.borrow_token.clone(),
                 amount: borrow_amount,
             }],
         };
-    
+
         Ok(Response::new()
             .add_message(send_msg)
             .add_attribute("method", "borrow")
             .add_attribute("borrower", info.sender)
-            .add_attribute("collateral_denom", collateral_denom.clone())
-            .add_attribute("collateral_amount", info_funds.unwrap().amount)
+            .add_attribute("collateral_denom", collateral_denom)
+            .add_attribute("collateral_amount", collateral_amount)
+            .add_attribute("borrow_denom", borrow_denom)
             .add_attribute("borrowed_amount", borrow_amount))
     }
 
-    pub fn repay(
+    pub fn repay(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+        let borrower = info.sender.to_string();
+        settle_debt(deps, &env, &info, &borrower, "repay", false)
+    }
+
+    pub fn repay_all(
         deps: DepsMut,
         env: Env,
         info: MessageInfo,
-        withdraw_denom: String,
-        withdraw_amount: Uint128,
     ) -> Result<Response, ContractError> {
-        // Verify USDC was sent for repayment
-        let usdc_repaid = info.funds
-            .iter()
-            .find(|coin| coin.denom == "usdc")
-            .ok_or(ContractError::NoRepayment {})?;
-            
-        // Load account with address from info
-        let mut account = ACCOUNTS.load(deps.storage, &info.sender.to_string())?;
+        let borrower = info.sender.to_string();
+        settle_debt(deps, &env, &info, &borrower, "repay_all", true)
+    }
+
+    pub fn repay_for(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        borrower: String,
+    ) -> Result<Response, ContractError> {
+        let borrower = deps.api.addr_validate(&borrower)?;
+        settle_debt(deps, &env, &info, borrower.as_str(), "repay_for", false)
+    }
 
-        //checking if account do not exist  
-        if account.borrowed_usdc == Uint128::zero() && account.address.is_empty() {
+    // Applies the attached coin to the borrower's debt in that denom and refunds the excess to
+    // the sender. With `exact` set the payment has to cover the whole debt.
+    fn settle_debt(
+        mut deps: DepsMut,
+        env: &Env,
+        info: &MessageInfo,
+        borrower: &str,
+        method: &str,
+        exact: bool,
+    ) -> Result<Response, ContractError> {
+        // The borrower's account may not be the sender's
+        if !ACCOUNTS.has(deps.storage, borrower) {
             return Err(ContractError::AccountDoesNotExist {});
         }
-        
-        // Update borrowed amount
-        account.borrowed_usdc = account.borrowed_usdc - usdc_repaid.amount;
-            
-        // get current collateral value with address and denom
-        let mut current_collateral = COLLATERAL.load(deps.storage)?;
-        if current_collateral.address != info.sender.to_string() || current_collateral.token_denom != withdraw_denom {
-            return Err(ContractError::TokenNotFound {});
+
+        // Verify a borrow asset was sent for repayment
+        let paid = repayment(info)?;
+        let borrow_asset = ensure_borrow_asset(deps.storage, &paid.denom)?;
+        let mut pool = load_pool(deps.storage, env, &paid.denom)?;
+
+        let debt = account_debt(deps.storage, borrower, &pool)?;
+        if debt.is_zero() {
+            return Err(ContractError::NoRepayment {});
+        }
+        if exact && paid.amount < debt {
+            return Err(ContractError::InsufficientFunds {});
+        }
+
+        let repaid = paid.amount.min(debt);
+        let refund = paid.amount - repaid;
+        let remaining = debt - repaid;
+
+        save_debt(deps.storage, borrower, &pool, remaining)?;
+        reduce_isolated_debt(deps.storage, borrower, &borrow_asset, repaid)?;
+        // individual debts round up, so the pool total can trail their sum by a few units
+        pool.total_borrowed = pool.total_borrowed.saturating_sub(repaid);
+        save_pool(deps.storage, &mut pool)?;
+        refresh_auction(deps.branch(), env, borrower)?;
+
+        let mut res = Response::new()
+            .add_attribute("method", method)
+            .add_attribute("repayer", info.sender.as_str())
+            .add_attribute("borrower", borrower)
+            .add_attribute("denom", &paid.denom)
+            .add_attribute("repaid", repaid)
+            .add_attribute("refund", refund)
+            .add_attribute("remaining_debt", remaining);
+        if !refund.is_zero() {
+            res = res.add_message(BankMsg::Send {
+                to_address: info.sender.to_string(),
+                amount: vec![Coin {
+                    denom: paid.denom,
+                    amount: refund,
+                }],
+            });
+        }
+
+        Ok(res)
+    }
+
+    pub fn fund_insurance(deps: DepsMut, info: MessageInfo) -> Result<Response, ContractError> {
+        let coin = match info.funds.as_slice() {
+            [coin] if BORROW_ASSETS.has(deps.storage, &coin.denom) => coin,
+            _ => return Err(ContractError::WrongToken {}),
+        };
+        let fund = INSURANCE_FUNDS
+            .may_load(deps.storage, &coin.denom)?
+            .unwrap_or_default()
+            + coin.amount;
+        INSURANCE_FUNDS.save(deps.storage, &coin.denom, &fund)?;
+
+        Ok(Response::new()
+            .add_attribute("method", "fund_insurance")
+            .add_attribute("funder", info.sender)
+            .add_attribute("pool_id", &coin.denom)
+            .add_attribute("amount", coin.amount)
+            .add_attribute("insurance_fund", fund))
+    }
+
+    pub fn stake(deps: DepsMut, env: Env, info: MessageInfo) -> Result<Response, ContractError> {
+        let coin = match info.funds.as_slice() {
+            [coin] if BORROW_ASSETS.has(deps.storage, &coin.denom) => coin,
+            _ => return Err(ContractError::WrongToken {}),
+        };
+        let mut pool = load_pool(deps.storage, &env, &coin.denom)?;
+        let mut module = load_safety_module(deps.storage, &mut pool)?;
+
+        let shares = math::amount_to_shares(
+            coin.amount,
+            module.total_shares,
+            module.total_staked,
+            Rounding::Down,
+        )?;
+        if shares.is_zero() {
+            return Err(ContractError::InsufficientFunds {});
         }
+        STAKERS.update(deps.storage, (info.sender.as_str(), &pool.id), |staker| {
+            let mut staker = staker.unwrap_or(Staker {
+                address: info.sender.to_string(),
+                pool_id: pool.id.clone(),
+                shares: Uint128::zero(),
+                unstaking: Uint128::zero(),
+                cooldown_end: None,
+            });
+            staker.shares += shares;
+            Ok::<_, ContractError>(staker)
+        })?;
+        module.total_staked += coin.amount;
+        module.total_shares += shares;
+        save_pool(deps.storage, &mut pool)?;
+        save_safety_module(deps.storage, &mut module, &pool)?;
 
-        //checking if withdraw amount is greater than current collateral
-        if withdraw_amount > current_collateral.amount {
+        Ok(Response::new()
+            .add_attribute("method", "stake")
+            .add_attribute("staker", info.sender)
+            .add_attribute("pool_id", &pool.id)
+            .add_attribute("amount", coin.amount)
+            .add_attribute("shares", shares))
+    }
+
+    pub fn request_unstake(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        pool_id: String,
+        shares: Uint128,
+    ) -> Result<Response, ContractError> {
+        let config = CONFIG.load(deps.storage)?;
+        let mut staker = STAKERS
+            .may_load(deps.storage, (info.sender.as_str(), &pool_id))?
+            .ok_or(ContractError::InsufficientFunds {})?;
+        if shares.is_zero() || shares > staker.shares {
             return Err(ContractError::InsufficientFunds {});
         }
+        let cooldown_end = env.block.time.seconds() + config.safety_module.cooldown;
+        staker.unstaking = shares;
+        staker.cooldown_end = Some(cooldown_end);
+        STAKERS.save(deps.storage, (info.sender.as_str(), &pool_id), &staker)?;
+
+        Ok(Response::new()
+            .add_attribute("method", "request_unstake")
+            .add_attribute("staker", info.sender)
+            .add_attribute("pool_id", pool_id)
+            .add_attribute("shares", shares)
+            .add_attribute("cooldown_end", cooldown_end.to_string()))
+    }
+
+    pub fn unstake(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        pool_id: String,
+    ) -> Result<Response, ContractError> {
+        let config = CONFIG.load(deps.storage)?;
+        let mut staker = STAKERS
+            .may_load(deps.storage, (info.sender.as_str(), &pool_id))?
+            .ok_or(ContractError::InsufficientFunds {})?;
+        let ends_at = match staker.cooldown_end {
+            Some(ends_at) if !staker.unstaking.is_zero() => ends_at,
+            _ => return Err(ContractError::InsufficientFunds {}),
+        };
+        if env.block.time.seconds() < ends_at {
+            return Err(ContractError::CooldownNotFinished { ends_at });
+        }
+        // a request left standing would let stakers leave the moment bad debt shows up
+        let expired_at = ends_at + config.safety_module.unstake_window;
+        if env.block.time.seconds() > expired_at {
+            return Err(ContractError::UnstakeRequestExpired { expired_at });
+        }
+
+        let mut pool = load_pool(deps.storage, &env, &pool_id)?;
+        let mut module = load_safety_module(deps.storage, &mut pool)?;
+        let shares = staker.unstaking;
+        let amount = math::shares_to_amount(
+            shares,
+            module.total_shares,
+            module.total_staked,
+            Rounding::Down,
+        )?;
+        module.total_staked -= amount;
+        module.total_shares -= shares;
+        staker.shares -= shares;
+        staker.unstaking = Uint128::zero();
+        staker.cooldown_end = None;
+        if staker.shares.is_zero() {
+            STAKERS.remove(deps.storage, (info.sender.as_str(), &pool_id));
+        } else {
+            STAKERS.save(deps.storage, (info.sender.as_str(), &pool_id), &staker)?;
+        }
+        save_pool(deps.storage, &mut pool)?;
+        save_safety_module(deps.storage, &mut module, &pool)?;
+
+        let mut res = Response::new()
+            .add_attribute("method", "unstake")
+            .add_attribute("staker", info.sender.as_str())
+            .add_attribute("pool_id", &pool_id)
+            .add_attribute("shares", shares)
+            .add_attribute("amount", amount);
+        if !amount.is_zero() {
+            res = res.add_message(BankMsg::Send {
+                to_address: info.sender.to_string(),
+                amount: vec![Coin {
+                    denom: pool_id,
+                    amount,
+                }],
+            });
+        }
+        Ok(res)
+    }
+
+    pub fn deposit_collateral(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+    ) -> Result<Response, ContractError> {
+        if ACCOUNTS
+            .may_load(deps.storage, info.sender.as_str())?
+            .is_none()
+        {
+            return Err(ContractError::AccountDoesNotExist {});
+        }
+        if info.funds.is_empty() {
+            return Err(ContractError::NoFunds {});
+        }
+
+        let mut res = Response::new()
+            .add_attribute("method", "deposit_collateral")
+            .add_attribute("depositor", info.sender.as_str());
+        for coin in &info.funds {
+            ensure_collateral_asset(deps.storage, &coin.denom)?;
+            add_collateral(deps.storage, info.sender.as_str(), &coin.denom, coin.amount)?;
+            res = res.add_attribute("deposited", coin.to_string());
+        }
+        refresh_auction(deps.branch(), &env, info.sender.as_str())?;
+
+        Ok(res)
+    }
+
+    pub fn withdraw_collateral(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        denom: String,
+        amount: Uint128,
+    ) -> Result<Response, ContractError> {
+        let address = info.sender.as_str();
+        if !ACCOUNTS.has(deps.storage, address) {
+            return Err(ContractError::AccountDoesNotExist {});
+        }
+
+        remove_collateral(deps.storage, address, &denom, amount)?;
+
+        //the remaining collateral has to cover the outstanding debt, which needs no prices when
+        // there is none
+        let debts = account_debts(deps.as_ref(), &env, address)?;
+        if !debts.is_empty()
+            && debts_value(deps.as_ref(), &env, address, &debts)?
+                > borrow_limit(deps.as_ref(), &env, address)?
+        {
+            return Err(ContractError::InsufficientCollateral {});
+        }
 
-        //updating collateral amount
-        current_collateral.amount = current_collateral.amount - withdraw_amount;
-            
-        COLLATERAL.save(deps.storage, &current_collateral)?;
-    
-        // Save updated account
-        ACCOUNTS.save(deps.storage, &info.sender.to_string(), &account)?;
-        
-        // Return requested collateral
         let return_msg = BankMsg::Send {
             to_address: info.sender.to_string(),
             amount: vec![Coin {
-                denom: withdraw_denom.clone(),
-                amount: withdraw_amount,
+                denom: denom.clone(),
+                amount,
             }],
         };
-        
+
         Ok(Response::new()
             .add_message(return_msg)
-            .add_attribute("method", "repay")
-            .add_attribute("repayer", info.sender)
-            .add_attribute("usdc_repaid", usdc_repaid.amount)
-            .add_attribute("collateral_withdrawn", withdraw_denom)
-            .add_attribute("withdrawal_amount", withdraw_amount))
+            .add_attribute("method", "withdraw_collateral")
+            .add_attribute("withdrawer", info.sender)
+            .add_attribute("denom", denom)
+            .add_attribute("amount", amount))
     }
 
     pub fn provide_liquidity(
@@ -226,251 +994,5984 @@ pub mod execute {
         env: Env,
         info: MessageInfo,
     ) -> Result<Response, ContractError> {
-        let liquidity_paid = info.funds
-        .iter()
-        .find(|coin| coin.denom == "usdc")
-        .ok_or(ContractError::WrongToken {})?;
-        
+        let (liquidity_paid, asset) = match info.funds.as_slice() {
+            [coin] => match BORROW_ASSETS.may_load(deps.storage, &coin.denom)? {
+                Some(asset) => (coin, asset),
+                None => return Err(ContractError::WrongToken {}),
+            },
+            _ => return Err(ContractError::WrongToken {}),
+        };
+        let mut pool = load_pool(deps.storage, &env, &liquidity_paid.denom)?;
+        if let Some(cap) = asset.supply_cap {
+            if pool.total_liquidity + liquidity_paid.amount > cap {
+                return Err(ContractError::SupplyCapExceeded {
+                    denom: asset.denom,
+                    cap,
+                });
+            }
+        }
+
+        // shares are priced off the pool including interest earned so far
+        let shares = math::amount_to_shares(
+            liquidity_paid.amount,
+            pool.total_shares,
+            pool.total_liquidity,
+            Rounding::Down,
+        )?;
+        if shares.is_zero() {
+            return Err(ContractError::InsufficientFunds {});
+        }
 
         // Create or update liquidity provider record
-        let liquidity_provider = LiquidityProvider {
-            address: info.sender.to_string(),
-            liquidity_amount: liquidity_paid.amount,
-        };
-        LIQUIDITY_PROVIDERS.save(deps.storage, &liquidity_provider)?;
+        LIQUIDITY_PROVIDERS.update(deps.storage, (info.sender.as_str(), &pool.id), |provider| {
+            let mut provider = provider.unwrap_or(LiquidityProvider {
+                address: info.sender.to_string(),
+                pool_id: pool.id.clone(),
+                shares: Uint128::zero(),
+            });
+            provider.shares += shares;
+            Ok::<_, ContractError>(provider)
+        })?;
 
+        pool.total_liquidity += liquidity_paid.amount;
+        pool.total_shares += shares;
+        save_pool(deps.storage, &mut pool)?;
 
         Ok(Response::new()
             .add_attribute("method", "provide_liquidity")
             .add_attribute("provider", info.sender)
-            .add_attribute("amount", liquidity_paid.amount))
-    }
-
-    pub fn change_pool_utilization(deps: DepsMut, id: Uint128) -> Result<Response, ContractError> {
-        let mut pool = POOLS.load(deps.storage, &id.to_string())?;
-        let total_liquidity = pool.total_liquidity;
-        let total_borrowed = pool.total_borrowed;
-    
-        let utilization_ratio = total_borrowed / total_liquidity;
-    
-        if utilization_ratio > Uint128::from(90u128) {
-            pool.utilization = PoolUtilization::High;
-        } else if utilization_ratio > Uint128::from(50u128) {
-            pool.utilization = PoolUtilization::Medium;
-        } else {
-            pool.utilization = PoolUtilization::Low;
-        }
-    
-        POOLS.save(deps.storage, &id.to_string(), &pool)?;
-
-        Ok(Response::new()
-            .add_attribute("method", "change_pool_utilization")
-            .add_attribute("pool_id", id.to_string())
-            .add_attribute("pool", pool.utilization.to_string()))
-    
+            .add_attribute("pool_id", pool.id)
+            .add_attribute("amount", liquidity_paid.amount)
+            .add_attribute("shares", shares))
     }
-}
 
+    pub fn liquidate(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        borrower: String,
+        collateral_denom: String,
+    ) -> Result<Response, ContractError> {
+        let borrower = deps.api.addr_validate(&borrower)?;
+        if !ACCOUNTS.has(deps.storage, borrower.as_str()) {
+            return Err(ContractError::AccountDoesNotExist {});
+        }
+        // the attached coin picks which of the borrower's debts is repaid
+        let paid = repayment(&info)?;
+        let borrow_asset = ensure_borrow_asset(deps.storage, &paid.denom)?;
+        let mut pool = load_pool(deps.storage, &env, &paid.denom)?;
 
+        let Seizure {
+            debt,
+            repaid,
+            seized,
+            bonus,
+            bonus_amount,
+        } = seizure(
+            deps.branch(),
+            &env,
+            borrower.as_str(),
+            &collateral_denom,
+            &borrow_asset,
+            &pool,
+            paid.amount,
+        )?;
+        let refund = paid.amount - repaid;
 
-// Helper function to get collateral value (simplified)
-fn get_collateral_value(deps: Deps, collateral: &Coin) -> Result<Uint128, ContractError> {
-    // In practice, you would query an oracle here
-    // This is a simplified version that assumes 1:1 value with USDC
-    Ok(collateral.amount)
-}
+        reduce_isolated_debt(deps.storage, borrower.as_str(), &borrow_asset, repaid)?;
+        remove_collateral(deps.storage, borrower.as_str(), &collateral_denom, seized)?;
+        save_debt(deps.storage, borrower.as_str(), &pool, debt - repaid)?;
+        pool.total_borrowed = pool.total_borrowed.saturating_sub(repaid);
+        save_pool(deps.storage, &mut pool)?;
+        let bad_debts = write_off_bad_debt(deps.branch(), &env, borrower.as_str())?;
+        // a healthy account needs a fresh auction the next time it falls under water
+        observe_health(deps.branch(), &env, borrower.as_str())?;
 
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
-        QueryMsg::GetAccount { address } => {
-            to_json_binary(&query::get_account(deps, address)?)
-        },
-        
+        let mut res = Response::new()
+            .add_message(BankMsg::Send {
+                to_address: info.sender.to_string(),
+                amount: vec![Coin {
+                    denom: collateral_denom.clone(),
+                    amount: seized,
+                }],
+            })
+            .add_attribute("method", "liquidate")
+            .add_attribute("liquidator", info.sender.as_str())
+            .add_attribute("borrower", borrower)
+            .add_attribute("collateral_denom", collateral_denom)
+            .add_attribute("repaid", repaid)
+            .add_attribute("seized", seized)
+            .add_attribute("refund", refund)
+            .add_attribute("debt_denom", &paid.denom)
+            .add_attribute("bonus", bonus.to_string())
+            .add_attribute("bonus_amount", bonus_amount)
+            .add_events(bad_debts);
+        if !refund.is_zero() {
+            res = res.add_message(BankMsg::Send {
+                to_address: info.sender.to_string(),
+                amount: vec![Coin {
+                    denom: paid.denom,
+                    amount: refund,
+                }],
+            });
+        }
+        Ok(res)
     }
-}
 
-pub mod query {
-    use super::*;
+    // The queue pays for the collateral in a submessage and the debt is settled in the reply
+    pub fn liquidate_with_queue(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        borrower: String,
+        collateral_denom: String,
+        debt_denom: String,
+        amount: Uint128,
+    ) -> Result<Response, ContractError> {
+        let borrower = deps.api.addr_validate(&borrower)?;
+        if !ACCOUNTS.has(deps.storage, borrower.as_str()) {
+            return Err(ContractError::AccountDoesNotExist {});
+        }
+        let config = CONFIG.load(deps.storage)?;
+        let queue = config
+            .liquidation
+            .queue
+            .ok_or(ContractError::NoLiquidationQueue {})?;
+        // the proceeds are measured in the debt denom, so the queue has to pay in it
+        let queue_config: QueueConfigResponse = deps
+            .querier
+            .query_wasm_smart(&queue, &QueueQueryMsg::GetConfig {})?;
+        if queue_config.stable_denom != debt_denom {
+            return Err(ContractError::QueueDenomMismatch {
+                denom: queue_config.stable_denom,
+            });
+        }
+        let borrow_asset = ensure_borrow_asset(deps.storage, &debt_denom)?;
+        let pool = load_pool(deps.storage, &env, &debt_denom)?;
 
-    pub fn get_account(deps: Deps, address: String) -> StdResult<Account> {
-        let account = ACCOUNTS.load(deps.storage, &address)?;
-        Ok(account)
-    }
+        let Seizure { seized, bonus, .. } = seizure(
+            deps.branch(),
+            &env,
+            borrower.as_str(),
+            &collateral_denom,
+            &borrow_asset,
+            &pool,
+            amount,
+        )?;
+        remove_collateral(deps.storage, borrower.as_str(), &collateral_denom, seized)?;
 
-    
-}
+        let emode = account_emode(deps.storage, borrower.as_str())?;
+        let asset = COLLATERAL_ASSETS.load(deps.storage, &collateral_denom)?;
+        let price = math::unit_price(
+            asset_price(deps.as_ref(), &env, &collateral_denom, emode.as_ref())?,
+            asset.decimals,
+            asset_price(deps.as_ref(), &env, &debt_denom, emode.as_ref())?,
+            borrow_asset.decimals,
+        )?;
+        // the bonus is all the borrower gives up, so the bids' premium is only allowed to eat
+        // into it by the configured slippage
+        let net_value = math::mul_div(
+            math::mul_decimal(seized, price, Rounding::Down)?,
+            Decimal::one().atomics(),
+            (Decimal::one() + bonus).atomics(),
+            Rounding::Down,
+        )?;
+        let min_proceeds = math::mul_decimal(
+            net_value,
+            Decimal::one() - config.liquidation.max_queue_slippage,
+            Rounding::Down,
+        )?;
+        let balance = deps
+            .querier
+            .query_balance(&env.contract.address, &debt_denom)?
+            .amount;
+        PENDING_LIQUIDATION.save(
+            deps.storage,
+            &PendingLiquidation {
+                borrower: borrower.to_string(),
+                collateral_denom: collateral_denom.clone(),
+                seized,
+                debt_denom: debt_denom.clone(),
+                balance,
+                min_proceeds,
+            },
+        )?;
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use cosmwasm_std::testing::{
-        mock_dependencies, mock_env, mock_info,
-        MockApi, MockQuerier, MockStorage,
-    };
-    use cosmwasm_std::{coins, Addr, OwnedDeps};
-    use crate::msg::InstantiateMsg;
+        let sale = WasmMsg::Execute {
+            contract_addr: queue.to_string(),
+            msg: to_json_binary(&QueueExecuteMsg::ExecuteLiquidation { price })?,
+            funds: vec![Coin {
+                denom: collateral_denom.clone(),
+                amount: seized,
+            }],
+        };
+        Ok(Response::new()
+            .add_submessage(SubMsg::reply_on_success(sale, QUEUE_LIQUIDATION_REPLY_ID))
+            .add_attribute("method", "liquidate_with_queue")
+            .add_attribute("liquidator", info.sender)
+            .add_attribute("borrower", borrower)
+            .add_attribute("collateral_denom", collateral_denom)
+            .add_attribute("seized", seized)
+            .add_attribute("debt_denom", debt_denom)
+            .add_attribute("bonus", bonus.to_string()))
+    }
+
+    // Borrows what brings the account to the target LTV once swapped for collateral at oracle
+    // prices. Like a flash loan the debt is opened before the collateral backing it arrives, so
+    // the borrow limit is only checked in the reply, and a failed swap or check reverts it all.
+    pub fn leverage(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        collateral_denom: String,
+        borrow_denom: String,
+        target_ltv: Decimal,
+        swap_route: Vec<SwapStep>,
+    ) -> Result<Response, ContractError> {
+        nonpayable(&info)?;
+        let address = info.sender.as_str();
+        if !ACCOUNTS.has(deps.storage, address) {
+            return Err(ContractError::AccountDoesNotExist {});
+        }
+        let config = CONFIG.load(deps.storage)?;
+        let dex = config.dex.ok_or(ContractError::NoDex {})?;
+        if target_ltv.is_zero() || target_ltv >= Decimal::one() {
+            return Err(ContractError::InvalidLeverageTarget {});
+        }
+        ensure_swap_route(&swap_route, &borrow_denom, &collateral_denom)?;
+        let borrow_asset = ensure_borrow_asset(deps.storage, &borrow_denom)?;
+        ensure_emode_asset(deps.storage, address, &borrow_denom)?;
+        ensure_pegged(deps.as_ref(), &env, &borrow_asset)?;
+        ensure_collateral_asset(deps.storage, &collateral_denom)?;
+        ensure_emode_asset(deps.storage, address, &collateral_denom)?;
+
+        // (debt + x) / (collateral + x) = target LTV, solved for the value x to borrow
+        let collateral_value = collateral_limit(deps.as_ref(), &env, address, |_| Decimal::one())?;
+        let debts = account_debts(deps.as_ref(), &env, address)?;
+        let debt_value = debts_value(deps.as_ref(), &env, address, &debts)?;
+        let target = math::mul_decimal(collateral_value, target_ltv, Rounding::Down)?;
+        if target <= debt_value {
+            return Err(ContractError::LeverageTargetReached {});
+        }
+        let value = math::mul_div(
+            target - debt_value,
+            Decimal::one().atomics(),
+            (Decimal::one() - target_ltv).atomics(),
+            Rounding::Down,
+        )?;
+        let emode = account_emode(deps.storage, address)?;
+        let amount = math::amount_for_value(
+            value,
+            borrow_asset.decimals,
+            debt_price(deps.as_ref(), &env, &borrow_asset, emode.as_ref())?,
+            config.oracle.price_decimals,
+            Rounding::Down,
+        )?;
+        if amount.is_zero() {
+            return Err(ContractError::LeverageTargetReached {});
+        }
+        let increase = debt_increase(deps.as_ref(), &env, address, &borrow_asset, amount)?;
+        save_debt_increase(deps.storage, address, increase)?;
+
+        // the borrowed amount's worth of collateral, less the slippage the DEX is allowed
+        let collateral_asset = COLLATERAL_ASSETS.load(deps.storage, &collateral_denom)?;
+        let price = math::unit_price(
+            asset_price(deps.as_ref(), &env, &borrow_denom, emode.as_ref())?,
+            borrow_asset.decimals,
+            asset_price(deps.as_ref(), &env, &collateral_denom, emode.as_ref())?,
+            collateral_asset.decimals,
+        )?;
+        let min_output = math::mul_decimal(
+            math::mul_decimal(amount, price, Rounding::Down)?,
+            Decimal::one() - dex.max_slippage,
+            Rounding::Down,
+        )?;
+        let swap = swap_for_collateral(
+            deps,
+            &env,
+            &dex.contract,
+            address,
+            Coin {
+                denom: borrow_denom.clone(),
+                amount,
+            },
+            &collateral_denom,
+            swap_route,
+            min_output,
+            LEVERAGE_REPLY_ID,
+        )?;
+        Ok(Response::new()
+            .add_submessage(swap)
+            .add_attribute("method", "leverage")
+            .add_attribute("borrower", info.sender)
+            .add_attribute("borrow_denom", borrow_denom)
+            .add_attribute("borrowed_amount", amount)
+            .add_attribute("collateral_denom", collateral_denom)
+            .add_attribute("min_output", min_output))
+    }
+
+    pub fn settle_leverage(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+        let (pending, received) = deposit_swap_output(deps, &env)?;
+        Ok(Response::new()
+            .add_attribute("method", "settle_leverage")
+            .add_attribute("borrower", pending.borrower)
+            .add_attribute("collateral_denom", pending.collateral_denom)
+            .add_attribute("received", received))
+    }
+
+    // The collateral sold leaves the account before the swap, and the proceeds only count
+    // towards its limit once deposited in the reply
+    #[allow(clippy::too_many_arguments)]
+    pub fn swap_collateral(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        from_denom: String,
+        to_denom: String,
+        amount: Uint128,
+        min_out: Uint128,
+        dex_route: Vec<SwapStep>,
+    ) -> Result<Response, ContractError> {
+        nonpayable(&info)?;
+        let address = info.sender.as_str();
+        if !ACCOUNTS.has(deps.storage, address) {
+            return Err(ContractError::AccountDoesNotExist {});
+        }
+        let dex = CONFIG
+            .load(deps.storage)?
+            .dex
+            .ok_or(ContractError::NoDex {})?;
+        ensure_swap_route(&dex_route, &from_denom, &to_denom)?;
+        ensure_collateral_asset(deps.storage, &to_denom)?;
+        ensure_emode_asset(deps.storage, address, &to_denom)?;
+        // the debt an account runs up in isolation counts against the collateral's ceiling
+        // until it is repaid, so the collateral cannot be traded away from under it
+        let from_asset = COLLATERAL_ASSETS.may_load(deps.storage, &from_denom)?;
+        if from_asset.is_some_and(|asset| asset.debt_ceiling.is_some())
+            && !account_debts(deps.as_ref(), &env, address)?.is_empty()
+        {
+            return Err(ContractError::IsolatedSwap { denom: from_denom });
+        }
+
+        remove_collateral(deps.storage, address, &from_denom, amount)?;
+        let swap = swap_for_collateral(
+            deps,
+            &env,
+            &dex.contract,
+            address,
+            Coin {
+                denom: from_denom.clone(),
+                amount,
+            },
+            &to_denom,
+            dex_route,
+            min_out,
+            COLLATERAL_SWAP_REPLY_ID,
+        )?;
+        Ok(Response::new()
+            .add_submessage(swap)
+            .add_attribute("method", "swap_collateral")
+            .add_attribute("borrower", info.sender)
+            .add_attribute("from_denom", from_denom)
+            .add_attribute("amount", amount)
+            .add_attribute("to_denom", to_denom)
+            .add_attribute("min_output", min_out))
+    }
+
+    pub fn settle_collateral_swap(deps: DepsMut, env: Env) -> Result<Response, ContractError> {
+        let (pending, received) = deposit_swap_output(deps, &env)?;
+        Ok(Response::new()
+            .add_attribute("method", "settle_collateral_swap")
+            .add_attribute("borrower", pending.borrower)
+            .add_attribute("collateral_denom", pending.collateral_denom)
+            .add_attribute("received", received))
+    }
+
+    // The route has to sell `offer_denom` for a different denom it ends in, every hop trading
+    // what the one before it bought for something else. Steps only name their output, so the
+    // first pool is left to the DEX, which refuses an offer it does not trade.
+    fn ensure_swap_route(
+        route: &[SwapStep],
+        offer_denom: &str,
+        denom: &str,
+    ) -> Result<(), ContractError> {
+        let route_end = route.last().map(|step| step.denom_out.as_str());
+        let mut denom_in = offer_denom;
+        for step in route {
+            if step.denom_out == denom_in {
+                return Err(ContractError::InvalidSwapRoute {
+                    denom: denom.to_string(),
+                });
+            }
+            denom_in = &step.denom_out;
+        }
+        if offer_denom == denom || route_end != Some(denom) {
+            return Err(ContractError::InvalidSwapRoute {
+                denom: denom.to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    // Swaps `offer` along the route in a submessage, remembering the contract's balance of the
+    // collateral bought so the reply can tell what the DEX paid
+    #[allow(clippy::too_many_arguments)]
+    fn swap_for_collateral(
+        deps: DepsMut,
+        env: &Env,
+        dex: &Addr,
+        borrower: &str,
+        offer: Coin,
+        collateral_denom: &str,
+        route: Vec<SwapStep>,
+        min_output: Uint128,
+        reply_id: u64,
+    ) -> Result<SubMsg, ContractError> {
+        let balance = deps
+            .querier
+            .query_balance(&env.contract.address, collateral_denom)?
+            .amount;
+        PENDING_SWAP.save(
+            deps.storage,
+            &PendingSwap {
+                borrower: borrower.to_string(),
+                collateral_denom: collateral_denom.to_string(),
+                balance,
+                min_output,
+            },
+        )?;
+
+        let swap = WasmMsg::Execute {
+            contract_addr: dex.to_string(),
+            msg: to_json_binary(&DexExecuteMsg::Swap { route, min_output })?,
+            funds: vec![offer],
+        };
+        Ok(SubMsg::reply_on_success(swap, reply_id))
+    }
+
+    // Deposits what the swap paid as collateral and holds the account to its borrow limit, which
+    // needs no prices when it has no debt
+    fn deposit_swap_output(
+        deps: DepsMut,
+        env: &Env,
+    ) -> Result<(PendingSwap, Uint128), ContractError> {
+        let pending = PENDING_SWAP.load(deps.storage)?;
+        PENDING_SWAP.remove(deps.storage);
+        let balance = deps
+            .querier
+            .query_balance(&env.contract.address, &pending.collateral_denom)?
+            .amount;
+        let received = balance
+            .checked_sub(pending.balance)
+            .map_err(StdError::from)?;
+        if received < pending.min_output {
+            return Err(ContractError::SlippageExceeded {
+                min_output: pending.min_output,
+                received,
+            });
+        }
+
+        add_collateral(
+            deps.storage,
+            &pending.borrower,
+            &pending.collateral_denom,
+            received,
+        )?;
+        let debts = account_debts(deps.as_ref(), env, &pending.borrower)?;
+        if !debts.is_empty()
+            && debts_value(deps.as_ref(), env, &pending.borrower, &debts)?
+                > borrow_limit(deps.as_ref(), env, &pending.borrower)?
+        {
+            return Err(ContractError::InsufficientCollateral {});
+        }
+        Ok((pending, received))
+    }
+
+    // Repays the debt with what the queue paid for the seized collateral
+    pub fn settle_queue_liquidation(
+        mut deps: DepsMut,
+        env: Env,
+    ) -> Result<Response, ContractError> {
+        let pending = PENDING_LIQUIDATION.load(deps.storage)?;
+        PENDING_LIQUIDATION.remove(deps.storage);
+        let balance = deps
+            .querier
+            .query_balance(&env.contract.address, &pending.debt_denom)?
+            .amount;
+        let proceeds = balance
+            .checked_sub(pending.balance)
+            .map_err(StdError::from)?;
+        if proceeds < pending.min_proceeds {
+            return Err(ContractError::SlippageExceeded {
+                min_output: pending.min_proceeds,
+                received: proceeds,
+            });
+        }
+
+        let borrow_asset = ensure_borrow_asset(deps.storage, &pending.debt_denom)?;
+        let mut pool = load_pool(deps.storage, &env, &pending.debt_denom)?;
+        let debt = account_debt(deps.storage, &pending.borrower, &pool)?;
+        let repaid = proceeds.min(debt);
+        let surplus = proceeds - repaid;
+
+        reduce_isolated_debt(deps.storage, &pending.borrower, &borrow_asset, repaid)?;
+        save_debt(deps.storage, &pending.borrower, &pool, debt - repaid)?;
+        pool.total_borrowed = pool.total_borrowed.saturating_sub(repaid);
+        save_pool(deps.storage, &mut pool)?;
+        let bad_debts = write_off_bad_debt(deps.branch(), &env, &pending.borrower)?;
+        observe_health(deps.branch(), &env, &pending.borrower)?;
+
+        let mut res = Response::new()
+            .add_attribute("method", "settle_queue_liquidation")
+            .add_attribute("borrower", &pending.borrower)
+            .add_attribute("proceeds", proceeds)
+            .add_attribute("repaid", repaid)
+            .add_attribute("surplus", surplus)
+            .add_events(bad_debts);
+        if !surplus.is_zero() {
+            res = res.add_message(BankMsg::Send {
+                to_address: pending.borrower,
+                amount: vec![Coin {
+                    denom: pending.debt_denom,
+                    amount: surplus,
+                }],
+            });
+        }
+        Ok(res)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn update_config(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        owner: Option<String>,
+        borrow_assets: Option<Vec<BorrowAssetConfig>>,
+        collateral_assets: Option<Vec<CollateralAsset>>,
+        emode_categories: Option<Vec<EModeCategory>>,
+        oracle: Option<OracleConfig>,
+        fees: Option<FeeConfig>,
+        utilization_thresholds: Option<UtilizationThresholds>,
+        liquidation: Option<LiquidationConfig>,
+        safety_module: Option<SafetyModuleConfig>,
+        dex: Option<DexConfig>,
+    ) -> Result<Response, ContractError> {
+        let mut config = CONFIG.load(deps.storage)?;
+        if info.sender != config.owner {
+            return Err(ContractError::Unauthorized {});
+        }
+
+        // settle interest and the stakers' reserve share under the old rates and fees before
+        // changing them
+        let mut pools = POOLS
+            .keys(deps.storage, None, None, Order::Ascending)
+            .map(|id| load_pool(deps.storage, &env, &id?))
+            .collect::<StdResult<Vec<_>>>()?;
+        let mut modules = pools
+            .iter_mut()
+            .map(|pool| load_safety_module(deps.storage, pool))
+            .collect::<StdResult<Vec<_>>>()?;
+
+        if let Some(owner) = owner {
+            config.owner = deps.api.addr_validate(&owner)?;
+        }
+        if let Some(oracle) = oracle {
+            config.oracle = validate_oracle(deps.as_ref(), oracle)?;
+        }
+        if let Some(fees) = fees {
+            config.fees = validate_fees(fees)?;
+        }
+        if let Some(thresholds) = utilization_thresholds {
+            config.utilization_thresholds = validate_utilization_thresholds(thresholds)?;
+        }
+        if let Some(liquidation) = liquidation {
+            config.liquidation = validate_liquidation(deps.as_ref(), liquidation)?;
+        }
+        if let Some(safety_module) = safety_module {
+            config.safety_module = validate_safety_module(safety_module)?;
+        }
+        if let Some(dex) = dex {
+            config.dex = Some(validate_dex(deps.as_ref(), dex)?);
+        }
+        CONFIG.save(deps.storage, &config)?;
+        for (pool, module) in pools.iter_mut().zip(modules.iter_mut()) {
+            save_pool(deps.storage, pool)?;
+            save_safety_module(deps.storage, module, pool)?;
+        }
+
+        for asset in borrow_assets.unwrap_or_default() {
+            save_borrow_asset(deps.storage, &env, asset)?;
+        }
+        for asset in collateral_assets.unwrap_or_default() {
+            save_collateral_asset(deps.storage, asset)?;
+        }
+        for category in emode_categories.unwrap_or_default() {
+            validate_emode_category(deps.storage, &category)?;
+            EMODE_CATEGORIES.save(deps.storage, category.id, &category)?;
+        }
+
+        Ok(Response::new()
+            .add_attribute("method", "update_config")
+            .add_attribute("owner", config.owner))
+    }
+
+    pub fn refresh_liquidation(
+        deps: DepsMut,
+        env: Env,
+        borrower: String,
+    ) -> Result<Response, ContractError> {
+        let borrower = deps.api.addr_validate(&borrower)?;
+        if !ACCOUNTS.has(deps.storage, borrower.as_str()) {
+            return Err(ContractError::AccountDoesNotExist {});
+        }
+        let started_at = observe_health(deps, &env, borrower.as_str())?;
+
+        Ok(Response::new()
+            .add_attribute("method", "refresh_liquidation")
+            .add_attribute("borrower", borrower)
+            .add_attribute(
+                "started_at",
+                started_at.map(|time| time.to_string()).unwrap_or_default(),
+            ))
+    }
+
+    pub fn set_emode(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        category: Option<u8>,
+    ) -> Result<Response, ContractError> {
+        let address = info.sender.as_str();
+        let mut account = ACCOUNTS
+            .may_load(deps.storage, address)?
+            .ok_or(ContractError::AccountDoesNotExist {})?;
+
+        //every position has to qualify for the category
+        if let Some(id) = category {
+            let emode = EMODE_CATEGORIES
+                .may_load(deps.storage, id)?
+                .ok_or(ContractError::EModeCategoryNotFound { category: id })?;
+            let collaterals =
+                COLLATERALS
+                    .prefix(address)
+                    .keys(deps.storage, None, None, Order::Ascending);
+            let debts = DEBTS
+                .prefix(address)
+                .keys(deps.storage, None, None, Order::Ascending);
+            for denom in collaterals.chain(debts) {
+                let denom = denom?;
+                if !emode.assets.contains(&denom) {
+                    return Err(ContractError::NotInEModeCategory {
+                        denom,
+                        category: id,
+                    });
+                }
+            }
+        }
+        account.emode_category = category;
+        ACCOUNTS.save(deps.storage, address, &account)?;
+
+        //leaving a category can lower the limit below the outstanding debt
+        let debts = account_debts(deps.as_ref(), &env, address)?;
+        if debts_value(deps.as_ref(), &env, address, &debts)?
+            > borrow_limit(deps.as_ref(), &env, address)?
+        {
+            return Err(ContractError::InsufficientCollateral {});
+        }
+
+        Ok(Response::new()
+            .add_attribute("method", "set_emode")
+            .add_attribute("address", address)
+            .add_attribute(
+                "category",
+                category.map(|id| id.to_string()).unwrap_or_default(),
+            ))
+    }
+
+    pub fn update_price(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token: String,
+        price: Uint128,
+    ) -> Result<Response, ContractError> {
+        let config = CONFIG.load(deps.storage)?;
+        let (feeders, quorum) = price_feed(&config.oracle, &token);
+        if !feeders.contains(&info.sender) {
+            return Err(ContractError::Unauthorized {});
+        }
+
+        let now = env.block.time.seconds();
+        let submission = Price {
+            price,
+            updated_at: now,
+        };
+        FEEDER_PRICES.save(deps.storage, (&token, &info.sender), &submission)?;
+        let response = Response::new()
+            .add_attribute("method", "update_price")
+            .add_attribute("feeder", info.sender)
+            .add_attribute("token", &token)
+            .add_attribute("price", price);
+
+        // the median of the fresh submissions becomes the price once a quorum of feeders is
+        // fresh, and stays usable until fewer than a quorum of them would be
+        let mut fresh = vec![];
+        for feeder in feeders {
+            if let Some(submission) = FEEDER_PRICES.may_load(deps.storage, (&token, feeder))? {
+                if !is_stale(&config.oracle, &submission, now) {
+                    fresh.push(submission);
+                }
+            }
+        }
+        let median = match math::median(fresh.iter().map(|price| price.price).collect()) {
+            Some(median) if fresh.len() >= quorum as usize => median,
+            _ => return Ok(response),
+        };
+        fresh.sort_by_key(|price| std::cmp::Reverse(price.updated_at));
+        let update = Price {
+            price: median,
+            updated_at: fresh[quorum as usize - 1].updated_at,
+        };
+        // an outlier is only accepted once a second update confirms it
+        let accepted = match (
+            config.oracle.max_deviation,
+            PRICES.may_load(deps.storage, &token)?,
+        ) {
+            (Some(max_deviation), Some(current)) => {
+                let quarantined = QUARANTINED_PRICES.may_load(deps.storage, &token)?;
+                within_deviation(current.price, median, max_deviation)?
+                    || match quarantined {
+                        Some(quarantined) => {
+                            within_deviation(quarantined.price, median, max_deviation)?
+                        }
+                        None => false,
+                    }
+            }
+            _ => true,
+        };
+        if accepted {
+            PRICES.save(deps.storage, &token, &update)?;
+            QUARANTINED_PRICES.remove(deps.storage, &token);
+            record_observation(
+                deps.storage,
+                &token,
+                Price {
+                    price: median,
+                    updated_at: now,
+                },
+                config.oracle.max_observations,
+            )?;
+        } else {
+            QUARANTINED_PRICES.save(deps.storage, &token, &update)?;
+        }
+
+        Ok(response
+            .add_attribute("median", median)
+            .add_attribute("quarantined", (!accepted).to_string()))
+    }
+}
+
+// Deposits are held to the asset's supply cap across all accounts, and isolated collateral
+// cannot share an account with any other
+fn add_collateral(
+    storage: &mut dyn Storage,
+    address: &str,
+    denom: &str,
+    amount: Uint128,
+) -> Result<Uint128, ContractError> {
+    let asset = COLLATERAL_ASSETS.load(storage, denom)?;
+    ensure_emode_asset(storage, address, denom)?;
+    for held in COLLATERALS
+        .prefix(address)
+        .keys(storage, None, None, Order::Ascending)
+    {
+        let held = held?;
+        if held == denom {
+            continue;
+        }
+        if asset.debt_ceiling.is_some() {
+            return Err(ContractError::IsolatedCollateral {
+                denom: denom.to_string(),
+            });
+        }
+        if COLLATERAL_ASSETS
+            .load(storage, &held)?
+            .debt_ceiling
+            .is_some()
+        {
+            return Err(ContractError::IsolatedCollateral { denom: held });
+        }
+    }
+
+    let total = TOTAL_COLLATERALS
+        .may_load(storage, denom)?
+        .unwrap_or_default()
+        .checked_add(amount)
+        .map_err(|_| ContractError::MathError {})?;
+    if let Some(cap) = asset.supply_cap {
+        if total > cap {
+            return Err(ContractError::SupplyCapExceeded {
+                denom: denom.to_string(),
+                cap,
+            });
+        }
+    }
+    TOTAL_COLLATERALS.save(storage, denom, &total)?;
+
+    COLLATERALS.update(storage, (address, denom), |current| {
+        current
+            .unwrap_or_default()
+            .checked_add(amount)
+            .map_err(|_| ContractError::MathError {})
+    })
+}
+
+fn remove_collateral(
+    storage: &mut dyn Storage,
+    address: &str,
+    denom: &str,
+    amount: Uint128,
+) -> Result<Uint128, ContractError> {
+    let current = COLLATERALS
+        .may_load(storage, (address, denom))?
+        .ok_or(ContractError::TokenNotFound {})?;
+    //checking if withdraw amount is greater than current collateral
+    let remaining = current
+        .checked_sub(amount)
+        .map_err(|_| ContractError::InsufficientFunds {})?;
+
+    if remaining.is_zero() {
+        COLLATERALS.remove(storage, (address, denom));
+    } else {
+        COLLATERALS.save(storage, (address, denom), &remaining)?;
+    }
+    TOTAL_COLLATERALS.update(storage, denom, |total| -> StdResult<_> {
+        Ok(total.unwrap_or_default().saturating_sub(amount))
+    })?;
+    Ok(remaining)
+}
+
+fn ensure_collateral_asset(storage: &dyn Storage, denom: &str) -> Result<(), ContractError> {
+    if !COLLATERAL_ASSETS.has(storage, denom) {
+        return Err(ContractError::UnsupportedCollateral {
+            denom: denom.to_string(),
+        });
+    }
+    Ok(())
+}
+
+// The e-mode category the account is in, if any
+fn account_emode(storage: &dyn Storage, address: &str) -> StdResult<Option<EModeCategory>> {
+    match ACCOUNTS
+        .may_load(storage, address)?
+        .and_then(|account| account.emode_category)
+    {
+        Some(id) => EMODE_CATEGORIES.may_load(storage, id),
+        None => Ok(None),
+    }
+}
+
+// Accounts in e-mode may only take positions in assets of their category
+fn ensure_emode_asset(
+    storage: &dyn Storage,
+    address: &str,
+    denom: &str,
+) -> Result<(), ContractError> {
+    if let Some(emode) = account_emode(storage, address)? {
+        if !emode.assets.iter().any(|asset| asset == denom) {
+            return Err(ContractError::NotInEModeCategory {
+                denom: denom.to_string(),
+                category: emode.id,
+            });
+        }
+    }
+    Ok(())
+}
+
+// The asset's risk parameters, replaced by the category's for accounts in e-mode
+fn risk_params(asset: CollateralAsset, emode: Option<&EModeCategory>) -> CollateralAsset {
+    match emode {
+        Some(emode) => CollateralAsset {
+            max_ltv: emode.max_ltv,
+            liquidation_threshold: emode.liquidation_threshold,
+            liquidation_bonus: emode.liquidation_bonus,
+            ..asset
+        },
+        None => asset,
+    }
+}
+
+// The isolated collateral backing the account, if it is in isolation mode
+fn isolated_collateral(storage: &dyn Storage, address: &str) -> StdResult<Option<CollateralAsset>> {
+    for denom in COLLATERALS
+        .prefix(address)
+        .keys(storage, None, None, Order::Ascending)
+    {
+        let asset = COLLATERAL_ASSETS.load(storage, &denom?)?;
+        if asset.debt_ceiling.is_some() {
+            return Ok(Some(asset));
+        }
+    }
+    Ok(None)
+}
+
+// Isolated debt is counted at face value so borrowing and repaying the same amount cancel out
+fn isolated_debt_value(asset: &BorrowAsset, amount: Uint128) -> StdResult<Uint128> {
+    math::usd_value(amount, asset.decimals, Uint128::one(), 0, Rounding::Up)
+}
+
+fn reduce_isolated_debt(
+    storage: &mut dyn Storage,
+    address: &str,
+    asset: &BorrowAsset,
+    repaid: Uint128,
+) -> StdResult<()> {
+    if let Some(denom) = ISOLATED_ACCOUNTS.may_load(storage, address)? {
+        let repaid = isolated_debt_value(asset, repaid)?;
+        // repayments include interest, which was never counted against the ceiling
+        ISOLATED_DEBT.update(storage, &denom, |debt| -> StdResult<_> {
+            Ok(debt.unwrap_or_default().saturating_sub(repaid))
+        })?;
+    }
+    Ok(())
+}
+
+fn ensure_borrow_asset(storage: &dyn Storage, denom: &str) -> Result<BorrowAsset, ContractError> {
+    BORROW_ASSETS
+        .may_load(storage, denom)?
+        .ok_or_else(|| ContractError::UnsupportedBorrowAsset {
+            denom: denom.to_string(),
+        })
+}
+
+// A pegged asset cannot be borrowed while its spot price is off the peg, where it would be lent
+// out at a value the market no longer gives it
+fn ensure_pegged(deps: Deps, env: &Env, asset: &BorrowAsset) -> Result<(), ContractError> {
+    let peg = match &asset.peg {
+        Some(peg) => peg,
+        None => return Ok(()),
+    };
+    // an old price says nothing about the peg now
+    let price = spot_price(deps, env, &asset.denom)?;
+    let oracle = CONFIG.load(deps.storage)?.oracle;
+    if is_stale(&oracle, &price, env.block.time.seconds()) {
+        return Err(ContractError::StalePrice {
+            denom: asset.denom.clone(),
+        });
+    }
+    if !within_deviation(peg.target, price.price, peg.max_deviation)? {
+        return Err(ContractError::Depegged {
+            denom: asset.denom.clone(),
+        });
+    }
+    Ok(())
+}
+
+// The single coin attached to a repayment, whose denom picks the debt it pays down
+fn repayment(info: &MessageInfo) -> Result<Coin, ContractError> {
+    match info.funds.as_slice() {
+        [coin] if !coin.amount.is_zero() => Ok(coin.clone()),
+        _ => Err(ContractError::NoRepayment {}),
+    }
+}
+
+// Refuses coins attached to a message that has no use for them, rather than keeping them
+fn nonpayable(info: &MessageInfo) -> Result<(), ContractError> {
+    if !info.funds.is_empty() {
+        return Err(ContractError::NonPayable {});
+    }
+    Ok(())
+}
+
+// Loads a pool with interest accrued up to the current block
+fn load_pool(storage: &dyn Storage, env: &Env, id: &str) -> StdResult<Pool> {
+    let config = CONFIG.load(storage)?;
+    let mut pool = POOLS.load(storage, id)?;
+    accrue_interest(
+        &mut pool,
+        config.fees.reserve_factor,
+        env.block.time.seconds(),
+    )?;
+    // accrued interest moves utilization, so readers see it as of this block too
+    refresh_utilization(&mut pool, &config.utilization_thresholds);
+    Ok(pool)
+}
+
+// Stores the pool after refreshing its utilization from the current totals
+fn save_pool(storage: &mut dyn Storage, pool: &mut Pool) -> StdResult<()> {
+    let thresholds = CONFIG.load(storage)?.utilization_thresholds;
+    refresh_utilization(pool, &thresholds);
+    POOLS.save(storage, &pool.id, pool)
+}
+
+fn refresh_utilization(pool: &mut Pool, thresholds: &UtilizationThresholds) {
+    let supplied = pool.total_liquidity + pool.total_reserves;
+    pool.utilization_rate = if supplied.is_zero() {
+        Decimal::zero()
+    } else {
+        Decimal::from_ratio(pool.total_borrowed, supplied).min(Decimal::one())
+    };
+    pool.utilization = if pool.utilization_rate >= thresholds.high {
+        PoolUtilization::High
+    } else if pool.utilization_rate >= thresholds.medium {
+        PoolUtilization::Medium
+    } else {
+        PoolUtilization::Low
+    };
+}
+
+// Borrow asset held by the pool and available to lend out
+fn pool_cash(pool: &Pool) -> Uint128 {
+    (pool.total_liquidity + pool.total_reserves).saturating_sub(pool.total_borrowed)
+}
+
+// Grows the borrow index and pool totals by the interest owed since the last accrual
+fn accrue_interest(pool: &mut Pool, reserve_factor: Decimal, now: u64) -> StdResult<()> {
+    if now <= pool.last_accrual {
+        return Ok(());
+    }
+    let borrow_index =
+        math::compound_index(pool.borrow_index, pool.borrow_rate, now - pool.last_accrual)?;
+    let total_borrowed = math::debt_at_index(pool.total_borrowed, pool.borrow_index, borrow_index)?;
+    let interest = total_borrowed - pool.total_borrowed;
+
+    pool.borrow_index = borrow_index;
+    // interest paid by borrowers is owed to the liquidity providers, minus the reserve cut
+    let reserves = math::mul_decimal(interest, reserve_factor, Rounding::Down)?;
+    pool.total_borrowed = total_borrowed;
+    pool.total_reserves += reserves;
+    pool.total_liquidity += interest - reserves;
+    pool.last_accrual = now;
+    Ok(())
+}
+
+// Outstanding debt of the account in the pool including accrued interest, rounded up
+fn account_debt(storage: &dyn Storage, address: &str, pool: &Pool) -> StdResult<Uint128> {
+    match DEBTS.may_load(storage, (address, &pool.id))? {
+        Some(debt) => math::debt_at_index(debt.principal, debt.borrow_index, pool.borrow_index),
+        None => Ok(Uint128::zero()),
+    }
+}
+
+// Records the account's debt in the pool as of the pool's current index
+fn save_debt(
+    storage: &mut dyn Storage,
+    address: &str,
+    pool: &Pool,
+    amount: Uint128,
+) -> StdResult<()> {
+    if amount.is_zero() {
+        DEBTS.remove(storage, (address, &pool.id));
+        return Ok(());
+    }
+    DEBTS.save(
+        storage,
+        (address, &pool.id),
+        &Debt {
+            principal: amount,
+            borrow_index: pool.borrow_index,
+        },
+    )
+}
+
+// A larger debt of an account that passed the pool's cash, cap and isolation checks, yet to be
+// saved
+struct DebtIncrease {
+    pool: Pool,
+    amount: Uint128,
+    // the account's debt in the pool once increased
+    debt: Uint128,
+    // isolated collateral backing the account and its total debt once increased
+    isolated_debt: Option<(String, Uint128)>,
+}
+
+fn debt_increase(
+    deps: Deps,
+    env: &Env,
+    address: &str,
+    asset: &BorrowAsset,
+    amount: Uint128,
+) -> Result<DebtIncrease, ContractError> {
+    let pool = load_pool(deps.storage, env, &asset.denom)?;
+
+    // Verify sufficient funds in the pool
+    if pool_cash(&pool) < amount {
+        return Err(ContractError::InsufficientFunds {});
+    }
+    if let Some(cap) = asset.borrow_cap {
+        if pool.total_borrowed + amount > cap {
+            return Err(ContractError::BorrowCapExceeded {
+                denom: asset.denom.clone(),
+                cap,
+            });
+        }
+    }
+
+    // isolated collateral only backs designated assets, up to its debt ceiling
+    let isolated_debt = match isolated_collateral(deps.storage, address)? {
+        Some(isolated) => {
+            if !asset.isolation_borrowable {
+                return Err(ContractError::NotBorrowableInIsolation {
+                    denom: asset.denom.clone(),
+                });
+            }
+            let isolated_debt = ISOLATED_DEBT
+                .may_load(deps.storage, &isolated.denom)?
+                .unwrap_or_default()
+                + isolated_debt_value(asset, amount)?;
+            if isolated_debt > isolated.debt_ceiling.unwrap_or_default() {
+                return Err(ContractError::DebtCeilingExceeded {
+                    denom: isolated.denom,
+                });
+            }
+            Some((isolated.denom, isolated_debt))
+        }
+        None => None,
+    };
+
+    let debt = account_debt(deps.storage, address, &pool)? + amount;
+    Ok(DebtIncrease {
+        pool,
+        amount,
+        debt,
+        isolated_debt,
+    })
+}
+
+fn save_debt_increase(
+    storage: &mut dyn Storage,
+    address: &str,
+    increase: DebtIncrease,
+) -> StdResult<Pool> {
+    let DebtIncrease {
+        mut pool,
+        amount,
+        debt,
+        isolated_debt,
+    } = increase;
+    match isolated_debt {
+        Some((denom, isolated_debt)) => {
+            ISOLATED_DEBT.save(storage, &denom, &isolated_debt)?;
+            ISOLATED_ACCOUNTS.save(storage, address, &denom)?;
+        }
+        // an account borrowing outside isolation has settled any debt it had in it
+        None => ISOLATED_ACCOUNTS.remove(storage, address),
+    }
+    save_debt(storage, address, &pool, debt)?;
+    pool.total_borrowed += amount;
+    save_pool(storage, &mut pool)?;
+    Ok(pool)
+}
+
+// Every outstanding debt of the account including interest accrued up to the current block
+fn account_debts(deps: Deps, env: &Env, address: &str) -> StdResult<Vec<Coin>> {
+    let denoms = DEBTS
+        .prefix(address)
+        .keys(deps.storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    let mut debts = vec![];
+    for denom in denoms {
+        let pool = load_pool(deps.storage, env, &denom)?;
+        let amount = account_debt(deps.storage, address, &pool)?;
+        if !amount.is_zero() {
+            debts.push(Coin { denom, amount });
+        }
+    }
+    Ok(debts)
+}
+
+// USD value of the account's debts at their latest prices, rounded up so it is never understated
+// against a limit. Pegged assets can be valued at no less than their peg.
+fn debts_value(
+    deps: Deps,
+    env: &Env,
+    address: &str,
+    debts: &[Coin],
+) -> Result<Uint128, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let emode = account_emode(deps.storage, address)?;
+    let mut value = Uint128::zero();
+    for debt in debts {
+        let asset = BORROW_ASSETS.load(deps.storage, &debt.denom)?;
+        value += math::usd_value(
+            debt.amount,
+            asset.decimals,
+            debt_price(deps, env, &asset, emode.as_ref())?,
+            config.oracle.price_decimals,
+            Rounding::Up,
+        )?;
+    }
+    Ok(value)
+}
+
+// Price debt in the asset is valued at, floored at its peg when configured to
+fn debt_price(
+    deps: Deps,
+    env: &Env,
+    asset: &BorrowAsset,
+    emode: Option<&EModeCategory>,
+) -> Result<Uint128, ContractError> {
+    let price = asset_price(deps, env, &asset.denom, emode)?;
+    match &asset.peg {
+        Some(peg) if peg.floor_debt_value => Ok(price.max(peg.target)),
+        _ => Ok(price),
+    }
+}
+
+// Once an account has no collateral left, whatever it still owes cannot be recovered. The loss is
+// covered by the pool's reserves, then its insurance fund, then by slashing its safety module, and
+// finally by liquidity providers through a lower share price.
+fn write_off_bad_debt(
+    deps: DepsMut,
+    env: &Env,
+    borrower: &str,
+) -> Result<Vec<Event>, ContractError> {
+    if COLLATERALS
+        .prefix(borrower)
+        .keys(deps.storage, None, None, Order::Ascending)
+        .next()
+        .is_some()
+    {
+        return Ok(vec![]);
+    }
+
+    let config = CONFIG.load(deps.storage)?;
+    let mut events = vec![];
+    for debt in account_debts(deps.as_ref(), env, borrower)? {
+        let borrow_asset = BORROW_ASSETS.load(deps.storage, &debt.denom)?;
+        let mut pool = load_pool(deps.storage, env, &debt.denom)?;
+        let mut module = load_safety_module(deps.storage, &mut pool)?;
+        let insurance = INSURANCE_FUNDS
+            .may_load(deps.storage, &pool.id)?
+            .unwrap_or_default();
+        let from_reserves = debt.amount.min(pool.total_reserves);
+        let from_insurance = (debt.amount - from_reserves).min(insurance);
+        let slashable = math::mul_decimal(
+            module.total_staked,
+            config.safety_module.max_slash,
+            Rounding::Down,
+        )?;
+        let from_safety_module = (debt.amount - from_reserves - from_insurance).min(slashable);
+        let socialized = debt.amount - from_reserves - from_insurance - from_safety_module;
+
+        reduce_isolated_debt(deps.storage, borrower, &borrow_asset, debt.amount)?;
+        save_debt(deps.storage, borrower, &pool, Uint128::zero())?;
+        INSURANCE_FUNDS.save(deps.storage, &pool.id, &(insurance - from_insurance))?;
+        pool.total_reserves -= from_reserves;
+        pool.total_liquidity = pool.total_liquidity.saturating_sub(socialized);
+        pool.total_borrowed = pool.total_borrowed.saturating_sub(debt.amount);
+        module.total_staked -= from_safety_module;
+        save_pool(deps.storage, &mut pool)?;
+        save_safety_module(deps.storage, &mut module, &pool)?;
+
+        let id = BAD_DEBTS
+            .prefix(&pool.id)
+            .keys(deps.storage, None, None, Order::Descending)
+            .next()
+            .transpose()?
+            .map_or(1, |last| last + 1);
+        let bad_debt = BadDebt {
+            id,
+            borrower: borrower.to_string(),
+            amount: debt.amount,
+            from_reserves,
+            from_insurance,
+            from_safety_module,
+            socialized,
+            time: env.block.time.seconds(),
+        };
+        BAD_DEBTS.save(deps.storage, (&pool.id, id), &bad_debt)?;
+        TOTAL_BAD_DEBT.update(deps.storage, &pool.id, |total| -> StdResult<_> {
+            Ok(total.unwrap_or_default() + debt.amount)
+        })?;
+
+        events.push(
+            Event::new("bad_debt")
+                .add_attribute("pool_id", &pool.id)
+                .add_attribute("borrower", borrower)
+                .add_attribute("amount", debt.amount)
+                .add_attribute("from_reserves", from_reserves)
+                .add_attribute("from_insurance", from_insurance)
+                .add_attribute("from_safety_module", from_safety_module)
+                .add_attribute("socialized", socialized),
+        );
+    }
+    Ok(events)
+}
+
+// Loads the pool's safety module after paying its stakers their share of the reserves accrued
+// since the last visit. Both have to be saved afterwards.
+fn load_safety_module(storage: &dyn Storage, pool: &mut Pool) -> StdResult<SafetyModule> {
+    let config = CONFIG.load(storage)?;
+    let mut module = SAFETY_MODULES
+        .may_load(storage, &pool.id)?
+        .unwrap_or(SafetyModule {
+            pool_id: pool.id.clone(),
+            total_staked: Uint128::zero(),
+            total_shares: Uint128::zero(),
+            last_reserves: pool.total_reserves,
+        });
+    // without stakers the protocol keeps everything
+    if !module.total_shares.is_zero() {
+        let accrued = pool.total_reserves.saturating_sub(module.last_reserves);
+        let reward =
+            math::mul_decimal(accrued, config.safety_module.reserve_share, Rounding::Down)?;
+        pool.total_reserves -= reward;
+        module.total_staked += reward;
+    }
+    module.last_reserves = pool.total_reserves;
+    Ok(module)
+}
+
+fn save_safety_module(
+    storage: &mut dyn Storage,
+    module: &mut SafetyModule,
+    pool: &Pool,
+) -> StdResult<()> {
+    module.last_reserves = pool.total_reserves;
+    SAFETY_MODULES.save(storage, &pool.id, module)
+}
+
+struct Seizure {
+    debt: Uint128,
+    repaid: Uint128,
+    seized: Uint128,
+    bonus: Decimal,
+    // collateral seized beyond what covers the repaid value
+    bonus_amount: Uint128,
+}
+
+// Debt repaid and collateral seized when up to `amount` of an unhealthy account's debt in the
+// pool's asset is liquidated. The collateral covers the repaid value plus the auctioned bonus, up
+// to what the account holds.
+fn seizure(
+    mut deps: DepsMut,
+    env: &Env,
+    borrower: &str,
+    collateral_denom: &str,
+    borrow_asset: &BorrowAsset,
+    pool: &Pool,
+    amount: Uint128,
+) -> Result<Seizure, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let (debt_value, limit) = account_health(deps.as_ref(), env, borrower)?;
+    let started_at =
+        observe_health(deps.branch(), env, borrower)?.ok_or(ContractError::AccountHealthy {})?;
+    let progress = auction_progress(
+        &config.liquidation,
+        env.block.time.seconds() - started_at,
+        debt_value,
+        limit,
+    );
+    let debt = account_debt(deps.storage, borrower, pool)?;
+    if debt.is_zero() {
+        return Err(ContractError::NoRepayment {});
+    }
+
+    let emode = account_emode(deps.storage, borrower)?;
+    let asset = COLLATERAL_ASSETS
+        .may_load(deps.storage, collateral_denom)?
+        .ok_or_else(|| ContractError::UnsupportedCollateral {
+            denom: collateral_denom.to_string(),
+        })?;
+    let asset = risk_params(asset, emode.as_ref());
+    // the bonus is auctioned up to the collateral's maximum
+    let bonus = asset.liquidation_bonus * progress;
+    let price = asset_price(deps.as_ref(), env, collateral_denom, emode.as_ref())?;
+    let debt_price = asset_price(deps.as_ref(), env, &borrow_asset.denom, emode.as_ref())?;
+    let available = COLLATERALS
+        .may_load(deps.storage, (borrower, collateral_denom))?
+        .ok_or(ContractError::TokenNotFound {})?;
+
+    let mut repaid = amount.min(debt);
+    let repay_value = math::usd_value(
+        repaid,
+        borrow_asset.decimals,
+        debt_price,
+        config.oracle.price_decimals,
+        Rounding::Down,
+    )?;
+    let mut seized = math::liquidation_collateral(
+        repay_value,
+        bonus,
+        price,
+        config.oracle.price_decimals,
+        asset.decimals,
+    )?;
+    if seized > available {
+        seized = available;
+        let repay_value = math::liquidation_repay(
+            seized,
+            bonus,
+            price,
+            config.oracle.price_decimals,
+            asset.decimals,
+        )?;
+        repaid = math::amount_for_value(
+            repay_value,
+            borrow_asset.decimals,
+            debt_price,
+            config.oracle.price_decimals,
+            Rounding::Up,
+        )?
+        .min(repaid);
+    }
+    if seized.is_zero() {
+        return Err(ContractError::InsufficientFunds {});
+    }
+    let covered = math::amount_for_value(
+        math::usd_value(
+            repaid,
+            borrow_asset.decimals,
+            debt_price,
+            config.oracle.price_decimals,
+            Rounding::Down,
+        )?,
+        asset.decimals,
+        price,
+        config.oracle.price_decimals,
+        Rounding::Up,
+    )?;
+    Ok(Seizure {
+        debt,
+        repaid,
+        seized,
+        bonus,
+        bonus_amount: seized.saturating_sub(covered),
+    })
+}
+
+// USD value of the account's debts and the limit above which it can be liquidated
+fn account_health(
+    deps: Deps,
+    env: &Env,
+    address: &str,
+) -> Result<(Uint128, Uint128), ContractError> {
+    let debts = account_debts(deps, env, address)?;
+    Ok((
+        debts_value(deps, env, address, &debts)?,
+        liquidation_limit(deps, env, address)?,
+    ))
+}
+
+// Records the block time an account is first seen unhealthy and forgets it once the account is
+// healthy again. Returns when the running liquidation auction started, if any.
+fn observe_health(deps: DepsMut, env: &Env, address: &str) -> Result<Option<u64>, ContractError> {
+    let (debt_value, limit) = account_health(deps.as_ref(), env, address)?;
+    if debt_value <= limit {
+        LIQUIDATION_STARTS.remove(deps.storage, address);
+        return Ok(None);
+    }
+    let now = env.block.time.seconds();
+    let started_at = auction_start(deps.as_ref(), address, now)?;
+    LIQUIDATION_STARTS.save(
+        deps.storage,
+        address,
+        &LiquidationStart {
+            started_at,
+            observed_at: now,
+        },
+    )?;
+    Ok(Some(started_at))
+}
+
+// Start of the auction an unhealthy account is in at `now`. Prices can recover and fall again
+// without anyone touching the account, so an auction left unobserved for longer than it runs
+// starts over rather than paying the full bonus straight away.
+fn auction_start(deps: Deps, address: &str, now: u64) -> StdResult<u64> {
+    let auction_duration = CONFIG.load(deps.storage)?.liquidation.auction_duration;
+    Ok(match LIQUIDATION_STARTS.may_load(deps.storage, address)? {
+        Some(start) if now - start.observed_at <= auction_duration => start.started_at,
+        _ => now,
+    })
+}
+
+// Re-examines a running liquidation auction after the account was topped up. Stale prices must not
+// block repayments, so the auction is then left for the next liquidation to settle.
+fn refresh_auction(deps: DepsMut, env: &Env, address: &str) -> Result<(), ContractError> {
+    if !LIQUIDATION_STARTS.has(deps.storage, address) {
+        return Ok(());
+    }
+    match observe_health(deps, env, address) {
+        Ok(_) | Err(ContractError::StalePrice { .. }) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+// Share of the maximum liquidation bonus on offer. It grows linearly over the auction and starts
+// ahead by how far the debt exceeds the liquidation limit, relative to that limit.
+fn auction_progress(
+    liquidation: &LiquidationConfig,
+    elapsed: u64,
+    debt_value: Uint128,
+    limit: Uint128,
+) -> Decimal {
+    let time = Decimal::from_ratio(
+        elapsed.min(liquidation.auction_duration),
+        liquidation.auction_duration,
+    );
+    let depth = if limit.is_zero() {
+        Decimal::one()
+    } else {
+        Decimal::checked_from_ratio(debt_value.saturating_sub(limit), limit)
+            .unwrap_or(Decimal::one())
+    };
+    (time + depth.min(Decimal::one())).min(Decimal::one())
+}
+
+// Maximum USD value of debt the account may owe given its current collateral
+fn borrow_limit(deps: Deps, env: &Env, address: &str) -> Result<Uint128, ContractError> {
+    collateral_limit(deps, env, address, |asset| asset.max_ltv)
+}
+
+// USD value of debt above which the account can be liquidated
+fn liquidation_limit(deps: Deps, env: &Env, address: &str) -> Result<Uint128, ContractError> {
+    collateral_limit(deps, env, address, |asset| asset.liquidation_threshold)
+}
+
+// Collateral value weighted by one of the risk parameters that apply to the account
+fn collateral_limit(
+    deps: Deps,
+    env: &Env,
+    address: &str,
+    weight: fn(&CollateralAsset) -> Decimal,
+) -> Result<Uint128, ContractError> {
+    let emode = account_emode(deps.storage, address)?;
+    let mut limit = Uint128::zero();
+    for item in COLLATERALS
+        .prefix(address)
+        .range(deps.storage, None, None, Order::Ascending)
+    {
+        let (denom, amount) = item?;
+        let asset = risk_params(
+            COLLATERAL_ASSETS.load(deps.storage, &denom)?,
+            emode.as_ref(),
+        );
+        let value = get_collateral_value(deps, env, &Coin { denom, amount }, emode.as_ref())?;
+        limit += math::mul_decimal(value, weight(&asset), Rounding::Down)?;
+    }
+    Ok(limit)
+}
+
+// Whether `price` is no further from `reference` than the allowed share of it
+fn within_deviation(reference: Uint128, price: Uint128, max_deviation: Decimal) -> StdResult<bool> {
+    Ok(reference.abs_diff(price) <= math::mul_decimal(reference, max_deviation, Rounding::Down)?)
+}
+
+// Feeders and quorum pricing a denom, the oracle's default ones unless it has a feed of its own
+fn price_feed<'a>(oracle: &'a OracleConfig, denom: &str) -> (&'a [Addr], u32) {
+    oracle
+        .feeds
+        .iter()
+        .find(|feed| feed.denom == denom)
+        .map(|feed| (feed.feeders.as_slice(), feed.quorum))
+        .unwrap_or((oracle.feeders.as_slice(), oracle.quorum))
+}
+
+fn is_stale(oracle: &OracleConfig, price: &Price, now: u64) -> bool {
+    now > price.updated_at + oracle.max_age
+}
+
+// Price positions are valued at: the time-weighted average of the feeders' accepted prices over
+// the oracle's window, so a spike within a block barely moves it. Prices read from other
+// contracts are used as they are.
+fn fetch_price(deps: Deps, env: &Env, denom: &str) -> Result<Price, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let spot = spot_price(deps, env, denom)?;
+    let external = config
+        .oracle
+        .sources
+        .iter()
+        .any(|source| source.denom == denom);
+    if config.oracle.twap_window == 0 || external {
+        return Ok(spot);
+    }
+    let now = env.block.time.seconds();
+    let history: Vec<(u64, Uint128)> = observations(deps.storage, denom)?
+        .into_iter()
+        .map(|observation| (observation.updated_at, observation.price))
+        .collect();
+    let twap =
+        math::time_weighted_average(&history, now.saturating_sub(config.oracle.twap_window), now)?;
+    Ok(Price {
+        price: twap.unwrap_or(spot.price),
+        ..spot
+    })
+}
+
+// Accepted prices of a denom from the oldest to the newest
+fn observations(storage: &dyn Storage, denom: &str) -> StdResult<Vec<Price>> {
+    let ring = OBSERVATIONS.may_load(storage, denom)?.unwrap_or_default();
+    let (newer, older) = ring.prices.split_at(ring.next as usize);
+    Ok(older.iter().chain(newer).cloned().collect())
+}
+
+// Writes an accepted price over the oldest observation once the ring buffer is full. Prices
+// accepted within the same block replace each other.
+fn record_observation(
+    storage: &mut dyn Storage,
+    denom: &str,
+    price: Price,
+    capacity: u32,
+) -> StdResult<()> {
+    let mut ring = OBSERVATIONS.may_load(storage, denom)?.unwrap_or_default();
+    let (len, next, capacity) = (ring.prices.len(), ring.next as usize, capacity as usize);
+    let newest = (next + len).checked_sub(1).map(|slot| slot % len.max(1));
+    match newest {
+        Some(newest) if ring.prices[newest].updated_at == price.updated_at => {
+            ring.prices[newest] = price;
+        }
+        _ if len < capacity => {
+            ring.prices.insert(next, price);
+            ring.next = ((next + 1) % ring.prices.len()) as u32;
+        }
+        _ if len == capacity => {
+            ring.prices[next] = price;
+            ring.next = ((next + 1) % capacity) as u32;
+        }
+        _ => {
+            // the buffer shrank, so only the newest observations are kept
+            let mut prices = observations(storage, denom)?;
+            prices.drain(..len + 1 - capacity);
+            prices.push(price);
+            ring = Observations { prices, next: 0 };
+        }
+    }
+    OBSERVATIONS.save(storage, denom, &ring)
+}
+
+// Latest price from the asset's source: the feeders' accepted median, or a read of the external
+// contract it is configured with
+fn spot_price(deps: Deps, env: &Env, denom: &str) -> Result<Price, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let decimals = config.oracle.price_decimals;
+    let source = config
+        .oracle
+        .sources
+        .iter()
+        .find(|source| source.denom == denom)
+        .map(|source| &source.source);
+    match source {
+        None => PRICES
+            .may_load(deps.storage, denom)?
+            .ok_or_else(|| ContractError::PriceNotFound {
+                denom: denom.to_string(),
+            }),
+        Some(PriceSource::Pyth {
+            contract,
+            price_feed_id,
+        }) => oracle::pyth_price(deps.querier, contract, price_feed_id, denom, decimals),
+        Some(PriceSource::Band { contract, symbol }) => {
+            oracle::band_price(deps.querier, contract, symbol, denom, decimals)
+        }
+        Some(PriceSource::DexTwap {
+            contract,
+            quote_denom,
+            window,
+        }) => {
+            let twap = oracle::dex_twap(deps.querier, contract, denom, quote_denom, *window)?;
+            // the pool's price is only as fresh as the quote asset's
+            let quote = spot_price(deps, env, quote_denom)?;
+            Ok(Price {
+                price: math::price_from_unit_price(
+                    twap,
+                    asset_decimals(deps.storage, denom)?,
+                    quote.price,
+                    asset_decimals(deps.storage, quote_denom)?,
+                )?,
+                updated_at: quote.updated_at.min(env.block.time.seconds()),
+            })
+        }
+    }
+}
+
+// Decimals of a listed collateral or borrowable asset
+fn asset_decimals(storage: &dyn Storage, denom: &str) -> Result<u8, ContractError> {
+    if let Some(asset) = COLLATERAL_ASSETS.may_load(storage, denom)? {
+        return Ok(asset.decimals);
+    }
+    match BORROW_ASSETS.may_load(storage, denom)? {
+        Some(asset) => Ok(asset.decimals),
+        None => Err(invalid_config("twap assets have to be listed")),
+    }
+}
+
+// Latest price, refusing one older than the oracle's max age. Anything valued with it,
+// borrowing and liquidations included, is paused until the source catches up.
+fn load_price(deps: Deps, env: &Env, denom: &str) -> Result<Uint128, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let price = fetch_price(deps, env, denom)?;
+    if is_stale(&config.oracle, &price, env.block.time.seconds()) {
+        return Err(ContractError::StalePrice {
+            denom: denom.to_string(),
+        });
+    }
+    Ok(price.price)
+}
+
+// Price of an asset, or of the e-mode category's shared price source when it has one
+fn asset_price(
+    deps: Deps,
+    env: &Env,
+    denom: &str,
+    emode: Option<&EModeCategory>,
+) -> Result<Uint128, ContractError> {
+    match emode.and_then(|emode| emode.price_source.as_deref()) {
+        Some(source) => load_price(deps, env, source),
+        None => load_price(deps, env, denom),
+    }
+}
+
+// USD value of the collateral at its source's latest price, normalized by the asset's
+// decimals so collaterals of any precision add up
+fn get_collateral_value(
+    deps: Deps,
+    env: &Env,
+    collateral: &Coin,
+    emode: Option<&EModeCategory>,
+) -> Result<Uint128, ContractError> {
+    let config = CONFIG.load(deps.storage)?;
+    let asset = COLLATERAL_ASSETS.load(deps.storage, &collateral.denom)?;
+    let price = asset_price(deps, env, &collateral.denom, emode)?;
+    Ok(math::usd_value(
+        collateral.amount,
+        asset.decimals,
+        price,
+        config.oracle.price_decimals,
+        Rounding::Down,
+    )?)
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, env: Env, msg: Reply) -> Result<Response, ContractError> {
+    match msg.id {
+        QUEUE_LIQUIDATION_REPLY_ID => execute::settle_queue_liquidation(deps, env),
+        LEVERAGE_REPLY_ID => execute::settle_leverage(deps, env),
+        COLLATERAL_SWAP_REPLY_ID => execute::settle_collateral_swap(deps, env),
+        id => Err(StdError::generic_err(format!("unknown reply id {}", id)).into()),
+    }
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        QueryMsg::GetAccount { address } => {
+            to_json_binary(&query::get_account(deps, env, address)?)
+        }
+        QueryMsg::GetCollaterals { address } => {
+            to_json_binary(&query::get_collaterals(deps, address)?)
+        }
+        QueryMsg::GetConfig {} => to_json_binary(&query::get_config(deps)?),
+        QueryMsg::GetPool { id } => to_json_binary(&query::get_pool(deps, env, id)?),
+        QueryMsg::GetMarket { denom } => to_json_binary(&query::get_market(deps, env, denom)?),
+        QueryMsg::GetPrice { denom } => to_json_binary(&query::get_price(deps, env, denom)?),
+        QueryMsg::GetFeederPrices { denom } => {
+            to_json_binary(&query::get_feeder_prices(deps, env, denom)?)
+        }
+        QueryMsg::GetSafetyModule { pool_id } => {
+            to_json_binary(&query::get_safety_module(deps, env, pool_id)?)
+        }
+        QueryMsg::GetStaker { address, pool_id } => {
+            to_json_binary(&query::get_staker(deps, env, address, pool_id)?)
+        }
+        QueryMsg::GetBadDebt { pool_id } => to_json_binary(&query::get_bad_debt(deps, pool_id)?),
+        QueryMsg::MaxBorrowable { address, pool_id } => {
+            to_json_binary(&query::max_borrowable(deps, env, address, pool_id)?)
+        }
+        QueryMsg::MaxWithdrawable { address, denom } => {
+            to_json_binary(&query::max_withdrawable(deps, env, address, denom)?)
+        }
+        QueryMsg::SimulateBorrow {
+            address,
+            borrow_denom,
+            borrow_amount,
+            collateral,
+        } => to_json_binary(&query::simulate_borrow(
+            deps,
+            env,
+            address,
+            borrow_denom,
+            borrow_amount,
+            collateral,
+        )?),
+        QueryMsg::SimulateRepay { address, amount } => {
+            to_json_binary(&query::simulate_repay(deps, env, address, amount)?)
+        }
+        QueryMsg::SimulateWithdrawCollateral {
+            address,
+            denom,
+            amount,
+        } => to_json_binary(&query::simulate_withdraw_collateral(
+            deps, env, address, denom, amount,
+        )?),
+        QueryMsg::SimulateLiquidation {
+            liquidator,
+            borrower,
+            collateral_denom,
+            amount,
+        } => to_json_binary(&query::simulate_liquidation(
+            deps,
+            env,
+            liquidator,
+            borrower,
+            collateral_denom,
+            amount,
+        )?),
+        QueryMsg::GetLiquidation { address } => {
+            to_json_binary(&query::get_liquidation(deps, env, address)?)
+        }
+    }
+}
+
+pub mod query {
+    use super::*;
+
+    // Debts and their value are as of the current block
+    pub fn get_account(deps: Deps, env: Env, address: String) -> StdResult<AccountResponse> {
+        let account = ACCOUNTS.load(deps.storage, &address)?;
+        let debts = account_debts(deps, &env, &address)?;
+        let health = || -> Result<_, ContractError> {
+            Ok((
+                debts_value(deps, &env, &address, &debts)?,
+                borrow_limit(deps, &env, &address)?,
+                liquidation_limit(deps, &env, &address)?,
+            ))
+        };
+        let (debt_value, borrow_limit, liquidation_limit) =
+            health().map_err(|err| StdError::generic_err(err.to_string()))?;
+
+        Ok(AccountResponse {
+            address: account.address,
+            emode_category: account.emode_category,
+            debts,
+            debt_value,
+            borrow_limit,
+            liquidation_limit,
+        })
+    }
+
+    pub fn get_config(deps: Deps) -> StdResult<ConfigResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        let borrow_assets = BORROW_ASSETS
+            .range(deps.storage, None, None, Order::Ascending)
+            .map(|item| {
+                let (denom, asset) = item?;
+                let pool = POOLS.load(deps.storage, &denom)?;
+                Ok(BorrowAssetConfig {
+                    denom,
+                    decimals: asset.decimals,
+                    borrow_rate: pool.borrow_rate,
+                    supply_cap: asset.supply_cap,
+                    borrow_cap: asset.borrow_cap,
+                    isolation_borrowable: asset.isolation_borrowable,
+                    peg: asset.peg,
+                })
+            })
+            .collect::<StdResult<Vec<_>>>()?;
+        let collateral_assets = COLLATERAL_ASSETS
+            .range(deps.storage, None, None, Order::Ascending)
+            .map(|item| item.map(|(_, asset)| asset))
+            .collect::<StdResult<Vec<_>>>()?;
+        let emode_categories = EMODE_CATEGORIES
+            .range(deps.storage, None, None, Order::Ascending)
+            .map(|item| item.map(|(_, category)| category))
+            .collect::<StdResult<Vec<_>>>()?;
+
+        Ok(ConfigResponse {
+            owner: config.owner,
+            borrow_assets,
+            collateral_assets,
+            emode_categories,
+            oracle: config.oracle,
+            fees: config.fees,
+            utilization_thresholds: config.utilization_thresholds,
+            liquidation: config.liquidation,
+            safety_module: config.safety_module,
+            dex: config.dex,
+        })
+    }
+
+    // Totals include interest accrued up to the current block, utilization as of the last update
+    pub fn get_pool(deps: Deps, env: Env, id: String) -> StdResult<Pool> {
+        load_pool(deps.storage, &env, &id)
+    }
+
+    pub fn get_market(deps: Deps, env: Env, denom: String) -> StdResult<MarketResponse> {
+        let collateral = COLLATERAL_ASSETS.may_load(deps.storage, &denom)?;
+        let borrow = BORROW_ASSETS.may_load(deps.storage, &denom)?;
+        if collateral.is_none() && borrow.is_none() {
+            return Err(StdError::not_found(format!("market {}", denom)));
+        }
+        let pool = match borrow {
+            Some(_) => Some(load_pool(deps.storage, &env, &denom)?),
+            None => None,
+        };
+
+        Ok(MarketResponse {
+            total_collateral: TOTAL_COLLATERALS
+                .may_load(deps.storage, &denom)?
+                .unwrap_or_default(),
+            collateral_cap: collateral.as_ref().and_then(|asset| asset.supply_cap),
+            total_supplied: pool
+                .as_ref()
+                .map(|pool| pool.total_liquidity)
+                .unwrap_or_default(),
+            supply_cap: borrow.as_ref().and_then(|asset| asset.supply_cap),
+            total_borrowed: pool
+                .as_ref()
+                .map(|pool| pool.total_borrowed)
+                .unwrap_or_default(),
+            borrow_cap: borrow.and_then(|asset| asset.borrow_cap),
+            insurance_fund: INSURANCE_FUNDS
+                .may_load(deps.storage, &denom)?
+                .unwrap_or_default(),
+            isolated_debt: ISOLATED_DEBT
+                .may_load(deps.storage, &denom)?
+                .unwrap_or_default(),
+            debt_ceiling: collateral.and_then(|asset| asset.debt_ceiling),
+            denom,
+        })
+    }
+
+    pub fn get_price(deps: Deps, env: Env, denom: String) -> StdResult<PriceResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        let price =
+            spot_price(deps, &env, &denom).map_err(|err| StdError::generic_err(err.to_string()))?;
+        let twap = fetch_price(deps, &env, &denom)
+            .map_err(|err| StdError::generic_err(err.to_string()))?;
+        Ok(PriceResponse {
+            price: price.price,
+            twap: twap.price,
+            updated_at: price.updated_at,
+            stale: is_stale(&config.oracle, &price, env.block.time.seconds()),
+            quarantined: QUARANTINED_PRICES.may_load(deps.storage, &denom)?,
+            denom,
+        })
+    }
+
+    // Every feeder allowed to price the denom, with or without a submission
+    pub fn get_feeder_prices(
+        deps: Deps,
+        env: Env,
+        denom: String,
+    ) -> StdResult<FeederPricesResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        let (feeders, quorum) = price_feed(&config.oracle, &denom);
+        let prices = feeders
+            .iter()
+            .map(|feeder| {
+                let price = FEEDER_PRICES.may_load(deps.storage, (&denom, feeder))?;
+                Ok(FeederPrice {
+                    feeder: feeder.clone(),
+                    fresh: price.as_ref().is_some_and(|price| {
+                        !is_stale(&config.oracle, price, env.block.time.seconds())
+                    }),
+                    price,
+                })
+            })
+            .collect::<StdResult<Vec<_>>>()?;
+        Ok(FeederPricesResponse {
+            denom,
+            quorum,
+            prices,
+        })
+    }
+
+    // Includes the stakers' share of reserves accrued up to the current block
+    pub fn get_safety_module(deps: Deps, env: Env, pool_id: String) -> StdResult<SafetyModule> {
+        let mut pool = load_pool(deps.storage, &env, &pool_id)?;
+        load_safety_module(deps.storage, &mut pool)
+    }
+
+    pub fn get_staker(
+        deps: Deps,
+        env: Env,
+        address: String,
+        pool_id: String,
+    ) -> StdResult<StakerResponse> {
+        let staker = STAKERS.load(deps.storage, (&address, &pool_id))?;
+        let module = get_safety_module(deps, env, pool_id)?;
+        Ok(StakerResponse {
+            value: math::shares_to_amount(
+                staker.shares,
+                module.total_shares,
+                module.total_staked,
+                Rounding::Down,
+            )?,
+            address: staker.address,
+            pool_id: staker.pool_id,
+            shares: staker.shares,
+            unstaking: staker.unstaking,
+            cooldown_end: staker.cooldown_end,
+        })
+    }
+
+    pub fn get_bad_debt(deps: Deps, pool_id: String) -> StdResult<BadDebtResponse> {
+        POOLS.load(deps.storage, &pool_id)?;
+        let history = BAD_DEBTS
+            .prefix(&pool_id)
+            .range(deps.storage, None, None, Order::Ascending)
+            .map(|item| item.map(|(_, bad_debt)| bad_debt))
+            .collect::<StdResult<Vec<_>>>()?;
+
+        Ok(BadDebtResponse {
+            total: TOTAL_BAD_DEBT
+                .may_load(deps.storage, &pool_id)?
+                .unwrap_or_default(),
+            history,
+            pool_id,
+        })
+    }
+
+    // The auction as it would stand for a liquidation in the current block
+    pub fn get_liquidation(
+        deps: Deps,
+        env: Env,
+        address: String,
+    ) -> StdResult<LiquidationResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        let (debt_value, limit) = account_health(deps, &env, &address)
+            .map_err(|err| StdError::generic_err(err.to_string()))?;
+        if debt_value <= limit {
+            return Ok(LiquidationResponse {
+                started_at: None,
+                progress: Decimal::zero(),
+            });
+        }
+        // an auction that is not recorded yet, or gone stale, starts with this block
+        let now = env.block.time.seconds();
+        let started_at = auction_start(deps, &address, now)?;
+
+        Ok(LiquidationResponse {
+            started_at: Some(started_at),
+            progress: auction_progress(&config.liquidation, now - started_at, debt_value, limit),
+        })
+    }
+
+    // Inverts the borrow limit check with the same rounding, so borrowing exactly this amount
+    // goes through
+    pub fn max_borrowable(
+        deps: Deps,
+        env: Env,
+        address: String,
+        pool_id: String,
+    ) -> StdResult<MaxAmountResponse> {
+        let amount = max_borrow_amount(deps, &env, &address, &pool_id)
+            .map_err(|err| StdError::generic_err(err.to_string()))?;
+        Ok(MaxAmountResponse {
+            denom: pool_id,
+            amount,
+        })
+    }
+
+    fn max_borrow_amount(
+        deps: Deps,
+        env: &Env,
+        address: &str,
+        pool_id: &str,
+    ) -> Result<Uint128, ContractError> {
+        ACCOUNTS.load(deps.storage, address)?;
+        let config = CONFIG.load(deps.storage)?;
+        let asset = ensure_borrow_asset(deps.storage, pool_id)?;
+        if ensure_emode_asset(deps.storage, address, pool_id).is_err()
+            || ensure_pegged(deps, env, &asset).is_err()
+        {
+            return Ok(Uint128::zero());
+        }
+        let pool = load_pool(deps.storage, env, pool_id)?;
+
+        // the largest debt in the asset the limit leaves room for, beside the other debts
+        let mut others = account_debts(deps, env, address)?;
+        others.retain(|debt| debt.denom != pool_id);
+        let headroom = borrow_limit(deps, env, address)?
+            .saturating_sub(debts_value(deps, env, address, &others)?);
+        let emode = account_emode(deps.storage, address)?;
+        let max_debt = math::amount_for_value(
+            headroom,
+            asset.decimals,
+            debt_price(deps, env, &asset, emode.as_ref())?,
+            config.oracle.price_decimals,
+            Rounding::Down,
+        )?;
+        let mut amount = max_debt.saturating_sub(account_debt(deps.storage, address, &pool)?);
+
+        amount = amount.min(pool_cash(&pool));
+        if let Some(cap) = asset.borrow_cap {
+            amount = amount.min(cap.saturating_sub(pool.total_borrowed));
+        }
+        if let Some(isolated) = isolated_collateral(deps.storage, address)? {
+            if !asset.isolation_borrowable {
+                return Ok(Uint128::zero());
+            }
+            let room = isolated.debt_ceiling.unwrap_or_default().saturating_sub(
+                ISOLATED_DEBT
+                    .may_load(deps.storage, &isolated.denom)?
+                    .unwrap_or_default(),
+            );
+            let ceiling =
+                math::amount_for_value(room, asset.decimals, Uint128::one(), 0, Rounding::Down)?;
+            amount = amount.min(ceiling);
+        }
+        Ok(amount)
+    }
+
+    // Inverts the borrow limit check with the same rounding, so withdrawing exactly this amount
+    // goes through
+    pub fn max_withdrawable(
+        deps: Deps,
+        env: Env,
+        address: String,
+        denom: String,
+    ) -> StdResult<MaxAmountResponse> {
+        let amount = max_withdraw_amount(deps, &env, &address, &denom)
+            .map_err(|err| StdError::generic_err(err.to_string()))?;
+        Ok(MaxAmountResponse { denom, amount })
+    }
+
+    fn max_withdraw_amount(
+        deps: Deps,
+        env: &Env,
+        address: &str,
+        denom: &str,
+    ) -> Result<Uint128, ContractError> {
+        ACCOUNTS.load(deps.storage, address)?;
+        let held = COLLATERALS
+            .may_load(deps.storage, (address, denom))?
+            .unwrap_or_default();
+        let debts = account_debts(deps, env, address)?;
+        if held.is_zero() || debts.is_empty() {
+            return Ok(held);
+        }
+
+        let config = CONFIG.load(deps.storage)?;
+        let emode = account_emode(deps.storage, address)?;
+        let asset = risk_params(COLLATERAL_ASSETS.load(deps.storage, denom)?, emode.as_ref());
+        let collateral = Coin {
+            denom: denom.to_string(),
+            amount: held,
+        };
+        let own_limit = math::mul_decimal(
+            get_collateral_value(deps, env, &collateral, emode.as_ref())?,
+            asset.max_ltv,
+            Rounding::Down,
+        )?;
+        let other_limit = borrow_limit(deps, env, address)?.saturating_sub(own_limit);
+        // the limit the collateral still has to provide once part of it is gone
+        let needed = debts_value(deps, env, address, &debts)?.saturating_sub(other_limit);
+        if needed.is_zero() {
+            return Ok(held);
+        }
+        if asset.max_ltv.is_zero() {
+            return Ok(Uint128::zero());
+        }
+        let value = math::mul_div(
+            needed,
+            Decimal::one().atomics(),
+            asset.max_ltv.atomics(),
+            Rounding::Up,
+        )?;
+        let price = asset_price(deps, env, denom, emode.as_ref())?;
+        let remaining = math::amount_for_value(
+            value,
+            asset.decimals,
+            price,
+            config.oracle.price_decimals,
+            Rounding::Up,
+        )?;
+        Ok(held.saturating_sub(remaining))
+    }
+
+    pub fn simulate_borrow(
+        deps: Deps,
+        env: Env,
+        address: String,
+        borrow_denom: String,
+        borrow_amount: Uint128,
+        collateral: Option<Coin>,
+    ) -> StdResult<SimulationResponse> {
+        let (collateral_denom, funds) = match collateral {
+            Some(coin) => (coin.denom.clone(), vec![coin]),
+            None => (String::new(), vec![]),
+        };
+        simulate(deps, &env, &address, &address, |deps| {
+            execute::borrow(
+                deps,
+                env.clone(),
+                sender_info(&address, &funds),
+                borrow_denom,
+                borrow_amount,
+                collateral_denom,
+            )
+        })
+    }
+
+    pub fn simulate_repay(
+        deps: Deps,
+        env: Env,
+        address: String,
+        amount: Coin,
+    ) -> StdResult<SimulationResponse> {
+        simulate(deps, &env, &address, &address, |deps| {
+            execute::repay(deps, env.clone(), sender_info(&address, &[amount]))
+        })
+    }
+
+    pub fn simulate_withdraw_collateral(
+        deps: Deps,
+        env: Env,
+        address: String,
+        denom: String,
+        amount: Uint128,
+    ) -> StdResult<SimulationResponse> {
+        simulate(deps, &env, &address, &address, |deps| {
+            execute::withdraw_collateral(
+                deps,
+                env.clone(),
+                sender_info(&address, &[]),
+                denom,
+                amount,
+            )
+        })
+    }
+
+    // The health factor reported is the borrower's
+    pub fn simulate_liquidation(
+        deps: Deps,
+        env: Env,
+        liquidator: String,
+        borrower: String,
+        collateral_denom: String,
+        amount: Coin,
+    ) -> StdResult<SimulationResponse> {
+        let mut res = simulate(deps, &env, &liquidator, &borrower, |deps| {
+            execute::liquidate(
+                deps,
+                env.clone(),
+                sender_info(&liquidator, &[amount]),
+                borrower.clone(),
+                collateral_denom.clone(),
+            )
+        })?;
+        if res.error.is_none() {
+            let amount = res
+                .attributes
+                .iter()
+                .find(|attr| attr.key == "bonus_amount")
+                .map(|attr| attr.value.parse::<Uint128>())
+                .transpose()?
+                .unwrap_or_default();
+            res.liquidation_bonus = Some(Coin {
+                denom: collateral_denom,
+                amount,
+            });
+        }
+        Ok(res)
+    }
+
+    // Runs an execute handler against a scratch copy of the state, so it validates and computes
+    // exactly as the real operation would without anything being saved
+    fn simulate(
+        deps: Deps,
+        env: &Env,
+        sender: &str,
+        account: &str,
+        handler: impl FnOnce(DepsMut) -> Result<Response, ContractError>,
+    ) -> StdResult<SimulationResponse> {
+        // debts as last settled, to tell the interest the operation settles
+        let debts = DEBTS
+            .prefix(account)
+            .range(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        let mut storage = SimulatedStorage::new(deps.storage);
+        let result = handler(DepsMut {
+            storage: &mut storage,
+            api: deps.api,
+            querier: deps.querier,
+        });
+        let res = match result {
+            Ok(res) => res,
+            Err(err) => {
+                return Ok(SimulationResponse {
+                    error: Some(err.to_string()),
+                    health_factor: None,
+                    received: vec![],
+                    fees: vec![],
+                    liquidation_bonus: None,
+                    attributes: vec![],
+                })
+            }
+        };
+
+        let mut fees = vec![];
+        for (pool_id, debt) in debts {
+            if DEBTS.may_load(&storage, (account, &pool_id))?.as_ref() == Some(&debt) {
+                continue;
+            }
+            let pool = load_pool(deps.storage, env, &pool_id)?;
+            let owed = math::debt_at_index(debt.principal, debt.borrow_index, pool.borrow_index)?;
+            if owed > debt.principal {
+                fees.push(Coin {
+                    denom: pool_id,
+                    amount: owed - debt.principal,
+                });
+            }
+        }
+        let deps = Deps {
+            storage: &storage,
+            api: deps.api,
+            querier: deps.querier,
+        };
+        let (debt_value, limit) = account_health(deps, env, account)
+            .map_err(|err| StdError::generic_err(err.to_string()))?;
+        let received = res
+            .messages
+            .iter()
+            .filter_map(|msg| match &msg.msg {
+                CosmosMsg::Bank(BankMsg::Send { to_address, amount }) if to_address == sender => {
+                    Some(amount.clone())
+                }
+                _ => None,
+            })
+            .flatten()
+            .collect();
+        Ok(SimulationResponse {
+            error: None,
+            health_factor: (!debt_value.is_zero()).then(|| Decimal::from_ratio(limit, debt_value)),
+            received,
+            fees,
+            liquidation_bonus: None,
+            attributes: res.attributes,
+        })
+    }
+
+    // Sender of a simulated operation
+    fn sender_info(sender: &str, funds: &[Coin]) -> MessageInfo {
+        MessageInfo {
+            sender: Addr::unchecked(sender),
+            funds: funds.to_vec(),
+        }
+    }
+
+    pub fn get_collaterals(deps: Deps, address: String) -> StdResult<Vec<Collateral>> {
+        COLLATERALS
+            .prefix(&address)
+            .range(deps.storage, None, None, Order::Ascending)
+            .map(|item| {
+                let (token_denom, amount) = item?;
+                Ok(Collateral {
+                    address: address.clone(),
+                    token_denom,
+                    amount,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::msg::InstantiateMsg;
+    use crate::state::PegConfig;
+    use cosmwasm_std::testing::{
+        mock_dependencies, mock_dependencies_with_balance, mock_env, mock_info, MockApi,
+        MockQuerier, MockStorage,
+    };
+    use cosmwasm_std::{coins, ContractResult, OwnedDeps, SystemResult, Uint256};
+    use cw_multi_test::{App, ContractWrapper, Executor};
 
     const ADDR1: &str = "archway1t00mqwm46hmvkgj4ysyh0ykyjln3yw2fvt92wj";
     const ADDR2: &str = "archway1ehuphj3j9ml5stwan46syfv8rj9uw49mm7a5vy";
+    const OWNER: &str = "archway1owner";
+    const FEEDER: &str = "archway1feeder";
+    const AUCTION_DURATION: u64 = 3600;
+    const COOLDOWN: u64 = 7 * 24 * 3600;
+    const UNSTAKE_WINDOW: u64 = 2 * 24 * 3600;
+    // long enough for prices to outlast tests that skip years ahead
+    const MAX_PRICE_AGE: u64 = 2 * math::SECONDS_PER_YEAR;
+    const TWAP_WINDOW: u64 = 1800;
+
+    fn collateral_asset(denom: &str) -> CollateralAsset {
+        CollateralAsset {
+            denom: denom.to_string(),
+            decimals: 6,
+            max_ltv: Decimal::percent(75),
+            liquidation_threshold: Decimal::percent(80),
+            liquidation_bonus: Decimal::percent(5),
+            supply_cap: None,
+            debt_ceiling: None,
+        }
+    }
+
+    fn usdc(borrow_rate: Decimal) -> BorrowAssetConfig {
+        BorrowAssetConfig {
+            denom: "usdc".to_string(),
+            decimals: 6,
+            borrow_rate,
+            supply_cap: None,
+            borrow_cap: None,
+            isolation_borrowable: true,
+            peg: None,
+        }
+    }
+
+    fn instantiate_msg() -> InstantiateMsg {
+        InstantiateMsg {
+            owner: OWNER.to_string(),
+            borrow_assets: vec![usdc(Decimal::percent(10))],
+            collateral_assets: vec![
+                collateral_asset("atom"),
+                CollateralAsset {
+                    decimals: 18,
+                    ..collateral_asset("aarch")
+                },
+            ],
+            oracle: OracleConfig {
+                feeders: vec![Addr::unchecked(FEEDER)],
+                quorum: 1,
+                feeds: vec![],
+                sources: vec![],
+                price_decimals: 6,
+                max_age: MAX_PRICE_AGE,
+                twap_window: TWAP_WINDOW,
+                max_observations: 10,
+                max_deviation: None,
+            },
+            fees: FeeConfig {
+                reserve_factor: Decimal::percent(10),
+            },
+            utilization_thresholds: UtilizationThresholds {
+                medium: Decimal::percent(50),
+                high: Decimal::percent(90),
+            },
+            liquidation: LiquidationConfig {
+                auction_duration: AUCTION_DURATION,
+                queue: None,
+                max_queue_slippage: Decimal::percent(5),
+            },
+            safety_module: SafetyModuleConfig {
+                cooldown: COOLDOWN,
+                unstake_window: UNSTAKE_WINDOW,
+                max_slash: Decimal::percent(30),
+                reserve_share: Decimal::percent(50),
+            },
+            dex: None,
+        }
+    }
+
+    // prices are USD per whole token scaled by 10^6
+    fn set_price(deps: DepsMut, token: &str, price: u128) {
+        execute::update_price(
+            deps,
+            mock_env(),
+            mock_info(FEEDER, &[]),
+            token.to_string(),
+            price.into(),
+        )
+        .unwrap();
+    }
+
+    fn setup() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies_with_balance(&coins(1000, "usdc"));
+        let info = mock_info(ADDR1, &[]);
+        let env = mock_env();
+
+        instantiate(deps.as_mut(), env, info, instantiate_msg()).unwrap();
+        // atom shares the 6 decimals of usdc, so at $1 a base unit of each is worth the same
+        set_price(deps.as_mut(), "usdc", 1_000_000);
+        set_price(deps.as_mut(), "atom", 1_000_000);
+        set_price(deps.as_mut(), "aarch", 1_000_000);
+        execute::provide_liquidity(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &coins(1000, "usdc")),
+        )
+        .unwrap();
+        deps
+    }
+
+    fn env_after(seconds: u64) -> Env {
+        let mut env = mock_env();
+        env.block.time = env.block.time.plus_seconds(seconds);
+        env
+    }
+
+    fn usdc_debt(deps: Deps, env: Env, address: &str) -> Uint128 {
+        let account = query::get_account(deps, env, address.to_string()).unwrap();
+        account
+            .debts
+            .iter()
+            .find(|coin| coin.denom == "usdc")
+            .map(|coin| coin.amount)
+            .unwrap_or_default()
+    }
+
+    fn refund_of(res: &Response) -> Option<Uint128> {
+        res.messages.iter().find_map(|msg| match &msg.msg {
+            CosmosMsg::Bank(BankMsg::Send { amount, .. }) => Some(amount[0].amount),
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn test_instantiate() {
+        let mut deps = mock_dependencies_with_balance(&[]);
+        let info = mock_info(ADDR1, &[]);
+        let env = mock_env();
+
+        let res = instantiate(deps.as_mut(), env, info, instantiate_msg()).unwrap();
+        assert_eq!(2, res.attributes.len());
+        assert_eq!(("method", "instantiate"), res.attributes[0]);
+        assert_eq!(("owner", OWNER), res.attributes[1]);
+
+        let config = query::get_config(deps.as_ref()).unwrap();
+        assert_eq!(vec![usdc(Decimal::percent(10))], config.borrow_assets);
+        assert_eq!(2, config.collateral_assets.len());
+
+        // Risk parameters are validated up front
+        let mut msg = instantiate_msg();
+        msg.collateral_assets[1].max_ltv = Decimal::percent(80);
+        let err = instantiate(
+            mock_dependencies_with_balance(&[]).as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &[]),
+            msg,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InvalidConfig { .. } => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        let mut msg = instantiate_msg();
+        msg.collateral_assets.push(collateral_asset("atom"));
+        let err = instantiate(
+            mock_dependencies_with_balance(&[]).as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &[]),
+            msg,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InvalidConfig { .. } => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_create_account() {
+        let mut deps = setup();
+        let info = mock_info(ADDR1, &[]);
+
+        // Test successful account creation
+        let res = execute::create_account(deps.as_mut(), info.clone()).unwrap();
+        assert_eq!(2, res.attributes.len());
+        assert_eq!(("method", "create_account"), res.attributes[0]);
+        assert_eq!(("address", ADDR1), res.attributes[1]);
+
+        // Test duplicate account creation
+        let err = execute::create_account(deps.as_mut(), info).unwrap_err();
+        match err {
+            ContractError::AccountExists {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_borrow() {
+        let mut deps = setup();
+
+        // Create account first
+        let info = mock_info(ADDR1, &[]);
+        execute::create_account(deps.as_mut(), info.clone()).unwrap();
+
+        // Test successful borrow
+        let borrow_amount = Uint128::new(100);
+        let collateral_denom = "atom".to_string();
+
+        let info = mock_info(
+            ADDR1,
+            &coins(200, "atom"), // Sending collateral
+        );
+
+        let res = execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            "usdc".to_string(),
+            borrow_amount,
+            collateral_denom.clone(),
+        )
+        .unwrap();
+
+        // Verify response
+        assert_eq!(6, res.attributes.len());
+        assert_eq!(("method", "borrow"), res.attributes[0]);
+        assert_eq!(("borrower", ADDR1), res.attributes[1]);
+        assert_eq!(("collateral_denom", "atom"), res.attributes[2]);
+        assert_eq!(("collateral_amount", "200"), res.attributes[3]);
+        assert_eq!(("borrow_denom", "usdc"), res.attributes[4]);
+        assert_eq!(("borrowed_amount", "100"), res.attributes[5]);
+
+        // Verify bank message
+        assert_eq!(1, res.messages.len());
+        match &res.messages[0].msg {
+            CosmosMsg::Bank(BankMsg::Send { to_address, amount }) => {
+                assert_eq!(to_address, &ADDR1.to_string());
+                assert_eq!(
+                    amount,
+                    &vec![Coin {
+                        denom: "usdc".to_string(),
+                        amount: borrow_amount,
+                    }]
+                );
+            }
+            _ => panic!("unexpected message type"),
+        }
+
+        // 200 atom at 75% only covers 150 usdc of debt
+        let err = execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &[]),
+            "usdc".to_string(),
+            Uint128::new(51),
+            collateral_denom,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InsufficientCollateral {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_repay() {
+        let mut deps = setup();
+
+        // Setup: Create account and borrow first
+        let info = mock_info(ADDR1, &[]);
+        execute::create_account(deps.as_mut(), info.clone()).unwrap();
+
+        let info = mock_info(ADDR1, &coins(200, "atom"));
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            info,
+            "usdc".to_string(),
+            Uint128::new(100),
+            "atom".to_string(),
+        )
+        .unwrap();
+
+        // Test partial repayment
+        let repay_info = mock_info(
+            ADDR1,
+            &coins(40, "usdc"), // Repaying USDC
+        );
+        let res = execute::repay(deps.as_mut(), mock_env(), repay_info).unwrap();
+
+        // Verify response
+        assert_eq!(0, res.messages.len());
+        assert_eq!(7, res.attributes.len());
+        assert_eq!(("method", "repay"), res.attributes[0]);
+        assert_eq!(("repayer", ADDR1), res.attributes[1]);
+        assert_eq!(("borrower", ADDR1), res.attributes[2]);
+        assert_eq!(("denom", "usdc"), res.attributes[3]);
+        assert_eq!(("repaid", "40"), res.attributes[4]);
+        assert_eq!(("refund", "0"), res.attributes[5]);
+        assert_eq!(("remaining_debt", "60"), res.attributes[6]);
+
+        // Overpaying refunds the excess and leaves the collateral in place
+        let res = execute::repay(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(100, "usdc")),
+        )
+        .unwrap();
+        assert_eq!(Some(Uint128::new(40)), refund_of(&res));
+        assert_eq!(("remaining_debt", "0"), res.attributes[6]);
+        let collaterals = query::get_collaterals(deps.as_ref(), ADDR1.to_string()).unwrap();
+        assert_eq!(Uint128::new(200), collaterals[0].amount);
+
+        // Test repayment without any debt left
+        let err = execute::repay(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(1, "usdc")),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NoRepayment {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // Test repayment without sending USDC
+        let no_funds_info = mock_info(ADDR1, &[]);
+        let err = execute::repay(deps.as_mut(), mock_env(), no_funds_info).unwrap_err();
+
+        match err {
+            ContractError::NoRepayment {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_deposit_and_withdraw_collateral() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+
+        // Several denoms can be deposited at once
+        // 50 usdc worth of an 18 decimal token
+        let aarch = 50_000_000_000_000u128;
+        let funds = vec![Coin::new(100, "atom"), Coin::new(aarch, "aarch")];
+        execute::deposit_collateral(deps.as_mut(), mock_env(), mock_info(ADDR1, &funds)).unwrap();
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(100, "atom")),
+        )
+        .unwrap();
+
+        let collaterals = query::get_collaterals(deps.as_ref(), ADDR1.to_string()).unwrap();
+        assert_eq!(2, collaterals.len());
+        assert_eq!(Uint128::new(aarch), collaterals[0].amount);
+        assert_eq!(Uint128::new(200), collaterals[1].amount);
+
+        // Borrow 150 against a limit of (200 + 50) * 75% = 187
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &[]),
+            "usdc".to_string(),
+            Uint128::new(150),
+            "atom".to_string(),
+        )
+        .unwrap();
+
+        // Withdrawing all the aarch would leave a limit of exactly 150
+        let res = execute::withdraw_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &[]),
+            "aarch".to_string(),
+            Uint128::new(aarch),
+        )
+        .unwrap();
+        assert_eq!(1, res.messages.len());
+        assert_eq!(("amount", aarch.to_string()), res.attributes[3]);
+
+        // Any further withdrawal breaks the limit
+        let err = execute::withdraw_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &[]),
+            "atom".to_string(),
+            Uint128::new(1),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InsufficientCollateral {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // Withdrawing more than deposited
+        let err = execute::withdraw_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &[]),
+            "atom".to_string(),
+            Uint128::new(201),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InsufficientFunds {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_repay_all_with_interest() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(400, "atom")),
+            "usdc".to_string(),
+            Uint128::new(200),
+            "atom".to_string(),
+        )
+        .unwrap();
+
+        // A year at 10% grows the debt to 220
+        let env = env_after(math::SECONDS_PER_YEAR);
+        assert_eq!(
+            Uint128::new(220),
+            usdc_debt(deps.as_ref(), env.clone(), ADDR1)
+        );
+
+        // Not enough to settle everything
+        let err = execute::repay_all(
+            deps.as_mut(),
+            env.clone(),
+            mock_info(ADDR1, &coins(219, "usdc")),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InsufficientFunds {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        let res = execute::repay_all(
+            deps.as_mut(),
+            env.clone(),
+            mock_info(ADDR1, &coins(300, "usdc")),
+        )
+        .unwrap();
+        assert_eq!(("method", "repay_all"), res.attributes[0]);
+        assert_eq!(("repaid", "220"), res.attributes[4]);
+        assert_eq!(Some(Uint128::new(80)), refund_of(&res));
+
+        assert_eq!(Uint128::zero(), usdc_debt(deps.as_ref(), env, ADDR1));
+
+        // Interest went to the pool, with the reserve factor kept by the protocol
+        let pool = POOLS.load(deps.as_ref().storage, "usdc").unwrap();
+        assert_eq!(Uint128::new(1018), pool.total_liquidity);
+        assert_eq!(Uint128::new(2), pool.total_reserves);
+        assert_eq!(Uint128::zero(), pool.total_borrowed);
+    }
+
+    #[test]
+    fn test_repay_for() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(200, "atom")),
+            "usdc".to_string(),
+            Uint128::new(100),
+            "atom".to_string(),
+        )
+        .unwrap();
+
+        // A third party pays down part of the debt and gets the excess back
+        let treasury = "archway1treasury";
+        let res = execute::repay_for(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(treasury, &coins(150, "usdc")),
+            ADDR1.to_string(),
+        )
+        .unwrap();
+        assert_eq!(("method", "repay_for"), res.attributes[0]);
+        assert_eq!(("repayer", treasury), res.attributes[1]);
+        assert_eq!(("borrower", ADDR1), res.attributes[2]);
+        assert_eq!(("repaid", "100"), res.attributes[4]);
+        match &res.messages[0].msg {
+            CosmosMsg::Bank(BankMsg::Send { to_address, amount }) => {
+                assert_eq!(treasury, to_address);
+                assert_eq!(coins(50, "usdc"), *amount);
+            }
+            _ => panic!("unexpected message type"),
+        }
+
+        // The borrower's collateral is untouched
+        assert_eq!(Uint128::zero(), usdc_debt(deps.as_ref(), mock_env(), ADDR1));
+        let collaterals = query::get_collaterals(deps.as_ref(), ADDR1.to_string()).unwrap();
+        assert_eq!(Uint128::new(200), collaterals[0].amount);
+
+        // Unknown borrowers are rejected
+        let err = execute::repay_for(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(treasury, &coins(10, "usdc")),
+            ADDR2.to_string(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::AccountDoesNotExist {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_delete_account() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(300, "atom")),
+        )
+        .unwrap();
+        execute::provide_liquidity(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(70, "usdc")),
+        )
+        .unwrap();
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &[]),
+            "usdc".to_string(),
+            Uint128::new(100),
+            "atom".to_string(),
+        )
+        .unwrap();
+
+        // Debt blocks the deletion
+        let err =
+            execute::delete_account(deps.as_mut(), mock_env(), mock_info(ADDR1, &[])).unwrap_err();
+        match err {
+            ContractError::OutstandingDebt {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        execute::repay_all(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(100, "usdc")),
+        )
+        .unwrap();
+        let res =
+            execute::delete_account(deps.as_mut(), mock_env(), mock_info(ADDR1, &[])).unwrap();
+        assert_eq!(1, res.messages.len());
+        match &res.messages[0].msg {
+            CosmosMsg::Bank(BankMsg::Send { to_address, amount }) => {
+                assert_eq!(ADDR1, to_address);
+                assert_eq!(&vec![Coin::new(300, "atom"), Coin::new(70, "usdc")], amount);
+            }
+            _ => panic!("unexpected message type"),
+        }
+
+        // Nothing tied to the address is left behind
+        assert!(ACCOUNTS
+            .may_load(deps.as_ref().storage, ADDR1)
+            .unwrap()
+            .is_none());
+        assert!(LIQUIDITY_PROVIDERS
+            .may_load(deps.as_ref().storage, (ADDR1, "usdc"))
+            .unwrap()
+            .is_none());
+        assert!(query::get_collaterals(deps.as_ref(), ADDR1.to_string())
+            .unwrap()
+            .is_empty());
+        let pool = POOLS.load(deps.as_ref().storage, "usdc").unwrap();
+        assert_eq!(Uint128::new(1000), pool.total_liquidity);
+
+        // Deleting twice fails cleanly
+        let err =
+            execute::delete_account(deps.as_mut(), mock_env(), mock_info(ADDR1, &[])).unwrap_err();
+        match err {
+            ContractError::AccountDoesNotExist {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_update_config() {
+        let mut deps = setup();
+
+        let err = execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &[]),
+            None,
+            Some(vec![usdc(Decimal::percent(20))]),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        let mut osmo = collateral_asset("osmo");
+        osmo.max_ltv = Decimal::percent(50);
+        execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            Some(ADDR2.to_string()),
+            Some(vec![usdc(Decimal::percent(20))]),
+            Some(vec![osmo.clone()]),
+            None,
+            None,
+            Some(FeeConfig {
+                reserve_factor: Decimal::percent(25),
+            }),
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        let config = query::get_config(deps.as_ref()).unwrap();
+        assert_eq!(ADDR2, config.owner.as_str());
+        assert_eq!(Decimal::percent(20), config.borrow_assets[0].borrow_rate);
+        assert_eq!(Decimal::percent(25), config.fees.reserve_factor);
+        assert_eq!(3, config.collateral_assets.len());
+        assert!(config.collateral_assets.contains(&osmo));
+
+        // Only the feeder posts prices
+        let err = execute::update_price(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            "osmo".to_string(),
+            Uint128::new(1),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_collateral_valued_at_oracle_price() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+
+        // Unlisted denoms are rejected
+        let err = execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(10, "osmo")),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::UnsupportedCollateral { denom } => assert_eq!("osmo", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // 100 atom at $2.50 with a 75% LTV supports 187 usdc
+        set_price(deps.as_mut(), "atom", 2_500_000);
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(100, "atom")),
+        )
+        .unwrap();
+        let borrow = |deps: DepsMut, amount: u128| {
+            execute::borrow(
+                deps,
+                mock_env(),
+                mock_info(ADDR1, &[]),
+                "usdc".to_string(),
+                Uint128::new(amount),
+                "atom".to_string(),
+            )
+        };
+        let err = borrow(deps.as_mut(), 188).unwrap_err();
+        match err {
+            ContractError::InsufficientCollateral {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        borrow(deps.as_mut(), 187).unwrap();
+    }
+
+    #[test]
+    fn test_pool_utilization() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(2000, "atom")),
+        )
+        .unwrap();
+        let borrow = |deps: DepsMut, amount: u128| {
+            execute::borrow(
+                deps,
+                mock_env(),
+                mock_info(ADDR1, &[]),
+                "usdc".to_string(),
+                Uint128::new(amount),
+                "atom".to_string(),
+            )
+            .unwrap();
+        };
+
+        let pool = query::get_pool(deps.as_ref(), mock_env(), "usdc".to_string()).unwrap();
+        assert_eq!(Decimal::zero(), pool.utilization_rate);
+        assert_eq!(PoolUtilization::Low, pool.utilization);
+
+        // Recomputed on every borrow without any manual trigger
+        borrow(deps.as_mut(), 333);
+        let pool = query::get_pool(deps.as_ref(), mock_env(), "usdc".to_string()).unwrap();
+        assert_eq!(Decimal::permille(333), pool.utilization_rate);
+        assert_eq!(PoolUtilization::Low, pool.utilization);
+
+        borrow(deps.as_mut(), 167);
+        let pool = query::get_pool(deps.as_ref(), mock_env(), "usdc".to_string()).unwrap();
+        assert_eq!(Decimal::percent(50), pool.utilization_rate);
+        assert_eq!(PoolUtilization::Medium, pool.utilization);
+
+        borrow(deps.as_mut(), 400);
+        let pool = query::get_pool(deps.as_ref(), mock_env(), "usdc".to_string()).unwrap();
+        assert_eq!(PoolUtilization::High, pool.utilization);
+
+        // ... and on repayments
+        execute::repay(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(500, "usdc")),
+        )
+        .unwrap();
+        let pool = query::get_pool(deps.as_ref(), mock_env(), "usdc".to_string()).unwrap();
+        assert_eq!(Decimal::percent(40), pool.utilization_rate);
+        assert_eq!(PoolUtilization::Low, pool.utilization);
+
+        // Thresholds are configurable
+        execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(UtilizationThresholds {
+                medium: Decimal::percent(30),
+                high: Decimal::percent(60),
+            }),
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let pool = query::get_pool(deps.as_ref(), mock_env(), "usdc".to_string()).unwrap();
+        assert_eq!(PoolUtilization::Medium, pool.utilization);
+    }
+
+    #[test]
+    fn test_mixed_decimal_collateral() {
+        let mut deps = setup();
+        execute::provide_liquidity(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &coins(10_000_000, "usdc")),
+        )
+        .unwrap();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+
+        // 1 atom (6 decimals) at $10 and 2 aarch (18 decimals) at $0.50 are worth $11
+        set_price(deps.as_mut(), "atom", 10_000_000);
+        set_price(deps.as_mut(), "aarch", 500_000);
+        let funds = vec![
+            Coin::new(1_000_000, "atom"),
+            Coin::new(2_000_000_000_000_000_000, "aarch"),
+        ];
+        execute::deposit_collateral(deps.as_mut(), mock_env(), mock_info(ADDR1, &funds)).unwrap();
+
+        // a 75% LTV supports $8.25 of usdc
+        let borrow = |deps: DepsMut, amount: u128| {
+            execute::borrow(
+                deps,
+                mock_env(),
+                mock_info(ADDR1, &[]),
+                "usdc".to_string(),
+                Uint128::new(amount),
+                "atom".to_string(),
+            )
+        };
+        let err = borrow(deps.as_mut(), 8_250_001).unwrap_err();
+        match err {
+            ContractError::InsufficientCollateral {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        borrow(deps.as_mut(), 8_250_000).unwrap();
+
+        // at $9 per atom the basket is worth $10 and liquidates above $8 of debt
+        set_price(deps.as_mut(), "atom", 9_000_000);
+        execute::refresh_liquidation(deps.as_mut(), mock_env(), ADDR1.to_string()).unwrap();
+        let res = execute::liquidate(
+            deps.as_mut(),
+            env_after(AUCTION_DURATION),
+            mock_info(ADDR2, &coins(1_000_000, "usdc")),
+            ADDR1.to_string(),
+            "aarch".to_string(),
+        )
+        .unwrap();
+        // $1.05 of aarch is more than the 2 aarch held, which only cover $0.952381 of debt
+        assert_eq!(("repaid", "952381"), res.attributes[4]);
+        assert_eq!(("seized", "2000000000000000000"), res.attributes[5]);
+        assert_eq!(("refund", "47619"), res.attributes[6]);
+    }
+
+    #[test]
+    fn test_multiple_borrow_assets() {
+        let mut deps = setup();
+
+        // weth gets a pool of its own with a different rate
+        execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            None,
+            Some(vec![BorrowAssetConfig {
+                denom: "weth".to_string(),
+                decimals: 18,
+                borrow_rate: Decimal::percent(5),
+                supply_cap: None,
+                borrow_cap: None,
+                isolation_borrowable: false,
+                peg: None,
+            }]),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        set_price(deps.as_mut(), "weth", 2_000_000_000);
+        let eth = 10u128.pow(18);
+        execute::provide_liquidity(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &coins(eth, "weth")),
+        )
+        .unwrap();
+        let pool = query::get_pool(deps.as_ref(), mock_env(), "weth".to_string()).unwrap();
+        assert_eq!(Uint128::new(eth), pool.total_liquidity);
+        assert_eq!(Decimal::percent(5), pool.borrow_rate);
+
+        // $2000 of atom supports $1500 of debt across both pools
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        set_price(deps.as_mut(), "atom", 2_000_000_000);
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(1_000_000, "atom")),
+        )
+        .unwrap();
+        let borrow = |deps: DepsMut, denom: &str, amount: u128| {
+            execute::borrow(
+                deps,
+                mock_env(),
+                mock_info(ADDR1, &[]),
+                denom.to_string(),
+                Uint128::new(amount),
+                "atom".to_string(),
+            )
+        };
+        borrow(deps.as_mut(), "usdc", 1000).unwrap();
+        // 0.75 weth is $1500, which together with the usdc is just over the limit
+        let err = borrow(deps.as_mut(), "weth", eth * 3 / 4).unwrap_err();
+        match err {
+            ContractError::InsufficientCollateral {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        borrow(deps.as_mut(), "weth", eth / 2).unwrap();
+
+        let err = borrow(deps.as_mut(), "atom", 1).unwrap_err();
+        match err {
+            ContractError::UnsupportedBorrowAsset { denom } => assert_eq!("atom", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        let account = query::get_account(deps.as_ref(), mock_env(), ADDR1.to_string()).unwrap();
+        assert_eq!(
+            vec![Coin::new(1000, "usdc"), Coin::new(eth / 2, "weth")],
+            account.debts
+        );
+        // $0.001 of usdc and $1000 of weth
+        assert_eq!(
+            Uint128::new(1_000_001_000_000_000_000_000),
+            account.debt_value
+        );
+        assert_eq!(
+            Uint128::new(1_500_000_000_000_000_000_000),
+            account.borrow_limit
+        );
+
+        // each pool accrues at its own rate
+        let env = env_after(math::SECONDS_PER_YEAR);
+        let account = query::get_account(deps.as_ref(), env.clone(), ADDR1.to_string()).unwrap();
+        assert_eq!(
+            vec![
+                Coin::new(1100, "usdc"),
+                Coin::new(eth / 2 * 105 / 100, "weth")
+            ],
+            account.debts
+        );
+
+        // repaying weth leaves the usdc debt alone
+        let res = execute::repay_all(
+            deps.as_mut(),
+            env.clone(),
+            mock_info(ADDR1, &coins(eth, "weth")),
+        )
+        .unwrap();
+        assert_eq!(("denom", "weth"), res.attributes[3]);
+        assert_eq!(
+            Some(Uint128::new(eth - eth / 2 * 105 / 100)),
+            refund_of(&res)
+        );
+        let account = query::get_account(deps.as_ref(), env, ADDR1.to_string()).unwrap();
+        assert_eq!(vec![Coin::new(1100, "usdc")], account.debts);
+    }
+
+    #[test]
+    fn test_supply_and_borrow_caps() {
+        let mut deps = setup();
+        execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            None,
+            Some(vec![BorrowAssetConfig {
+                supply_cap: Some(Uint128::new(1500)),
+                borrow_cap: Some(Uint128::new(300)),
+                ..usdc(Decimal::percent(10))
+            }]),
+            Some(vec![CollateralAsset {
+                supply_cap: Some(Uint128::new(1000)),
+                ..collateral_asset("atom")
+            }]),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        // 1000 usdc are already in the pool
+        let err = execute::provide_liquidity(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(501, "usdc")),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::SupplyCapExceeded { denom, cap } => {
+                assert_eq!("usdc", denom);
+                assert_eq!(Uint128::new(1500), cap);
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        execute::provide_liquidity(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(500, "usdc")),
+        )
+        .unwrap();
+
+        // the collateral cap counts deposits of every account
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::create_account(deps.as_mut(), mock_info(ADDR2, &[])).unwrap();
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(600, "atom")),
+        )
+        .unwrap();
+        let err = execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &coins(401, "atom")),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::SupplyCapExceeded { denom, .. } => assert_eq!("atom", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &coins(400, "atom")),
+        )
+        .unwrap();
+
+        // borrowing stops at the cap even though the pool has cash and the collateral allows more
+        let borrow = |deps: DepsMut, address: &str, amount: u128| {
+            execute::borrow(
+                deps,
+                mock_env(),
+                mock_info(address, &[]),
+                "usdc".to_string(),
+                Uint128::new(amount),
+                "atom".to_string(),
+            )
+        };
+        borrow(deps.as_mut(), ADDR1, 200).unwrap();
+        let err = borrow(deps.as_mut(), ADDR2, 101).unwrap_err();
+        match err {
+            ContractError::BorrowCapExceeded { denom, cap } => {
+                assert_eq!("usdc", denom);
+                assert_eq!(Uint128::new(300), cap);
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        borrow(deps.as_mut(), ADDR2, 100).unwrap();
+
+        // withdrawing frees up room under the collateral cap
+        execute::withdraw_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &[]),
+            "atom".to_string(),
+            Uint128::new(100),
+        )
+        .unwrap();
+
+        let market = query::get_market(deps.as_ref(), mock_env(), "usdc".to_string()).unwrap();
+        assert_eq!(Uint128::new(1500), market.total_supplied);
+        assert_eq!(Some(Uint128::new(1500)), market.supply_cap);
+        assert_eq!(Uint128::new(300), market.total_borrowed);
+        assert_eq!(Some(Uint128::new(300)), market.borrow_cap);
+        assert_eq!(None, market.collateral_cap);
+        let market = query::get_market(deps.as_ref(), mock_env(), "atom".to_string()).unwrap();
+        assert_eq!(Uint128::new(900), market.total_collateral);
+        assert_eq!(Some(Uint128::new(1000)), market.collateral_cap);
+        assert_eq!(None, market.borrow_cap);
+        assert!(query::get_market(deps.as_ref(), mock_env(), "osmo".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_isolation_mode() {
+        let mut deps = setup();
+        // debt backed by pepe is capped at 150 base units of a 6 decimal stable
+        let ceiling = Uint128::new(150_000_000_000_000);
+        execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            None,
+            Some(vec![BorrowAssetConfig {
+                denom: "weth".to_string(),
+                decimals: 18,
+                borrow_rate: Decimal::percent(5),
+                supply_cap: None,
+                borrow_cap: None,
+                isolation_borrowable: false,
+                peg: None,
+            }]),
+            Some(vec![CollateralAsset {
+                debt_ceiling: Some(ceiling),
+                ..collateral_asset("pepe")
+            }]),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        set_price(deps.as_mut(), "pepe", 1_000_000);
+        execute::provide_liquidity(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &coins(1000, "weth")),
+        )
+        .unwrap();
+        let treasury = "archway1treasury";
+        for address in [ADDR1, ADDR2, treasury] {
+            execute::create_account(deps.as_mut(), mock_info(address, &[])).unwrap();
+        }
+
+        // isolated collateral cannot be mixed with anything else, in either order
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(1000, "pepe")),
+        )
+        .unwrap();
+        let err = execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(10, "atom")),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::IsolatedCollateral { denom } => assert_eq!("pepe", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &coins(10, "atom")),
+        )
+        .unwrap();
+        let err = execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &coins(10, "pepe")),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::IsolatedCollateral { denom } => assert_eq!("pepe", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        let borrow = |deps: DepsMut, address: &str, denom: &str, amount: u128| {
+            execute::borrow(
+                deps,
+                mock_env(),
+                mock_info(address, &[]),
+                denom.to_string(),
+                Uint128::new(amount),
+                "pepe".to_string(),
+            )
+        };
+
+        // only designated assets can be borrowed against it
+        let err = borrow(deps.as_mut(), ADDR1, "weth", 1).unwrap_err();
+        match err {
+            ContractError::NotBorrowableInIsolation { denom } => assert_eq!("weth", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        borrow(deps.as_mut(), ADDR1, "usdc", 100).unwrap();
+
+        // the ceiling is shared by every account backed by pepe
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(treasury, &coins(1000, "pepe")),
+        )
+        .unwrap();
+        let err = borrow(deps.as_mut(), treasury, "usdc", 51).unwrap_err();
+        match err {
+            ContractError::DebtCeilingExceeded { denom } => assert_eq!("pepe", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        borrow(deps.as_mut(), treasury, "usdc", 50).unwrap();
+
+        // repaying frees up room under the ceiling
+        execute::repay(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(40, "usdc")),
+        )
+        .unwrap();
+        let market = query::get_market(deps.as_ref(), mock_env(), "pepe".to_string()).unwrap();
+        assert_eq!(Some(ceiling), market.debt_ceiling);
+        assert_eq!(Uint128::new(110_000_000_000_000), market.isolated_debt);
+        borrow(deps.as_mut(), treasury, "usdc", 40).unwrap();
+    }
+
+    #[test]
+    fn test_emode() {
+        let mut deps = setup();
+        let stablecoins = EModeCategory {
+            id: 1,
+            label: "stablecoins".to_string(),
+            max_ltv: Decimal::percent(93),
+            liquidation_threshold: Decimal::percent(95),
+            liquidation_bonus: Decimal::percent(1),
+            price_source: Some("usdc".to_string()),
+            assets: vec!["usdc".to_string(), "usdt".to_string()],
+        };
+        execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            None,
+            None,
+            Some(vec![collateral_asset("usdt")]),
+            Some(vec![stablecoins.clone()]),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let config = query::get_config(deps.as_ref()).unwrap();
+        assert_eq!(vec![stablecoins], config.emode_categories);
+
+        // usdt trades slightly below usdc
+        set_price(deps.as_mut(), "usdt", 990_000);
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(1000, "usdt")),
+        )
+        .unwrap();
+        let account = query::get_account(deps.as_ref(), mock_env(), ADDR1.to_string()).unwrap();
+        // 75% of 990
+        assert_eq!(Uint128::new(742_500_000_000_000), account.borrow_limit);
+
+        // in e-mode usdt is priced as usdc and borrows at 93%
+        execute::set_emode(deps.as_mut(), mock_env(), mock_info(ADDR1, &[]), Some(1)).unwrap();
+        let account = query::get_account(deps.as_ref(), mock_env(), ADDR1.to_string()).unwrap();
+        assert_eq!(Some(1), account.emode_category);
+        assert_eq!(Uint128::new(930_000_000_000_000), account.borrow_limit);
+        let borrow = |deps: DepsMut, amount: u128| {
+            execute::borrow(
+                deps,
+                mock_env(),
+                mock_info(ADDR1, &[]),
+                "usdc".to_string(),
+                Uint128::new(amount),
+                "usdt".to_string(),
+            )
+        };
+        let err = borrow(deps.as_mut(), 931).unwrap_err();
+        match err {
+            ContractError::InsufficientCollateral {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        borrow(deps.as_mut(), 930).unwrap();
+
+        // positions outside the category are refused while in e-mode
+        let err = execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(10, "atom")),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NotInEModeCategory { denom, category } => {
+                assert_eq!("atom", denom);
+                assert_eq!(1, category);
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // ... and accounts holding them cannot enter it
+        execute::create_account(deps.as_mut(), mock_info(ADDR2, &[])).unwrap();
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &coins(10, "atom")),
+        )
+        .unwrap();
+        let err = execute::set_emode(deps.as_mut(), mock_env(), mock_info(ADDR2, &[]), Some(1))
+            .unwrap_err();
+        match err {
+            ContractError::NotInEModeCategory { denom, .. } => assert_eq!("atom", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let err = execute::set_emode(deps.as_mut(), mock_env(), mock_info(ADDR2, &[]), Some(7))
+            .unwrap_err();
+        match err {
+            ContractError::EModeCategoryNotFound { category } => assert_eq!(7, category),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // leaving e-mode would put the debt above the standard limit
+        let err =
+            execute::set_emode(deps.as_mut(), mock_env(), mock_info(ADDR1, &[]), None).unwrap_err();
+        match err {
+            ContractError::InsufficientCollateral {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_liquidate() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(100, "atom")),
+            "usdc".to_string(),
+            Uint128::new(75),
+            "atom".to_string(),
+        )
+        .unwrap();
+
+        let liquidate = |deps: DepsMut, paid: u128| {
+            execute::liquidate(
+                deps,
+                env_after(AUCTION_DURATION),
+                mock_info(ADDR2, &coins(paid, "usdc")),
+                ADDR1.to_string(),
+                "atom".to_string(),
+            )
+        };
+
+        // 75 of debt is below the 80 liquidation limit
+        let err = liquidate(deps.as_mut(), 50).unwrap_err();
+        match err {
+            ContractError::AccountHealthy {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // at $0.90 the limit drops to 72, the auction runs its course before anyone liquidates
+        set_price(deps.as_mut(), "atom", 900_000);
+        execute::refresh_liquidation(deps.as_mut(), mock_env(), ADDR1.to_string()).unwrap();
+        let res = liquidate(deps.as_mut(), 50).unwrap();
+        assert_eq!(("repaid", "50"), res.attributes[4]);
+        // 52.5 usdc worth of atom at $0.90, rounded down
+        assert_eq!(("seized", "58"), res.attributes[5]);
+        assert_eq!(("bonus", "0.05"), res.attributes[8]);
+        assert_eq!(1, res.messages.len());
+
+        // an hour of interest rounds the debt up by one
+        assert_eq!(
+            Uint128::new(26),
+            usdc_debt(deps.as_ref(), env_after(AUCTION_DURATION), ADDR1)
+        );
+        let collaterals = query::get_collaterals(deps.as_ref(), ADDR1.to_string()).unwrap();
+        assert_eq!(Uint128::new(42), collaterals[0].amount);
+
+        // crash the price so the remaining collateral cannot cover the payment. The account was
+        // healthy in between, so a new auction starts, but it is deep enough for the full bonus.
+        set_price(deps.as_mut(), "atom", 100_000);
+        let res = liquidate(deps.as_mut(), 25).unwrap();
+        // 42 atom at $0.10 is 4.2 usdc, worth 4 usdc of debt after the 5% bonus
+        assert_eq!(("repaid", "4"), res.attributes[4]);
+        assert_eq!(("seized", "42"), res.attributes[5]);
+        assert_eq!(("refund", "21"), res.attributes[6]);
+        assert_eq!(2, res.messages.len());
+    }
+
+    #[test]
+    fn test_liquidation_auction() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(100, "atom")),
+            "usdc".to_string(),
+            Uint128::new(75),
+            "atom".to_string(),
+        )
+        .unwrap();
+
+        // healthy accounts have no auction
+        execute::refresh_liquidation(deps.as_mut(), mock_env(), ADDR1.to_string()).unwrap();
+        let auction = query::get_liquidation(deps.as_ref(), mock_env(), ADDR1.to_string()).unwrap();
+        assert_eq!(None, auction.started_at);
+        assert_eq!(Decimal::zero(), auction.progress);
+
+        // 75 of debt is 3 over the 72 limit, which starts the auction 1/24 of the way in
+        set_price(deps.as_mut(), "atom", 900_000);
+        let started_at = mock_env().block.time.seconds();
+        let res =
+            execute::refresh_liquidation(deps.as_mut(), mock_env(), ADDR1.to_string()).unwrap();
+        assert_eq!(("started_at", started_at.to_string()), res.attributes[2]);
+        let depth = Decimal::from_ratio(3u128, 72u128);
+        let auction = query::get_liquidation(deps.as_ref(), mock_env(), ADDR1.to_string()).unwrap();
+        assert_eq!(Some(started_at), auction.started_at);
+        assert_eq!(depth, auction.progress);
+
+        // refreshing again keeps the original start, while half an hour of interest rounds the
+        // debt up to 76 and so deepens the violation
+        let depth = Decimal::from_ratio(4u128, 72u128);
+        execute::refresh_liquidation(
+            deps.as_mut(),
+            env_after(AUCTION_DURATION / 2),
+            ADDR1.to_string(),
+        )
+        .unwrap();
+        let auction = query::get_liquidation(
+            deps.as_ref(),
+            env_after(AUCTION_DURATION / 2),
+            ADDR1.to_string(),
+        )
+        .unwrap();
+        assert_eq!(Some(started_at), auction.started_at);
+        assert_eq!(Decimal::percent(50) + depth, auction.progress);
+
+        // halfway through the liquidator gets a bit over half of the 5% bonus
+        let res = execute::liquidate(
+            deps.as_mut(),
+            env_after(AUCTION_DURATION / 2),
+            mock_info(ADDR2, &coins(10, "usdc")),
+            ADDR1.to_string(),
+            "atom".to_string(),
+        )
+        .unwrap();
+        let bonus = Decimal::percent(5) * (Decimal::percent(50) + depth);
+        assert_eq!(("bonus", bonus.to_string()), res.attributes[8]);
+        // 10 usdc plus the bonus is 10.28 usdc worth of atom at $0.90, rounded down
+        assert_eq!(("seized", "11"), res.attributes[5]);
+
+        // still under water, so the auction carries on
+        let auction = query::get_liquidation(
+            deps.as_ref(),
+            env_after(AUCTION_DURATION / 2),
+            ADDR1.to_string(),
+        )
+        .unwrap();
+        assert_eq!(Some(started_at), auction.started_at);
+
+        // topping up the collateral ends the auction
+        execute::deposit_collateral(
+            deps.as_mut(),
+            env_after(AUCTION_DURATION / 2),
+            mock_info(ADDR1, &coins(100, "atom")),
+        )
+        .unwrap();
+        let auction = query::get_liquidation(
+            deps.as_ref(),
+            env_after(AUCTION_DURATION / 2),
+            ADDR1.to_string(),
+        )
+        .unwrap();
+        assert_eq!(None, auction.started_at);
+
+        let err = execute::liquidate(
+            deps.as_mut(),
+            env_after(AUCTION_DURATION),
+            mock_info(ADDR2, &coins(10, "usdc")),
+            ADDR1.to_string(),
+            "atom".to_string(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::AccountHealthy {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_liquidate_with_queue() {
+        let (owner, feeder) = (Addr::unchecked(OWNER), Addr::unchecked(FEEDER));
+        let (borrower, bidder) = (Addr::unchecked(ADDR1), Addr::unchecked(ADDR2));
+        let keeper = Addr::unchecked("archway1keeper");
+        let mut app = App::new(|router, _, storage| {
+            router
+                .bank
+                .init_balance(storage, &borrower, coins(100, "atom"))
+                .unwrap();
+            router
+                .bank
+                .init_balance(storage, &bidder, coins(1200, "usdc"))
+                .unwrap();
+        });
+
+        let queue_code = app.store_code(Box::new(ContractWrapper::new(
+            liquidation_queue::contract::execute,
+            liquidation_queue::contract::instantiate,
+            liquidation_queue::contract::query,
+        )));
+        let queue = app
+            .instantiate_contract(
+                queue_code,
+                owner.clone(),
+                &liquidation_queue::msg::InstantiateMsg {
+                    owner: OWNER.to_string(),
+                    market: None,
+                    stable_denom: "usdc".to_string(),
+                    max_premium_slot: 30,
+                    min_bid_amount: Uint128::new(10),
+                },
+                &[],
+                "queue",
+                None,
+            )
+            .unwrap();
+        let market_code = app.store_code(Box::new(
+            ContractWrapper::new(execute, instantiate, query).with_reply(reply),
+        ));
+        let mut msg = instantiate_msg();
+        msg.liquidation.queue = Some(queue.clone());
+        let market = app
+            .instantiate_contract(market_code, owner.clone(), &msg, &[], "eulend", None)
+            .unwrap();
+        app.execute_contract(
+            owner,
+            queue.clone(),
+            &QueueExecuteMsg::UpdateConfig {
+                owner: None,
+                market: Some(market.to_string()),
+                max_premium_slot: None,
+                min_bid_amount: None,
+            },
+            &[],
+        )
+        .unwrap();
+
+        let set_price = |app: &mut App, token: &str, price: u128| {
+            app.execute_contract(
+                feeder.clone(),
+                market.clone(),
+                &ExecuteMsg::UpdatePrice {
+                    token: token.to_string(),
+                    price: Uint128::new(price),
+                },
+                &[],
+            )
+            .unwrap();
+        };
+        set_price(&mut app, "usdc", 1_000_000);
+        set_price(&mut app, "atom", 1_000_000);
+        app.execute_contract(
+            bidder.clone(),
+            market.clone(),
+            &ExecuteMsg::ProvideLiquidity {},
+            &coins(1000, "usdc"),
+        )
+        .unwrap();
+        app.execute_contract(
+            borrower.clone(),
+            market.clone(),
+            &ExecuteMsg::CreateAccount {},
+            &[],
+        )
+        .unwrap();
+        app.execute_contract(
+            borrower.clone(),
+            market.clone(),
+            &ExecuteMsg::Borrow {
+                borrow_denom: "usdc".to_string(),
+                borrow_amount: Uint128::new(75),
+                collateral_denom: "atom".to_string(),
+            },
+            &coins(100, "atom"),
+        )
+        .unwrap();
+
+        // at $0.90 the account is under water and the auction runs its course
+        set_price(&mut app, "atom", 900_000);
+        app.execute_contract(
+            keeper.clone(),
+            market.clone(),
+            &ExecuteMsg::RefreshLiquidation {
+                borrower: ADDR1.to_string(),
+            },
+            &[],
+        )
+        .unwrap();
+        app.update_block(|block| block.time = block.time.plus_seconds(AUCTION_DURATION));
+        let liquidate = |app: &mut App| {
+            app.execute_contract(
+                keeper.clone(),
+                market.clone(),
+                &ExecuteMsg::LiquidateWithQueue {
+                    borrower: ADDR1.to_string(),
+                    collateral_denom: "atom".to_string(),
+                    debt_denom: "usdc".to_string(),
+                    amount: Uint128::new(50),
+                },
+                &[],
+            )
+        };
+        let collateral = |app: &App| {
+            let collaterals: Vec<Collateral> = app
+                .wrap()
+                .query_wasm_smart(
+                    &market,
+                    &QueryMsg::GetCollaterals {
+                        address: ADDR1.to_string(),
+                    },
+                )
+                .unwrap();
+            collaterals[0].amount
+        };
+
+        // the queue only pays out in usdc
+        let err = app
+            .execute_contract(
+                keeper.clone(),
+                market.clone(),
+                &ExecuteMsg::LiquidateWithQueue {
+                    borrower: ADDR1.to_string(),
+                    collateral_denom: "atom".to_string(),
+                    debt_denom: "atom".to_string(),
+                    amount: Uint128::new(50),
+                },
+                &[],
+            )
+            .unwrap_err();
+        match err.downcast_ref::<ContractError>() {
+            Some(ContractError::QueueDenomMismatch { denom }) => assert_eq!("usdc", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // without bids the sale fails and nothing changes
+        let err = liquidate(&mut app).unwrap_err();
+        match err
+            .root_cause()
+            .downcast_ref::<liquidation_queue::ContractError>()
+        {
+            Some(liquidation_queue::ContractError::InsufficientBids { .. }) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(Uint128::new(100), collateral(&app));
+
+        // 58 atom at $0.90 net of the 5% bonus are worth 49 usdc, so a 20% bid paying 42 falls
+        // short of the 46 allowed by 5% slippage
+        app.execute_contract(
+            bidder.clone(),
+            queue.clone(),
+            &QueueExecuteMsg::SubmitBid {
+                collateral_denom: "atom".to_string(),
+                premium_slot: 20,
+            },
+            &coins(100, "usdc"),
+        )
+        .unwrap();
+        let err = liquidate(&mut app).unwrap_err();
+        match err.downcast_ref::<ContractError>() {
+            Some(ContractError::SlippageExceeded {
+                min_output,
+                received,
+            }) => {
+                assert_eq!(Uint128::new(46), *min_output);
+                assert_eq!(Uint128::new(42), *received);
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(Uint128::new(100), collateral(&app));
+
+        app.execute_contract(
+            bidder.clone(),
+            queue.clone(),
+            &QueueExecuteMsg::SubmitBid {
+                collateral_denom: "atom".to_string(),
+                premium_slot: 3,
+            },
+            &coins(100, "usdc"),
+        )
+        .unwrap();
+
+        // 52.5 usdc worth of atom is 58 atom at $0.90, bought at $0.873 for 51 usdc
+        liquidate(&mut app).unwrap();
+        assert_eq!(Uint128::new(42), collateral(&app));
+        let account: AccountResponse = app
+            .wrap()
+            .query_wasm_smart(
+                &market,
+                &QueryMsg::GetAccount {
+                    address: ADDR1.to_string(),
+                },
+            )
+            .unwrap();
+        // an hour of interest rounds the 75 owed up to 76
+        assert_eq!(coins(25, "usdc"), account.debts);
+        let pool: Pool = app
+            .wrap()
+            .query_wasm_smart(
+                &market,
+                &QueryMsg::GetPool {
+                    id: "usdc".to_string(),
+                },
+            )
+            .unwrap();
+        assert_eq!(Uint128::new(25), pool.total_borrowed);
+
+        app.execute_contract(
+            bidder.clone(),
+            queue.clone(),
+            &QueueExecuteMsg::ClaimLiquidations {
+                collateral_denom: "atom".to_string(),
+            },
+            &[],
+        )
+        .unwrap();
+        let balance = app.wrap().query_balance(&bidder, "atom").unwrap();
+        assert_eq!(Uint128::new(58), balance.amount);
+        let balance = app.wrap().query_balance(&market, "usdc").unwrap();
+        assert_eq!(Uint128::new(976), balance.amount);
+    }
+
+    #[test]
+    fn test_bad_debt() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(1000, "atom")),
+            "usdc".to_string(),
+            Uint128::new(750),
+            "atom".to_string(),
+        )
+        .unwrap();
+
+        let err = execute::fund_insurance(deps.as_mut(), mock_info(ADDR2, &coins(30, "atom")))
+            .unwrap_err();
+        match err {
+            ContractError::WrongToken {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        execute::fund_insurance(deps.as_mut(), mock_info(ADDR2, &coins(30, "usdc"))).unwrap();
+
+        // a year of interest builds up some reserves
+        let env = env_after(math::SECONDS_PER_YEAR);
+        let pool = query::get_pool(deps.as_ref(), env.clone(), "usdc".to_string()).unwrap();
+        assert!(!pool.total_reserves.is_zero());
+        let debt = usdc_debt(deps.as_ref(), env.clone(), ADDR1);
+
+        // the crash leaves 1000 atom worth $100 against more than $800 of debt
+        set_price(deps.as_mut(), "atom", 100_000);
+        let res = execute::liquidate(
+            deps.as_mut(),
+            env.clone(),
+            mock_info(ADDR2, &coins(900, "usdc")),
+            ADDR1.to_string(),
+            "atom".to_string(),
+        )
+        .unwrap();
+        // $100 of atom covers 96 usdc of debt after the 5% bonus
+        assert_eq!(("repaid", "96"), res.attributes[4]);
+        assert_eq!(("seized", "1000"), res.attributes[5]);
+
+        // reserves go first, then the insurance fund, and liquidity providers take the rest
+        let bad_debt = BadDebt {
+            id: 1,
+            borrower: ADDR1.to_string(),
+            amount: debt - Uint128::new(96),
+            from_reserves: pool.total_reserves,
+            from_insurance: Uint128::new(30),
+            from_safety_module: Uint128::zero(),
+            socialized: debt - Uint128::new(96) - pool.total_reserves - Uint128::new(30),
+            time: env.block.time.seconds(),
+        };
+        assert_eq!(1, res.events.len());
+        assert_eq!("bad_debt", res.events[0].ty);
+        assert_eq!(
+            ("socialized", bad_debt.socialized.to_string()),
+            res.events[0].attributes[6]
+        );
+
+        assert_eq!(
+            Uint128::zero(),
+            usdc_debt(deps.as_ref(), env.clone(), ADDR1)
+        );
+        let after = query::get_pool(deps.as_ref(), env.clone(), "usdc".to_string()).unwrap();
+        assert_eq!(Uint128::zero(), after.total_reserves);
+        assert_eq!(Uint128::zero(), after.total_borrowed);
+        assert_eq!(
+            pool.total_liquidity - bad_debt.socialized,
+            after.total_liquidity
+        );
+        // the share price drops below where it started
+        assert!(after.total_liquidity < after.total_shares);
+        let market = query::get_market(deps.as_ref(), env.clone(), "usdc".to_string()).unwrap();
+        assert_eq!(Uint128::zero(), market.insurance_fund);
+
+        let history = query::get_bad_debt(deps.as_ref(), "usdc".to_string()).unwrap();
+        assert_eq!(bad_debt.amount, history.total);
+        assert_eq!(vec![bad_debt], history.history);
+    }
+
+    #[test]
+    fn test_liquidity_shares_earn_interest() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(2000, "atom")),
+            "usdc".to_string(),
+            Uint128::new(1000),
+            "atom".to_string(),
+        )
+        .unwrap();
+
+        // a year later the pool is worth 1000 + 90% of 100 interest
+        let env = env_after(math::SECONDS_PER_YEAR);
+        let res = execute::provide_liquidity(
+            deps.as_mut(),
+            env.clone(),
+            mock_info(ADDR1, &coins(1090, "usdc")),
+        )
+        .unwrap();
+        assert_eq!(("shares", "1000"), res.attributes[4]);
+
+        execute::repay_all(
+            deps.as_mut(),
+            env.clone(),
+            mock_info(ADDR1, &coins(1100, "usdc")),
+        )
+        .unwrap();
+        let res = execute::delete_account(deps.as_mut(), env, mock_info(ADDR1, &[])).unwrap();
+        assert_eq!(("refunded", "2000atom"), res.attributes[2]);
+        assert_eq!(("refunded", "1090usdc"), res.attributes[3]);
+    }
+
+    #[test]
+    fn test_safety_module() {
+        let mut deps = setup();
+        let err = execute::stake(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &coins(100, "atom")),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::WrongToken {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let res = execute::stake(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &coins(100, "usdc")),
+        )
+        .unwrap();
+        assert_eq!(("shares", "100"), res.attributes[4]);
+
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(1000, "atom")),
+            "usdc".to_string(),
+            Uint128::new(750),
+            "atom".to_string(),
+        )
+        .unwrap();
+
+        // stakers earn half of the reserves accrued over the year
+        let env = env_after(math::SECONDS_PER_YEAR);
+        let reserves = query::get_pool(deps.as_ref(), env.clone(), "usdc".to_string())
+            .unwrap()
+            .total_reserves;
+        let module =
+            query::get_safety_module(deps.as_ref(), env.clone(), "usdc".to_string()).unwrap();
+        let reward = reserves.multiply_ratio(1u128, 2u128);
+        assert_eq!(Uint128::new(100) + reward, module.total_staked);
+        let staker = query::get_staker(
+            deps.as_ref(),
+            env.clone(),
+            ADDR2.to_string(),
+            "usdc".to_string(),
+        )
+        .unwrap();
+        assert_eq!(module.total_staked, staker.value);
+
+        // unstaking waits for the cooldown
+        execute::request_unstake(
+            deps.as_mut(),
+            env.clone(),
+            mock_info(ADDR2, &[]),
+            "usdc".to_string(),
+            Uint128::new(50),
+        )
+        .unwrap();
+        let ends_at = env.block.time.seconds() + COOLDOWN;
+        let err = execute::unstake(
+            deps.as_mut(),
+            env.clone(),
+            mock_info(ADDR2, &[]),
+            "usdc".to_string(),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::CooldownNotFinished { ends_at: end } => assert_eq!(ends_at, end),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // once the remaining reserves are used up, bad debt takes 30% of the stake, shares
+        // waiting for their cooldown included
+        set_price(deps.as_mut(), "atom", 100_000);
+        let res = execute::liquidate(
+            deps.as_mut(),
+            env.clone(),
+            mock_info(ADDR2, &coins(900, "usdc")),
+            ADDR1.to_string(),
+            "atom".to_string(),
+        )
+        .unwrap();
+        assert_eq!(
+            ("from_reserves", (reserves - reward).to_string()),
+            res.events[0].attributes[3]
+        );
+        let slashed = module.total_staked.multiply_ratio(3u128, 10u128);
+        assert_eq!(
+            ("from_safety_module", slashed.to_string()),
+            res.events[0].attributes[5]
+        );
+        let module =
+            query::get_safety_module(deps.as_ref(), env.clone(), "usdc".to_string()).unwrap();
+        assert_eq!(Uint128::new(100) + reward - slashed, module.total_staked);
+
+        let res = execute::unstake(
+            deps.as_mut(),
+            env_after(math::SECONDS_PER_YEAR + COOLDOWN),
+            mock_info(ADDR2, &[]),
+            "usdc".to_string(),
+        )
+        .unwrap();
+        let amount = module.total_staked.multiply_ratio(1u128, 2u128);
+        assert_eq!(("amount", amount.to_string()), res.attributes[4]);
+        let staker = query::get_staker(
+            deps.as_ref(),
+            env_after(math::SECONDS_PER_YEAR + COOLDOWN),
+            ADDR2.to_string(),
+            "usdc".to_string(),
+        )
+        .unwrap();
+        assert_eq!(Uint128::new(50), staker.shares);
+        assert_eq!(Uint128::zero(), staker.unstaking);
+        assert_eq!(None, staker.cooldown_end);
+    }
+
+    #[test]
+    fn test_stale_price_pauses_borrowing() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(200, "atom")),
+            "usdc".to_string(),
+            Uint128::new(50),
+            "atom".to_string(),
+        )
+        .unwrap();
+
+        let env = env_after(MAX_PRICE_AGE + 1);
+        let price = query::get_price(deps.as_ref(), env.clone(), "atom".to_string()).unwrap();
+        assert!(price.stale);
+        let borrow = |deps: DepsMut, env: Env| {
+            execute::borrow(
+                deps,
+                env,
+                mock_info(ADDR1, &[]),
+                "usdc".to_string(),
+                Uint128::new(10),
+                "atom".to_string(),
+            )
+        };
+        let err = borrow(deps.as_mut(), env.clone()).unwrap_err();
+        match err {
+            ContractError::StalePrice { denom } => assert_eq!("usdc", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // Repayments need no prices and go through
+        execute::repay(
+            deps.as_mut(),
+            env.clone(),
+            mock_info(ADDR1, &coins(10, "usdc")),
+        )
+        .unwrap();
+
+        // Fresh prices resume borrowing
+        for denom in ["usdc", "atom"] {
+            execute::update_price(
+                deps.as_mut(),
+                env.clone(),
+                mock_info(FEEDER, &[]),
+                denom.to_string(),
+                Uint128::new(1_000_000),
+            )
+            .unwrap();
+        }
+        borrow(deps.as_mut(), env).unwrap();
+    }
+
+    #[test]
+    fn test_price_deviation_quarantine() {
+        let mut deps = setup();
+        execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            None,
+            None,
+            None,
+            None,
+            Some(OracleConfig {
+                feeders: vec![Addr::unchecked(FEEDER)],
+                quorum: 1,
+                feeds: vec![],
+                sources: vec![],
+                price_decimals: 6,
+                max_age: MAX_PRICE_AGE,
+                twap_window: TWAP_WINDOW,
+                max_observations: 10,
+                max_deviation: Some(Decimal::percent(10)),
+            }),
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+
+        // A 10% move is accepted
+        set_price(deps.as_mut(), "atom", 1_100_000);
+        let price = query::get_price(deps.as_ref(), mock_env(), "atom".to_string()).unwrap();
+        assert_eq!(Uint128::new(1_100_000), price.price);
+        assert_eq!(None, price.quarantined);
+
+        // A jump to $2 is held back
+        let res = execute::update_price(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(FEEDER, &[]),
+            "atom".to_string(),
+            Uint128::new(2_000_000),
+        )
+        .unwrap();
+        assert_eq!(("quarantined", "true"), res.attributes[5]);
+        let price = query::get_price(deps.as_ref(), mock_env(), "atom".to_string()).unwrap();
+        assert_eq!(Uint128::new(1_100_000), price.price);
+        assert_eq!(Uint128::new(2_000_000), price.quarantined.unwrap().price);
+
+        // A second update close to the outlier confirms it
+        set_price(deps.as_mut(), "atom", 1_950_000);
+        let price = query::get_price(deps.as_ref(), mock_env(), "atom".to_string()).unwrap();
+        assert_eq!(Uint128::new(1_950_000), price.price);
+        assert_eq!(None, price.quarantined);
+    }
+
+    #[test]
+    fn test_median_price_with_quorum() {
+        let mut deps = setup();
+        let feeders = ["archway1feeder1", "archway1feeder2", "archway1feeder3"];
+        execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            None,
+            None,
+            None,
+            None,
+            Some(OracleConfig {
+                feeders: vec![Addr::unchecked(FEEDER)],
+                quorum: 1,
+                feeds: vec![PriceFeed {
+                    denom: "atom".to_string(),
+                    feeders: feeders
+                        .iter()
+                        .map(|feeder| Addr::unchecked(*feeder))
+                        .collect(),
+                    quorum: 2,
+                }],
+                sources: vec![],
+                price_decimals: 6,
+                max_age: MAX_PRICE_AGE,
+                twap_window: TWAP_WINDOW,
+                max_observations: 10,
+                max_deviation: None,
+            }),
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        let submit = |deps: DepsMut, env: Env, feeder: &str, price: u128| {
+            execute::update_price(
+                deps,
+                env,
+                mock_info(feeder, &[]),
+                "atom".to_string(),
+                Uint128::new(price),
+            )
+        };
+        let atom_price =
+            |deps: Deps, env: Env| query::get_price(deps, env, "atom".to_string()).unwrap();
+
+        // atom has its own feeders, the default one can no longer price it
+        let err = submit(deps.as_mut(), mock_env(), FEEDER, 1_000_000).unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // A single submission is short of the quorum
+        submit(deps.as_mut(), mock_env(), feeders[0], 1_200_000).unwrap();
+        assert_eq!(
+            Uint128::new(1_000_000),
+            atom_price(deps.as_ref(), mock_env()).price
+        );
+        submit(deps.as_mut(), mock_env(), feeders[1], 1_400_000).unwrap();
+        assert_eq!(
+            Uint128::new(1_300_000),
+            atom_price(deps.as_ref(), mock_env()).price
+        );
+        // an outlier cannot move the median beyond the honest feeders
+        submit(deps.as_mut(), env_after(100), feeders[2], 50_000_000).unwrap();
+        assert_eq!(
+            Uint128::new(1_400_000),
+            atom_price(deps.as_ref(), mock_env()).price
+        );
+
+        // Once the first two go stale the last feeder alone cannot set the price, which is
+        // stale as well
+        let env = env_after(MAX_PRICE_AGE + 50);
+        submit(deps.as_mut(), env.clone(), feeders[2], 1_000_000).unwrap();
+        let price = atom_price(deps.as_ref(), env.clone());
+        assert_eq!(Uint128::new(1_400_000), price.price);
+        assert!(price.stale);
+        let submissions = query::get_feeder_prices(deps.as_ref(), env, "atom".to_string()).unwrap();
+        assert_eq!(2, submissions.quorum);
+        let fresh: Vec<bool> = submissions.prices.iter().map(|price| price.fresh).collect();
+        assert_eq!(vec![false, false, true], fresh);
+    }
+
+    // Mock oracles answer the adapters' queries with the response they were last given
+    const MOCK_RESPONSE: cw_storage_plus::Item<Binary> = cw_storage_plus::Item::new("response");
+
+    fn mock_oracle_set(
+        deps: DepsMut,
+        _env: Env,
+        _info: MessageInfo,
+        response: Binary,
+    ) -> StdResult<Response> {
+        MOCK_RESPONSE.save(deps.storage, &response)?;
+        Ok(Response::new())
+    }
+
+    fn mock_pyth_query(deps: Deps, _env: Env, msg: oracle::PythQueryMsg) -> StdResult<Binary> {
+        match msg {
+            oracle::PythQueryMsg::PriceFeed { .. } => MOCK_RESPONSE.load(deps.storage),
+        }
+    }
+
+    fn mock_band_query(deps: Deps, _env: Env, msg: oracle::BandQueryMsg) -> StdResult<Binary> {
+        match msg {
+            oracle::BandQueryMsg::GetReferenceData { .. } => MOCK_RESPONSE.load(deps.storage),
+        }
+    }
+
+    fn mock_dex_query(deps: Deps, _env: Env, msg: oracle::DexQueryMsg) -> StdResult<Binary> {
+        match msg {
+            oracle::DexQueryMsg::Twap { .. } => MOCK_RESPONSE.load(deps.storage),
+        }
+    }
+
+    fn pyth_response(price: i64, expo: i32, publish_time: i64) -> oracle::PriceFeedResponse {
+        oracle::PriceFeedResponse {
+            price_feed: oracle::PythPriceFeed {
+                id: "atom".to_string(),
+                price: oracle::PythPrice {
+                    price,
+                    conf: 0,
+                    expo,
+                    publish_time,
+                },
+            },
+        }
+    }
+
+    #[test]
+    fn test_external_price_sources() {
+        let (owner, feeder) = (Addr::unchecked(OWNER), Addr::unchecked(FEEDER));
+        let (borrower, lp) = (Addr::unchecked(ADDR1), Addr::unchecked(ADDR2));
+        let mut app = App::new(|router, _, storage| {
+            router
+                .bank
+                .init_balance(storage, &borrower, coins(100, "atom"))
+                .unwrap();
+            router
+                .bank
+                .init_balance(storage, &lp, coins(1000, "usdc"))
+                .unwrap();
+        });
+        let now = app.block_info().time.seconds();
+
+        let pyth_code = app.store_code(Box::new(ContractWrapper::new(
+            mock_oracle_set,
+            mock_oracle_set,
+            mock_pyth_query,
+        )));
+        let band_code = app.store_code(Box::new(ContractWrapper::new(
+            mock_oracle_set,
+            mock_oracle_set,
+            mock_band_query,
+        )));
+        let dex_code = app.store_code(Box::new(ContractWrapper::new(
+            mock_oracle_set,
+            mock_oracle_set,
+            mock_dex_query,
+        )));
+        let mut mock_oracle = |code: u64, response: Binary| {
+            app.instantiate_contract(code, owner.clone(), &response, &[], "oracle", None)
+                .unwrap()
+        };
+        // $2.50 as 250000000 * 10^-8
+        let pyth = mock_oracle(
+            pyth_code,
+            to_json_binary(&pyth_response(250_000_000, -8, now as i64)).unwrap(),
+        );
+        let band = mock_oracle(
+            band_code,
+            to_json_binary(&oracle::ReferenceData {
+                rate: Uint256::from(500_000_000_000_000_000u128),
+                last_updated_base: now,
+                last_updated_quote: now,
+            })
+            .unwrap(),
+        );
+        // 1.5 usdc per osmo
+        let dex = mock_oracle(
+            dex_code,
+            to_json_binary(&oracle::TwapResponse {
+                price: Decimal::percent(150),
+            })
+            .unwrap(),
+        );
+
+        let market_code =
+            app.store_code(Box::new(ContractWrapper::new(execute, instantiate, query)));
+        let mut msg = instantiate_msg();
+        msg.collateral_assets.push(collateral_asset("osmo"));
+        msg.oracle.sources = vec![
+            AssetPriceSource {
+                denom: "atom".to_string(),
+                source: PriceSource::Pyth {
+                    contract: pyth.clone(),
+                    price_feed_id: "atom".to_string(),
+                },
+            },
+            AssetPriceSource {
+                denom: "aarch".to_string(),
+                source: PriceSource::Band {
+                    contract: band,
+                    symbol: "ARCH".to_string(),
+                },
+            },
+            AssetPriceSource {
+                denom: "osmo".to_string(),
+                source: PriceSource::DexTwap {
+                    contract: dex,
+                    quote_denom: "usdc".to_string(),
+                    window: 1800,
+                },
+            },
+        ];
+        let market = app
+            .instantiate_contract(market_code, owner.clone(), &msg, &[], "eulend", None)
+            .unwrap();
+        app.execute_contract(
+            feeder,
+            market.clone(),
+            &ExecuteMsg::UpdatePrice {
+                token: "usdc".to_string(),
+                price: Uint128::new(1_000_000),
+            },
+            &[],
+        )
+        .unwrap();
+        app.execute_contract(
+            lp,
+            market.clone(),
+            &ExecuteMsg::ProvideLiquidity {},
+            &coins(1000, "usdc"),
+        )
+        .unwrap();
+
+        let price = |app: &App, denom: &str| {
+            let price: PriceResponse = app
+                .wrap()
+                .query_wasm_smart(
+                    &market,
+                    &QueryMsg::GetPrice {
+                        denom: denom.to_string(),
+                    },
+                )
+                .unwrap();
+            price
+        };
+        assert_eq!(Uint128::new(2_500_000), price(&app, "atom").price);
+        assert_eq!(Uint128::new(500_000), price(&app, "aarch").price);
+        assert_eq!(Uint128::new(1_500_000), price(&app, "osmo").price);
+
+        // 100 atom at the Pyth price of $2.50 with a 75% LTV supports 187 usdc
+        app.execute_contract(
+            borrower.clone(),
+            market.clone(),
+            &ExecuteMsg::CreateAccount {},
+            &[],
+        )
+        .unwrap();
+        app.execute_contract(
+            borrower.clone(),
+            market.clone(),
+            &ExecuteMsg::DepositCollateral {},
+            &coins(100, "atom"),
+        )
+        .unwrap();
+        let borrow = |app: &mut App, amount: u128| {
+            app.execute_contract(
+                borrower.clone(),
+                market.clone(),
+                &ExecuteMsg::Borrow {
+                    borrow_denom: "usdc".to_string(),
+                    borrow_amount: Uint128::new(amount),
+                    collateral_denom: "atom".to_string(),
+                },
+                &[],
+            )
+        };
+        let err = borrow(&mut app, 188).unwrap_err();
+        match err.downcast_ref::<ContractError>() {
+            Some(ContractError::InsufficientCollateral {}) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        borrow(&mut app, 100).unwrap();
+
+        // An old publish time on the feed pauses borrowing
+        app.execute_contract(
+            owner,
+            pyth,
+            &to_json_binary(&pyth_response(250_000_000, -8, 0)).unwrap(),
+            &[],
+        )
+        .unwrap();
+        assert!(price(&app, "atom").stale);
+        let err = borrow(&mut app, 1).unwrap_err();
+        match err.downcast_ref::<ContractError>() {
+            Some(ContractError::StalePrice { denom }) => assert_eq!("atom", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_twap_prices() {
+        let mut deps = setup();
+        let post = |deps: DepsMut, seconds: u64, token: &str, price: u128| {
+            execute::update_price(
+                deps,
+                env_after(seconds),
+                mock_info(FEEDER, &[]),
+                token.to_string(),
+                Uint128::new(price),
+            )
+            .unwrap();
+        };
+
+        // atom spends half the window at $1 and half at $3
+        post(deps.as_mut(), TWAP_WINDOW / 2, "atom", 3_000_000);
+        let env = env_after(TWAP_WINDOW);
+        let price = query::get_price(deps.as_ref(), env.clone(), "atom".to_string()).unwrap();
+        assert_eq!(Uint128::new(3_000_000), price.price);
+        assert_eq!(Uint128::new(2_000_000), price.twap);
+
+        // 100 atom at the $2 average with a 75% LTV supports 150 usdc
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        let borrow = |deps: DepsMut, amount: u128| {
+            execute::borrow(
+                deps,
+                env.clone(),
+                mock_info(ADDR1, &coins(100, "atom")),
+                "usdc".to_string(),
+                Uint128::new(amount),
+                "atom".to_string(),
+            )
+        };
+        let err = borrow(deps.as_mut(), 151).unwrap_err();
+        match err {
+            ContractError::InsufficientCollateral {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        borrow(deps.as_mut(), 150).unwrap();
+
+        // With two observations kept the first aarch price drops out of the average
+        execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            None,
+            None,
+            None,
+            None,
+            Some(OracleConfig {
+                max_observations: 2,
+                ..instantiate_msg().oracle
+            }),
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        post(deps.as_mut(), 100, "aarch", 2_000_000);
+        post(deps.as_mut(), 200, "aarch", 3_000_000);
+        let price = query::get_price(deps.as_ref(), env_after(300), "aarch".to_string()).unwrap();
+        assert_eq!(Uint128::new(2_500_000), price.twap);
+    }
+
+    #[test]
+    fn test_depeg_protection() {
+        let mut deps = setup();
+        let set_peg = |deps: DepsMut, floor_debt_value: bool| {
+            execute::update_config(
+                deps,
+                mock_env(),
+                mock_info(OWNER, &[]),
+                None,
+                Some(vec![BorrowAssetConfig {
+                    peg: Some(PegConfig {
+                        target: Uint128::new(1_000_000),
+                        max_deviation: Decimal::percent(2),
+                        floor_debt_value,
+                    }),
+                    ..usdc(Decimal::percent(10))
+                }]),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        };
+        set_peg(deps.as_mut(), true);
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        let borrow = |deps: DepsMut, funds: &[Coin], amount: u128| {
+            execute::borrow(
+                deps,
+                mock_env(),
+                mock_info(ADDR1, funds),
+                "usdc".to_string(),
+                Uint128::new(amount),
+                "atom".to_string(),
+            )
+        };
+        borrow(deps.as_mut(), &coins(100, "atom"), 70).unwrap();
+
+        // A 3% depeg pauses borrowing, a 1% one does not
+        set_price(deps.as_mut(), "usdc", 970_000);
+        let err = borrow(deps.as_mut(), &[], 1).unwrap_err();
+        match err {
+            ContractError::Depegged { denom } => assert_eq!("usdc", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        set_price(deps.as_mut(), "usdc", 990_000);
+        borrow(deps.as_mut(), &[], 1).unwrap();
+
+        // 71 usdc of debt against a $70.40 liquidation limit is only covered when the depeg is
+        // allowed to shrink the debt's value to $68.87
+        set_price(deps.as_mut(), "usdc", 970_000);
+        set_price(deps.as_mut(), "atom", 880_000);
+        let refresh = |deps: DepsMut| {
+            let res = execute::refresh_liquidation(deps, mock_env(), ADDR1.to_string()).unwrap();
+            res.attributes[2].value.clone()
+        };
+        assert_eq!(
+            mock_env().block.time.seconds().to_string(),
+            refresh(deps.as_mut())
+        );
+        set_peg(deps.as_mut(), false);
+        assert_eq!("", refresh(deps.as_mut()));
+    }
+
+    #[test]
+    fn test_simulations() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        let simulate_borrow = |deps: Deps, amount: u128| {
+            query::simulate_borrow(
+                deps,
+                mock_env(),
+                ADDR1.to_string(),
+                "usdc".to_string(),
+                Uint128::new(amount),
+                Some(Coin::new(200, "atom")),
+            )
+            .unwrap()
+        };
+
+        // 200 atom at an 80% liquidation threshold against 100 usdc
+        let res = simulate_borrow(deps.as_ref(), 100);
+        assert_eq!(None, res.error);
+        assert_eq!(coins(100, "usdc"), res.received);
+        assert_eq!(Some(Decimal::percent(160)), res.health_factor);
+        assert!(res.fees.is_empty());
+        assert_eq!(None, res.liquidation_bonus);
+        let res = simulate_borrow(deps.as_ref(), 151);
+        assert_eq!(
+            Some(ContractError::InsufficientCollateral {}.to_string()),
+            res.error
+        );
+        // nothing was saved
+        assert!(query::get_collaterals(deps.as_ref(), ADDR1.to_string())
+            .unwrap()
+            .is_empty());
+
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(200, "atom")),
+            "usdc".to_string(),
+            Uint128::new(100),
+            "atom".to_string(),
+        )
+        .unwrap();
+
+        let res = query::simulate_repay(
+            deps.as_ref(),
+            mock_env(),
+            ADDR1.to_string(),
+            Coin::new(150, "usdc"),
+        )
+        .unwrap();
+        assert_eq!(coins(50, "usdc"), res.received);
+        assert_eq!(None, res.health_factor);
+        assert!(res.fees.is_empty());
+        // a year on the repayment settles the interest accrued on the debt
+        let res = query::simulate_repay(
+            deps.as_ref(),
+            env_after(math::SECONDS_PER_YEAR),
+            ADDR1.to_string(),
+            Coin::new(150, "usdc"),
+        )
+        .unwrap();
+        assert_eq!(coins(40, "usdc"), res.received);
+        assert_eq!(coins(10, "usdc"), res.fees);
+
+        let simulate_withdraw = |deps: Deps, amount: u128| {
+            query::simulate_withdraw_collateral(
+                deps,
+                mock_env(),
+                ADDR1.to_string(),
+                "atom".to_string(),
+                Uint128::new(amount),
+            )
+            .unwrap()
+        };
+        let res = simulate_withdraw(deps.as_ref(), 100);
+        assert_eq!(
+            Some(ContractError::InsufficientCollateral {}.to_string()),
+            res.error
+        );
+        let res = simulate_withdraw(deps.as_ref(), 50);
+        assert_eq!(coins(50, "atom"), res.received);
+        assert_eq!(Some(Decimal::percent(120)), res.health_factor);
+        assert!(res.fees.is_empty());
+        assert_eq!(None, res.liquidation_bonus);
+
+        let simulate_liquidation = |deps: Deps| {
+            query::simulate_liquidation(
+                deps,
+                mock_env(),
+                ADDR2.to_string(),
+                ADDR1.to_string(),
+                "atom".to_string(),
+                Coin::new(50, "usdc"),
+            )
+            .unwrap()
+        };
+        assert_eq!(
+            Some(ContractError::AccountHealthy {}.to_string()),
+            simulate_liquidation(deps.as_ref()).error
+        );
+        // at $0.60 the 200 atom only support 96 usdc of debt
+        set_price(deps.as_mut(), "atom", 600_000);
+        let res = simulate_liquidation(deps.as_ref());
+        assert_eq!(None, res.error);
+        assert_eq!("atom", res.received[0].denom);
+        assert!(!res.received[0].amount.is_zero());
+        // just started, the auction's bonus is too small to add a whole atom
+        assert_eq!(Some(Coin::new(0, "atom")), res.liquidation_bonus);
+        assert!(res.fees.is_empty());
+        // the auction has not started outside the simulation
+        let liquidation =
+            query::get_liquidation(deps.as_ref(), env_after(60), ADDR1.to_string()).unwrap();
+        assert_eq!(
+            Some(env_after(60).block.time.seconds()),
+            liquidation.started_at
+        );
+
+        // once the auction has run its course the liquidator gets the full 5% bonus
+        execute::refresh_liquidation(deps.as_mut(), mock_env(), ADDR1.to_string()).unwrap();
+        let res = query::simulate_liquidation(
+            deps.as_ref(),
+            env_after(AUCTION_DURATION),
+            ADDR2.to_string(),
+            ADDR1.to_string(),
+            "atom".to_string(),
+            Coin::new(50, "usdc"),
+        )
+        .unwrap();
+        // 50 usdc of debt is worth 83.3 atom at $0.60 and the 5% on top brings it to 87.5
+        assert_eq!(coins(87, "atom"), res.received);
+        assert_eq!(Some(Coin::new(3, "atom")), res.liquidation_bonus);
+        // an hour of interest on the 100 usdc debt is rounded up to 1 usdc when settled
+        assert_eq!(coins(1, "usdc"), res.fees);
+    }
+
+    #[test]
+    fn test_max_borrowable_and_withdrawable() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        set_price(deps.as_mut(), "atom", 1_370_000);
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(333, "atom")),
+        )
+        .unwrap();
+        let max_borrowable = |deps: Deps| {
+            query::max_borrowable(deps, mock_env(), ADDR1.to_string(), "usdc".to_string())
+                .unwrap()
+                .amount
+        };
+        let simulate_borrow = |deps: Deps, amount: Uint128| {
+            query::simulate_borrow(
+                deps,
+                mock_env(),
+                ADDR1.to_string(),
+                "usdc".to_string(),
+                amount,
+                None,
+            )
+            .unwrap()
+            .error
+        };
+
+        // $456.21 of atom at a 75% LTV
+        let max = max_borrowable(deps.as_ref());
+        assert_eq!(Uint128::new(342), max);
+        assert_eq!(None, simulate_borrow(deps.as_ref(), max));
+        assert_eq!(
+            Some(ContractError::InsufficientCollateral {}.to_string()),
+            simulate_borrow(deps.as_ref(), max + Uint128::one())
+        );
+
+        // A borrow cap below the limit bounds it instead
+        execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            None,
+            Some(vec![BorrowAssetConfig {
+                borrow_cap: Some(Uint128::new(100)),
+                ..usdc(Decimal::percent(10))
+            }]),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        assert_eq!(Uint128::new(100), max_borrowable(deps.as_ref()));
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &[]),
+            "usdc".to_string(),
+            Uint128::new(100),
+            "atom".to_string(),
+        )
+        .unwrap();
+        assert_eq!(Uint128::zero(), max_borrowable(deps.as_ref()));
 
-    fn setup() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
-        let mut deps = mock_dependencies();
-        let msg = InstantiateMsg {};
-        let info = mock_info(ADDR1, &[]);
-        let env = mock_env();
-        
-        instantiate(deps.as_mut(), env, info, msg).unwrap();
-        deps
+        // 100 usdc of debt needs 98 of the atom to stay
+        let max = query::max_withdrawable(
+            deps.as_ref(),
+            mock_env(),
+            ADDR1.to_string(),
+            "atom".to_string(),
+        )
+        .unwrap()
+        .amount;
+        assert_eq!(Uint128::new(235), max);
+        let simulate_withdraw = |deps: Deps, amount: Uint128| {
+            query::simulate_withdraw_collateral(
+                deps,
+                mock_env(),
+                ADDR1.to_string(),
+                "atom".to_string(),
+                amount,
+            )
+            .unwrap()
+            .error
+        };
+        assert_eq!(None, simulate_withdraw(deps.as_ref(), max));
+        assert_eq!(
+            Some(ContractError::InsufficientCollateral {}.to_string()),
+            simulate_withdraw(deps.as_ref(), max + Uint128::one())
+        );
+    }
+
+    // Mock DEX paying out the last denom of the route at a fixed rate per unit swapped. It leaves
+    // enforcing the minimum output to the market.
+    const MOCK_RATE: cw_storage_plus::Item<Decimal> = cw_storage_plus::Item::new("rate");
+
+    #[cosmwasm_schema::cw_serde]
+    enum MockDexMsg {
+        Swap {
+            route: Vec<SwapStep>,
+            min_output: Uint128,
+        },
+        SetRate {
+            rate: Decimal,
+        },
+    }
+
+    fn mock_dex_instantiate(
+        deps: DepsMut,
+        _env: Env,
+        _info: MessageInfo,
+        rate: Decimal,
+    ) -> StdResult<Response> {
+        MOCK_RATE.save(deps.storage, &rate)?;
+        Ok(Response::new())
+    }
+
+    fn mock_dex_execute(
+        deps: DepsMut,
+        _env: Env,
+        info: MessageInfo,
+        msg: MockDexMsg,
+    ) -> StdResult<Response> {
+        match msg {
+            MockDexMsg::Swap { route, .. } => {
+                let rate = MOCK_RATE.load(deps.storage)?;
+                let output = math::mul_decimal(info.funds[0].amount, rate, Rounding::Down)?;
+                Ok(Response::new().add_message(BankMsg::Send {
+                    to_address: info.sender.to_string(),
+                    amount: coins(output.u128(), &route.last().unwrap().denom_out),
+                }))
+            }
+            MockDexMsg::SetRate { rate } => {
+                MOCK_RATE.save(deps.storage, &rate)?;
+                Ok(Response::new())
+            }
+        }
+    }
+
+    // Market with a mock DEX holding atom and osmo to swap into, usdc to borrow and an account
+    // holding 100 atom of collateral. atom and usdc are at $1, osmo at $2.
+    fn dex_market() -> (App, Addr, Addr) {
+        let (owner, feeder) = (Addr::unchecked(OWNER), Addr::unchecked(FEEDER));
+        let (borrower, lp) = (Addr::unchecked(ADDR1), Addr::unchecked(ADDR2));
+        let mut app = App::new(|router, _, storage| {
+            router
+                .bank
+                .init_balance(
+                    storage,
+                    &owner,
+                    vec![Coin::new(1000, "atom"), Coin::new(1000, "osmo")],
+                )
+                .unwrap();
+            router
+                .bank
+                .init_balance(storage, &borrower, coins(100, "atom"))
+                .unwrap();
+            router
+                .bank
+                .init_balance(storage, &lp, coins(1000, "usdc"))
+                .unwrap();
+        });
+
+        let dex_code = app.store_code(Box::new(ContractWrapper::new(
+            mock_dex_execute,
+            mock_dex_instantiate,
+            mock_dex_query,
+        )));
+        let dex = app
+            .instantiate_contract(dex_code, owner.clone(), &Decimal::one(), &[], "dex", None)
+            .unwrap();
+        app.send_tokens(
+            owner.clone(),
+            dex.clone(),
+            &[Coin::new(1000, "atom"), Coin::new(1000, "osmo")],
+        )
+        .unwrap();
+
+        let market_code = app.store_code(Box::new(
+            ContractWrapper::new(execute, instantiate, query).with_reply(reply),
+        ));
+        let mut msg = instantiate_msg();
+        msg.collateral_assets.push(collateral_asset("osmo"));
+        msg.dex = Some(DexConfig {
+            contract: dex.clone(),
+            max_slippage: Decimal::percent(1),
+        });
+        let market = app
+            .instantiate_contract(market_code, owner, &msg, &[], "eulend", None)
+            .unwrap();
+        for (token, price) in [
+            ("usdc", 1_000_000),
+            ("atom", 1_000_000),
+            ("osmo", 2_000_000),
+        ] {
+            app.execute_contract(
+                feeder.clone(),
+                market.clone(),
+                &ExecuteMsg::UpdatePrice {
+                    token: token.to_string(),
+                    price: Uint128::new(price),
+                },
+                &[],
+            )
+            .unwrap();
+        }
+        app.execute_contract(
+            lp,
+            market.clone(),
+            &ExecuteMsg::ProvideLiquidity {},
+            &coins(1000, "usdc"),
+        )
+        .unwrap();
+        app.execute_contract(
+            borrower.clone(),
+            market.clone(),
+            &ExecuteMsg::CreateAccount {},
+            &[],
+        )
+        .unwrap();
+        app.execute_contract(
+            borrower,
+            market.clone(),
+            &ExecuteMsg::DepositCollateral {},
+            &coins(100, "atom"),
+        )
+        .unwrap();
+        (app, market, dex)
+    }
+
+    fn set_swap_rate(app: &mut App, dex: &Addr, rate: Decimal) {
+        app.execute_contract(
+            Addr::unchecked(OWNER),
+            dex.clone(),
+            &MockDexMsg::SetRate { rate },
+            &[],
+        )
+        .unwrap();
+    }
+
+    // usdc the dex market's borrower can still borrow
+    fn max_usdc_borrowable(app: &App, market: &Addr) -> Uint128 {
+        let max: MaxAmountResponse = app
+            .wrap()
+            .query_wasm_smart(
+                market,
+                &QueryMsg::MaxBorrowable {
+                    address: ADDR1.to_string(),
+                    pool_id: "usdc".to_string(),
+                },
+            )
+            .unwrap();
+        max.amount
     }
 
     #[test]
-    fn test_instantiate() {
-        let mut deps = mock_dependencies();
-        let msg = InstantiateMsg {};
-        let info = mock_info(ADDR1, &[]);
-        let env = mock_env();
+    fn test_leverage() {
+        let (mut app, market, dex) = dex_market();
+        let borrower = Addr::unchecked(ADDR1);
+        let leverage = |app: &mut App, target_ltv: u64| {
+            app.execute_contract(
+                borrower.clone(),
+                market.clone(),
+                &ExecuteMsg::Leverage {
+                    collateral_denom: "atom".to_string(),
+                    borrow_denom: "usdc".to_string(),
+                    target_ltv: Decimal::percent(target_ltv),
+                    swap_route: vec![SwapStep {
+                        pool_id: 1,
+                        denom_out: "atom".to_string(),
+                    }],
+                },
+                &[],
+            )
+        };
+        let balance =
+            |app: &App, denom: &str| app.wrap().query_balance(&market, denom).unwrap().amount;
 
-        let res = instantiate(deps.as_mut(), env, info, msg).unwrap();
-        assert_eq!(1, res.attributes.len());
-        assert_eq!(("method", "instantiate"), res.attributes[0]);
+        // 150 usdc swapped for 135 atom falls short of the 148 allowed by 1% slippage, and the
+        // borrow is reverted with the swap
+        set_swap_rate(&mut app, &dex, Decimal::percent(90));
+        let err = leverage(&mut app, 60).unwrap_err();
+        match err.downcast_ref::<ContractError>() {
+            Some(ContractError::SlippageExceeded {
+                min_output,
+                received,
+            }) => {
+                assert_eq!(Uint128::new(148), *min_output);
+                assert_eq!(Uint128::new(135), *received);
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(Uint128::new(1000), balance(&app, "usdc"));
+        assert_eq!(Uint128::new(75), max_usdc_borrowable(&app, &market));
+
+        // 100 atom levered to 60% borrows 150 usdc for 150 more atom: 150 / 250
+        set_swap_rate(&mut app, &dex, Decimal::one());
+        let res = leverage(&mut app, 60).unwrap();
+        assert!(res.has_event(
+            &Event::new("wasm")
+                .add_attribute("method", "settle_leverage")
+                .add_attribute("received", "150")
+        ));
+        assert_eq!(Uint128::new(850), balance(&app, "usdc"));
+        assert_eq!(Uint128::new(250), balance(&app, "atom"));
+        // 75% of 250 atom leaves room for 37 more usdc
+        assert_eq!(Uint128::new(37), max_usdc_borrowable(&app, &market));
+
+        let err = leverage(&mut app, 50).unwrap_err();
+        match err.downcast_ref::<ContractError>() {
+            Some(ContractError::LeverageTargetReached {}) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // 80% is beyond the 75% max LTV, so the account fails the check once the swap settles
+        let err = leverage(&mut app, 80).unwrap_err();
+        match err.downcast_ref::<ContractError>() {
+            Some(ContractError::InsufficientCollateral {}) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(Uint128::new(850), balance(&app, "usdc"));
+        assert_eq!(Uint128::new(37), max_usdc_borrowable(&app, &market));
     }
 
     #[test]
-    fn test_create_account() {
+    fn test_swap_collateral() {
+        let (mut app, market, dex) = dex_market();
+        let borrower = Addr::unchecked(ADDR1);
+        app.execute_contract(
+            borrower.clone(),
+            market.clone(),
+            &ExecuteMsg::Borrow {
+                borrow_denom: "usdc".to_string(),
+                borrow_amount: Uint128::new(60),
+                collateral_denom: "atom".to_string(),
+            },
+            &[],
+        )
+        .unwrap();
+        let swap = |app: &mut App, to_denom: &str, amount: u128, min_out: u128| {
+            app.execute_contract(
+                borrower.clone(),
+                market.clone(),
+                &ExecuteMsg::SwapCollateral {
+                    from_denom: "atom".to_string(),
+                    to_denom: to_denom.to_string(),
+                    amount: Uint128::new(amount),
+                    min_out: Uint128::new(min_out),
+                    dex_route: vec![SwapStep {
+                        pool_id: 1,
+                        denom_out: to_denom.to_string(),
+                    }],
+                },
+                &[],
+            )
+        };
+        let balance =
+            |app: &App, denom: &str| app.wrap().query_balance(&market, denom).unwrap().amount;
+
+        let err = swap(&mut app, "atom", 50, 0).unwrap_err();
+        match err.downcast_ref::<ContractError>() {
+            Some(ContractError::InvalidSwapRoute { denom }) => assert_eq!("atom", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // 50 atom at $1 buys 25 osmo at $2
+        set_swap_rate(&mut app, &dex, Decimal::percent(50));
+        let err = swap(&mut app, "osmo", 50, 26).unwrap_err();
+        match err.downcast_ref::<ContractError>() {
+            Some(ContractError::SlippageExceeded {
+                min_output,
+                received,
+            }) => {
+                assert_eq!(Uint128::new(26), *min_output);
+                assert_eq!(Uint128::new(25), *received);
+            }
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(Uint128::new(100), balance(&app, "atom"));
+
+        let res = swap(&mut app, "osmo", 50, 25).unwrap();
+        assert!(res.has_event(
+            &Event::new("wasm")
+                .add_attribute("method", "settle_collateral_swap")
+                .add_attribute("received", "25")
+        ));
+        assert_eq!(Uint128::new(50), balance(&app, "atom"));
+        assert_eq!(Uint128::new(25), balance(&app, "osmo"));
+        // the collateral is still worth $100, leaving 75 - 60 usdc to borrow
+        assert_eq!(Uint128::new(15), max_usdc_borrowable(&app, &market));
+
+        // selling the rest of the atom for 5 osmo would leave $60 of collateral for 60 usdc
+        set_swap_rate(&mut app, &dex, Decimal::percent(10));
+        let err = swap(&mut app, "osmo", 50, 0).unwrap_err();
+        match err.downcast_ref::<ContractError>() {
+            Some(ContractError::InsufficientCollateral {}) => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(Uint128::new(50), balance(&app, "atom"));
+        assert_eq!(Uint128::new(15), max_usdc_borrowable(&app, &market));
+    }
+
+    #[test]
+    fn test_liquidation_releases_isolated_debt() {
         let mut deps = setup();
-        let info = mock_info(ADDR1, &[]);
+        execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            None,
+            None,
+            Some(vec![CollateralAsset {
+                debt_ceiling: Some(Uint128::new(150_000_000_000_000)),
+                ..collateral_asset("pepe")
+            }]),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        set_price(deps.as_mut(), "pepe", 1_000_000);
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(100, "pepe")),
+            "usdc".to_string(),
+            Uint128::new(75),
+            "pepe".to_string(),
+        )
+        .unwrap();
+        let isolated_debt = |deps: Deps| {
+            query::get_market(deps, mock_env(), "pepe".to_string())
+                .unwrap()
+                .isolated_debt
+        };
+        assert_eq!(
+            Uint128::new(75_000_000_000_000),
+            isolated_debt(deps.as_ref())
+        );
 
-        // Test successful account creation
-        let res = execute::create_account(deps.as_mut(), info.clone()).unwrap();
-        assert_eq!(2, res.attributes.len());
-        assert_eq!(("method", "create_account"), res.attributes[0]);
-        assert_eq!(("address", ADDR1), res.attributes[1]);
+        // pepe crashes and the liquidation takes all of it, writing the rest of the debt off
+        set_price(deps.as_mut(), "pepe", 100_000);
+        execute::refresh_liquidation(deps.as_mut(), mock_env(), ADDR1.to_string()).unwrap();
+        let res = execute::liquidate(
+            deps.as_mut(),
+            env_after(AUCTION_DURATION),
+            mock_info(ADDR2, &coins(75, "usdc")),
+            ADDR1.to_string(),
+            "pepe".to_string(),
+        )
+        .unwrap();
+        assert_eq!(("seized", "100"), res.attributes[5]);
+        assert!(query::get_collaterals(deps.as_ref(), ADDR1.to_string())
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            Uint128::zero(),
+            usdc_debt(deps.as_ref(), env_after(AUCTION_DURATION), ADDR1)
+        );
 
-        // Test duplicate account creation
-        let err = execute::create_account(deps.as_mut(), info).unwrap_err();
+        // the ceiling is free again
+        assert_eq!(Uint128::zero(), isolated_debt(deps.as_ref()));
+    }
+
+    #[test]
+    fn test_swap_isolated_collateral() {
+        let mut deps = setup();
+        execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            None,
+            None,
+            Some(vec![CollateralAsset {
+                debt_ceiling: Some(Uint128::new(150_000_000_000_000)),
+                ..collateral_asset("pepe")
+            }]),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(DexConfig {
+                contract: Addr::unchecked("dex"),
+                max_slippage: Decimal::percent(1),
+            }),
+        )
+        .unwrap();
+        set_price(deps.as_mut(), "pepe", 1_000_000);
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(100, "pepe")),
+            "usdc".to_string(),
+            Uint128::new(75),
+            "pepe".to_string(),
+        )
+        .unwrap();
+        let swap = |deps: DepsMut| {
+            execute::swap_collateral(
+                deps,
+                mock_env(),
+                mock_info(ADDR1, &[]),
+                "pepe".to_string(),
+                "atom".to_string(),
+                Uint128::new(100),
+                Uint128::new(90),
+                vec![SwapStep {
+                    pool_id: 1,
+                    denom_out: "atom".to_string(),
+                }],
+            )
+        };
+
+        // selling the pepe would take the debt out of isolation without releasing the ceiling
+        let err = swap(deps.as_mut()).unwrap_err();
         match err {
-            ContractError::AccountExists {} => {}
+            ContractError::IsolatedSwap { denom } => assert_eq!("pepe", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let collaterals = query::get_collaterals(deps.as_ref(), ADDR1.to_string()).unwrap();
+        assert_eq!(Uint128::new(100), collaterals[0].amount);
+
+        execute::repay_all(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(75, "usdc")),
+        )
+        .unwrap();
+        let res = swap(deps.as_mut()).unwrap();
+        assert_eq!(1, res.messages.len());
+    }
+
+    #[test]
+    fn test_unstake_request_expires() {
+        let mut deps = setup();
+        execute::stake(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &coins(100, "usdc")),
+        )
+        .unwrap();
+        let request_unstake = |deps: DepsMut, env: Env| {
+            execute::request_unstake(
+                deps,
+                env,
+                mock_info(ADDR2, &[]),
+                "usdc".to_string(),
+                Uint128::new(40),
+            )
+            .unwrap();
+        };
+        let unstake = |deps: DepsMut, env: Env| {
+            execute::unstake(deps, env, mock_info(ADDR2, &[]), "usdc".to_string())
+        };
+        request_unstake(deps.as_mut(), mock_env());
+
+        // the request can be carried out until the window after the cooldown closes
+        let expired_at = mock_env().block.time.seconds() + COOLDOWN + UNSTAKE_WINDOW;
+        let err = unstake(deps.as_mut(), env_after(COOLDOWN + UNSTAKE_WINDOW + 1)).unwrap_err();
+        match err {
+            ContractError::UnstakeRequestExpired { expired_at: at } => assert_eq!(expired_at, at),
             e => panic!("unexpected error: {:?}", e),
+        }
 
+        // a lapsed request has to wait out a fresh cooldown
+        let env = env_after(COOLDOWN + UNSTAKE_WINDOW + 1);
+        request_unstake(deps.as_mut(), env.clone());
+        let err = unstake(deps.as_mut(), env).unwrap_err();
+        match err {
+            ContractError::CooldownNotFinished { .. } => {}
+            e => panic!("unexpected error: {:?}", e),
         }
+        let res = unstake(
+            deps.as_mut(),
+            env_after(2 * COOLDOWN + 2 * UNSTAKE_WINDOW + 1),
+        )
+        .unwrap();
+        assert_eq!(("amount", "40"), res.attributes[4]);
     }
 
+    #[test]
+    fn test_update_collateral_asset() {
+        let mut deps = setup();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(100, "atom")),
+        )
+        .unwrap();
+        let update = |deps: DepsMut, asset: CollateralAsset| {
+            execute::update_config(
+                deps,
+                mock_env(),
+                mock_info(OWNER, &[]),
+                None,
+                None,
+                Some(vec![asset]),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+        };
+
+        let err = update(
+            deps.as_mut(),
+            CollateralAsset {
+                decimals: 8,
+                ..collateral_asset("atom")
+            },
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InvalidConfig { .. } => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // deposited atom cannot be isolated, but pepe can as long as nobody holds any
+        let isolated = |denom: &str| CollateralAsset {
+            debt_ceiling: Some(Uint128::new(150_000_000_000_000)),
+            ..collateral_asset(denom)
+        };
+        let err = update(deps.as_mut(), isolated("atom")).unwrap_err();
+        match err {
+            ContractError::InvalidConfig { .. } => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        update(deps.as_mut(), isolated("pepe")).unwrap();
+        update(deps.as_mut(), collateral_asset("pepe")).unwrap();
 
+        // the rest of the parameters can change
+        update(
+            deps.as_mut(),
+            CollateralAsset {
+                max_ltv: Decimal::percent(50),
+                ..collateral_asset("atom")
+            },
+        )
+        .unwrap();
+        let config = query::get_config(deps.as_ref()).unwrap();
+        let atom = config
+            .collateral_assets
+            .iter()
+            .find(|asset| asset.denom == "atom");
+        assert_eq!(Decimal::percent(50), atom.unwrap().max_ltv);
+    }
 
     #[test]
-    fn test_borrow() {
+    fn test_stale_liquidation_auction() {
         let mut deps = setup();
-        
-        // Create account first
-        let info = mock_info(ADDR1, &[]);
-        execute::create_account(deps.as_mut(), info.clone()).unwrap();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::borrow(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(100, "atom")),
+            "usdc".to_string(),
+            Uint128::new(75),
+            "atom".to_string(),
+        )
+        .unwrap();
+        set_price(deps.as_mut(), "atom", 900_000);
+        execute::refresh_liquidation(deps.as_mut(), mock_env(), ADDR1.to_string()).unwrap();
 
-        // Test successful borrow
-        let borrow_amount = Uint128::new(100);
-        let collateral_amount = Uint128::new(200);
-        let collateral_denom = "atom".to_string();
-        
-        let info = mock_info(
-            ADDR1,
-            &coins(200, "atom"), // Sending collateral
-        );
-        
-        let res = execute::borrow(
+        // the price recovers without anyone touching the account, then falls again
+        set_price(deps.as_mut(), "atom", 1_000_000);
+        set_price(deps.as_mut(), "atom", 900_000);
+
+        // an auction nobody has seen for longer than it runs starts over instead of paying the
+        // full bonus at once
+        let env = env_after(2 * AUCTION_DURATION);
+        let now = env.block.time.seconds();
+        let auction =
+            query::get_liquidation(deps.as_ref(), env.clone(), ADDR1.to_string()).unwrap();
+        assert_eq!(Some(now), auction.started_at);
+        assert!(auction.progress < Decimal::percent(10));
+        let res = execute::refresh_liquidation(deps.as_mut(), env, ADDR1.to_string()).unwrap();
+        assert_eq!(("started_at", now.to_string()), res.attributes[2]);
+
+        // an auction kept under watch carries on
+        let env = env_after(3 * AUCTION_DURATION);
+        let auction = query::get_liquidation(deps.as_ref(), env, ADDR1.to_string()).unwrap();
+        assert_eq!(Some(now), auction.started_at);
+        assert_eq!(Decimal::one(), auction.progress);
+    }
+
+    #[test]
+    fn test_swap_route_and_funds() {
+        let mut deps = setup();
+        execute::update_config(
             deps.as_mut(),
             mock_env(),
-            info,
-            borrow_amount,
-            collateral_denom.clone(),
-            collateral_amount,
-        ).unwrap();
+            mock_info(OWNER, &[]),
+            None,
+            None,
+            Some(vec![collateral_asset("osmo")]),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Some(DexConfig {
+                contract: Addr::unchecked("dex"),
+                max_slippage: Decimal::percent(1),
+            }),
+        )
+        .unwrap();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(100, "atom")),
+        )
+        .unwrap();
+        let step = |denom: &str| SwapStep {
+            pool_id: 1,
+            denom_out: denom.to_string(),
+        };
+        let swap = |deps: DepsMut, funds: &[Coin], route: Vec<SwapStep>| {
+            execute::swap_collateral(
+                deps,
+                mock_env(),
+                mock_info(ADDR1, funds),
+                "atom".to_string(),
+                "osmo".to_string(),
+                Uint128::new(50),
+                Uint128::zero(),
+                route,
+            )
+        };
 
-        // Verify response
-        assert_eq!(5, res.attributes.len());
-        assert_eq!(("method", "borrow"), res.attributes[0]);
-        assert_eq!(("borrower", ADDR1), res.attributes[1]);
-        assert_eq!(("collateral_denom", "atom"), res.attributes[2]);
-        assert_eq!(("collateral_amount", "200"), res.attributes[3]);
-        assert_eq!(("borrowed_amount", "100"), res.attributes[4]);
+        // attached coins would be kept
+        let err = swap(deps.as_mut(), &coins(10, "usdc"), vec![step("osmo")]).unwrap_err();
+        match err {
+            ContractError::NonPayable {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let err = execute::leverage(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(10, "usdc")),
+            "atom".to_string(),
+            "usdc".to_string(),
+            Decimal::percent(50),
+            vec![step("atom")],
+        )
+        .unwrap_err();
+        match err {
+            ContractError::NonPayable {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
 
-        // Verify bank message
-        assert_eq!(1, res.messages.len());
-        match &res.messages[0].msg {
-            cosmwasm_std::CosmosMsg::Bank(BankMsg::Send { to_address, amount }) => {
-                assert_eq!(to_address, &ADDR1.to_string());
-                assert_eq!(
-                    amount,
-                    &vec![Coin {
-                        denom: "usdc".to_string(),
-                        amount: borrow_amount,
-                    }]
-                );
+        // every hop has to trade what the one before bought for something else
+        for route in [
+            vec![step("atom"), step("osmo")],
+            vec![step("usdc"), step("usdc"), step("osmo")],
+        ] {
+            let err = swap(deps.as_mut(), &[], route).unwrap_err();
+            match err {
+                ContractError::InvalidSwapRoute { denom } => assert_eq!("osmo", denom),
+                e => panic!("unexpected error: {:?}", e),
             }
-            _ => panic!("unexpected message type"),
         }
+        let res = swap(deps.as_mut(), &[], vec![step("usdc"), step("osmo")]).unwrap();
+        assert_eq!(1, res.messages.len());
     }
 
     #[test]
-    fn test_repay() {
+    fn test_pyth_price_below_precision() {
+        let mut deps = mock_dependencies();
+        let response = pyth_response(5, -8, 0);
+        deps.querier.update_wasm(move |_| {
+            SystemResult::Ok(ContractResult::Ok(to_json_binary(&response).unwrap()))
+        });
+        let pyth = Addr::unchecked("pyth");
+
+        // $0.00000005 is below the oracle's 6 price decimals and must not read as free
+        let err = oracle::pyth_price(deps.as_ref().querier, &pyth, "atom", "atom", 6).unwrap_err();
+        match err {
+            ContractError::InvalidPrice { denom } => assert_eq!("atom", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let price = oracle::pyth_price(deps.as_ref().querier, &pyth, "atom", "atom", 8).unwrap();
+        assert_eq!(Uint128::new(5), price.price);
+    }
+
+    #[test]
+    fn test_pool_utilization_includes_accrued_interest() {
         let mut deps = setup();
-        
-        // Setup: Create account and borrow first
-        let info = mock_info(ADDR1, &[]);
-        execute::create_account(deps.as_mut(), info.clone()).unwrap();
-        
-        let borrow_amount = Uint128::new(100);
-        let collateral_amount = Uint128::new(200);
-        let collateral_denom = "atom".to_string();
-        
-        let info = mock_info(
-            ADDR1,
-            &coins(200, "atom"),
-        );
-        
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::deposit_collateral(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR1, &coins(2000, "atom")),
+        )
+        .unwrap();
         execute::borrow(
             deps.as_mut(),
             mock_env(),
-            info,
-            borrow_amount,
-            collateral_denom.clone(),
-            collateral_amount,
-        ).unwrap();
+            mock_info(ADDR1, &[]),
+            "usdc".to_string(),
+            Uint128::new(450),
+            "atom".to_string(),
+        )
+        .unwrap();
 
-        // Test successful repayment
-        let repay_info = mock_info(
-            ADDR1,
-            &coins(100, "usdc"), // Repaying USDC
+        // three years of interest push the pool past the medium threshold before anyone
+        // touches it again
+        let env = env_after(3 * math::SECONDS_PER_YEAR);
+        let pool = query::get_pool(deps.as_ref(), env, "usdc".to_string()).unwrap();
+        let stored = POOLS.load(&deps.storage, "usdc").unwrap();
+        assert!(pool.total_borrowed > stored.total_borrowed);
+        assert_eq!(PoolUtilization::Low, stored.utilization);
+        assert_eq!(
+            Decimal::from_ratio(
+                pool.total_borrowed,
+                pool.total_liquidity + pool.total_reserves
+            ),
+            pool.utilization_rate
         );
-        
-        let res = execute::repay(
-            deps.as_mut(),
+        assert!(pool.utilization_rate > Decimal::percent(50));
+        assert_eq!(PoolUtilization::Medium, pool.utilization);
+    }
+
+    #[test]
+    fn test_unstake_periods_are_capped() {
+        let mut deps = setup();
+        let update = |deps: DepsMut, cooldown: u64, unstake_window: u64| {
+            execute::update_config(
+                deps,
+                mock_env(),
+                mock_info(OWNER, &[]),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(SafetyModuleConfig {
+                    cooldown,
+                    unstake_window,
+                    ..instantiate_msg().safety_module
+                }),
+                None,
+            )
+        };
+
+        // deadlines this far out would overflow once added to the block time
+        for (cooldown, unstake_window) in [(u64::MAX, UNSTAKE_WINDOW), (COOLDOWN, u64::MAX)] {
+            let err = update(deps.as_mut(), cooldown, unstake_window).unwrap_err();
+            match err {
+                ContractError::InvalidConfig { .. } => {}
+                e => panic!("unexpected error: {:?}", e),
+            }
+        }
+        let mut msg = instantiate_msg();
+        msg.safety_module.cooldown = math::SECONDS_PER_YEAR + 1;
+        let err = instantiate(
+            mock_dependencies().as_mut(),
             mock_env(),
-            repay_info,
-            "atom".to_string(),
-            Uint128::new(200), // Withdrawing all collateral
-        ).unwrap();
+            mock_info(OWNER, &[]),
+            msg,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InvalidConfig { .. } => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
 
-        // Verify response
-        assert_eq!(5, res.attributes.len());
-        assert_eq!(("method", "repay"), res.attributes[0]);
-        assert_eq!(("repayer", ADDR1), res.attributes[1]);
-        assert_eq!(("usdc_repaid", "100"), res.attributes[2]);
-        assert_eq!(("collateral_withdrawn", "atom"), res.attributes[3]);
-        assert_eq!(("withdrawal_amount", "200"), res.attributes[4]);
+        update(
+            deps.as_mut(),
+            math::SECONDS_PER_YEAR,
+            math::SECONDS_PER_YEAR,
+        )
+        .unwrap();
+        execute::stake(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &coins(100, "usdc")),
+        )
+        .unwrap();
+        execute::request_unstake(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(ADDR2, &[]),
+            "usdc".to_string(),
+            Uint128::new(40),
+        )
+        .unwrap();
+        execute::unstake(
+            deps.as_mut(),
+            env_after(2 * math::SECONDS_PER_YEAR),
+            mock_info(ADDR2, &[]),
+            "usdc".to_string(),
+        )
+        .unwrap();
+    }
 
-        // Test repayment without sending USDC
-        let no_funds_info = mock_info(ADDR1, &[]);
-        let err = execute::repay(
+    #[test]
+    fn test_stale_peg_price() {
+        let mut deps = setup();
+        execute::update_config(
+            deps.as_mut(),
+            mock_env(),
+            mock_info(OWNER, &[]),
+            None,
+            Some(vec![BorrowAssetConfig {
+                peg: Some(PegConfig {
+                    target: Uint128::new(1_000_000),
+                    max_deviation: Decimal::percent(2),
+                    floor_debt_value: false,
+                }),
+                ..usdc(Decimal::percent(10))
+            }]),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap();
+        execute::create_account(deps.as_mut(), mock_info(ADDR1, &[])).unwrap();
+        execute::deposit_collateral(
             deps.as_mut(),
             mock_env(),
-            no_funds_info,
+            mock_info(ADDR1, &coins(100, "atom")),
+        )
+        .unwrap();
+
+        // a depeg that was last seen long ago is reported as a stale price, not as the peg
+        set_price(deps.as_mut(), "usdc", 970_000);
+        let err = execute::borrow(
+            deps.as_mut(),
+            env_after(MAX_PRICE_AGE + 1),
+            mock_info(ADDR1, &[]),
+            "usdc".to_string(),
+            Uint128::new(10),
             "atom".to_string(),
-            Uint128::new(200),
-        ).unwrap_err();
-        
+        )
+        .unwrap_err();
         match err {
-            ContractError::NoRepayment {} => {}
+            ContractError::StalePrice { denom } => assert_eq!("usdc", denom),
             e => panic!("unexpected error: {:?}", e),
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,19 @@
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::Uint128;
+
+/// One hop of a swap through a pool of the DEX
+#[cw_serde]
+pub struct SwapStep {
+    pub pool_id: u64,
+    pub denom_out: String,
+}
+
+/// Swap of the attached coin along `route`. The DEX pays the output to the sender and fails if it
+/// comes to less than `min_output`.
+#[cw_serde]
+pub enum DexExecuteMsg {
+    Swap {
+        route: Vec<SwapStep>,
+        min_output: Uint128,
+    },
+}
@@ -1,4 +1,4 @@
-use cosmwasm_std::StdError;
+use cosmwasm_std::{StdError, Uint128};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -17,7 +17,7 @@ pub enum ContractError {
     TokenNotFound {},
 
     #[error("Account already exists")]
-    AccountExists{},
+    AccountExists {},
 
     #[error("Account does not exist")]
     AccountDoesNotExist {},
@@ -33,4 +33,91 @@ pub enum ContractError {
 
     #[error("Wrong token")]
     WrongToken {},
+
+    #[error("No funds sent")]
+    NoFunds {},
+
+    #[error("No funds can be attached")]
+    NonPayable {},
+
+    #[error("Outstanding debt must be repaid first")]
+    OutstandingDebt {},
+
+    #[error("Invalid config: {reason}")]
+    InvalidConfig { reason: String },
+
+    #[error("Collateral {denom} is not supported")]
+    UnsupportedCollateral { denom: String },
+
+    #[error("{denom} cannot be borrowed")]
+    UnsupportedBorrowAsset { denom: String },
+
+    #[error("Supply cap of {cap}{denom} exceeded")]
+    SupplyCapExceeded { denom: String, cap: Uint128 },
+
+    #[error("Borrow cap of {cap}{denom} exceeded")]
+    BorrowCapExceeded { denom: String, cap: Uint128 },
+
+    #[error("Collateral {denom} is isolated and cannot be combined with other collateral")]
+    IsolatedCollateral { denom: String },
+
+    #[error("{denom} cannot be borrowed in isolation mode")]
+    NotBorrowableInIsolation { denom: String },
+
+    #[error("Isolated collateral {denom} cannot be swapped while the account has debt")]
+    IsolatedSwap { denom: String },
+
+    #[error("Debt ceiling of {denom} exceeded")]
+    DebtCeilingExceeded { denom: String },
+
+    #[error("E-mode category {category} does not exist")]
+    EModeCategoryNotFound { category: u8 },
+
+    #[error("{denom} is not part of e-mode category {category}")]
+    NotInEModeCategory { denom: String, category: u8 },
+
+    #[error("No price for {denom}")]
+    PriceNotFound { denom: String },
+
+    #[error("Price source returned an unusable price for {denom}")]
+    InvalidPrice { denom: String },
+
+    #[error("Price of {denom} is stale")]
+    StalePrice { denom: String },
+
+    #[error("{denom} is off its peg, borrowing it is paused")]
+    Depegged { denom: String },
+
+    #[error("Account is not eligible for liquidation")]
+    AccountHealthy {},
+
+    #[error("No liquidation queue is configured")]
+    NoLiquidationQueue {},
+
+    #[error("The liquidation queue only pays out in {denom}")]
+    QueueDenomMismatch { denom: String },
+
+    #[error("No DEX is configured")]
+    NoDex {},
+
+    #[error("Swap route has to trade through to {denom}")]
+    InvalidSwapRoute { denom: String },
+
+    #[error("Target LTV has to be between 0 and 100%")]
+    InvalidLeverageTarget {},
+
+    #[error("Account is already at or above the target LTV")]
+    LeverageTargetReached {},
+
+    #[error("Swap paid {received} while at least {min_output} was required")]
+    SlippageExceeded {
+        min_output: Uint128,
+        received: Uint128,
+    },
+
+    #[error("Cooldown ends at {ends_at}")]
+    CooldownNotFinished { ends_at: u64 },
+
+    #[error("Unstake request lapsed at {expired_at}")]
+    UnstakeRequestExpired { expired_at: u64 },
 }
@@ -1,6 +1,10 @@
 pub mod contract;
+pub mod dex;
 mod error;
+pub mod math;
 pub mod msg;
+pub mod oracle;
+mod simulation;
 pub mod state;
 
 pub use crate::error::ContractError;
@@ -0,0 +1,514 @@
+//! Fixed-point helpers for the lending math.
+//!
+//! Intermediate values are computed in 256 bits so multiplying two `Uint128` amounts cannot
+//! overflow. Every helper takes or documents its rounding direction, which is always chosen in
+//! the protocol's favour: amounts owed to the protocol round up, amounts paid out round down.
+
+use cosmwasm_std::{Decimal, Decimal256, DivideByZeroError, StdError, StdResult, Uint128, Uint256};
+
+pub const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+
+/// Decimals of every USD value, so assets with different decimals can be added up
+pub const VALUE_DECIMALS: u8 = 18;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    Down,
+    Up,
+}
+
+/// `a * b / c` rounded as requested
+pub fn mul_div(a: Uint128, b: Uint128, c: Uint128, rounding: Rounding) -> StdResult<Uint128> {
+    to_uint128(mul_div_256(a.into(), b.into(), c.into(), rounding)?)
+}
+
+/// `amount * factor` rounded as requested
+pub fn mul_decimal(amount: Uint128, factor: Decimal, rounding: Rounding) -> StdResult<Uint128> {
+    let factor = Decimal256::from(factor);
+    to_uint128(mul_div_256(
+        amount.into(),
+        factor.atomics(),
+        Decimal256::one().atomics(),
+        rounding,
+    )?)
+}
+
+/// Shares minted for depositing `amount` into a pool worth `total_amount`.
+/// The first deposit mints shares one to one.
+pub fn amount_to_shares(
+    amount: Uint128,
+    total_shares: Uint128,
+    total_amount: Uint128,
+    rounding: Rounding,
+) -> StdResult<Uint128> {
+    if total_shares.is_zero() {
+        return Ok(amount);
+    }
+    if total_amount.is_zero() {
+        // outstanding shares with nothing behind them would capture part of the new deposit
+        return Err(StdError::generic_err("cannot mint shares of an empty pool"));
+    }
+    mul_div(amount, total_shares, total_amount, rounding)
+}
+
+/// Amount of the pool that `shares` are worth
+pub fn shares_to_amount(
+    shares: Uint128,
+    total_shares: Uint128,
+    total_amount: Uint128,
+    rounding: Rounding,
+) -> StdResult<Uint128> {
+    if total_shares.is_zero() {
+        return Ok(Uint128::zero());
+    }
+    mul_div(shares, total_amount, total_shares, rounding)
+}
+
+/// Borrow index after `elapsed` seconds at the annual `rate`, rounded up.
+/// Interest compounds every time the index is updated.
+pub fn compound_index(index: Decimal, rate: Decimal, elapsed: u64) -> StdResult<Decimal> {
+    let one = Decimal256::one().atomics();
+    let period_rate = mul_div_256(
+        Decimal256::from(rate).atomics(),
+        Uint256::from(elapsed),
+        Uint256::from(SECONDS_PER_YEAR),
+        Rounding::Up,
+    )?;
+    let growth = one.checked_add(period_rate)?;
+    let index = mul_div_256(Decimal256::from(index).atomics(), growth, one, Rounding::Up)?;
+    Decimal::try_from(Decimal256::new(index))
+        .map_err(|_| StdError::generic_err("borrow index overflow"))
+}
+
+/// Debt recorded at `from_index` grown to `to_index`, rounded up
+pub fn debt_at_index(
+    principal: Uint128,
+    from_index: Decimal,
+    to_index: Decimal,
+) -> StdResult<Uint128> {
+    to_uint128(mul_div_256(
+        principal.into(),
+        Decimal256::from(to_index).atomics(),
+        Decimal256::from(from_index).atomics(),
+        Rounding::Up,
+    )?)
+}
+
+/// USD value of `amount` base units of a token with `decimals`, at `price` per whole token
+/// scaled by `10^price_decimals`. The result has [`VALUE_DECIMALS`] decimals.
+pub fn usd_value(
+    amount: Uint128,
+    decimals: u8,
+    price: Uint128,
+    price_decimals: u8,
+    rounding: Rounding,
+) -> StdResult<Uint128> {
+    let value = Uint256::from(amount).checked_mul(price.into())?;
+    to_uint128(mul_div_256(
+        value,
+        pow10(VALUE_DECIMALS),
+        pow10(decimals + price_decimals),
+        rounding,
+    )?)
+}
+
+/// Base units of a token with `decimals` worth `value`, the inverse of [`usd_value`]
+pub fn amount_for_value(
+    value: Uint128,
+    decimals: u8,
+    price: Uint128,
+    price_decimals: u8,
+    rounding: Rounding,
+) -> StdResult<Uint128> {
+    let price = Uint256::from(price).checked_mul(pow10(VALUE_DECIMALS))?;
+    to_uint128(mul_div_256(
+        value.into(),
+        pow10(decimals + price_decimals),
+        price,
+        rounding,
+    )?)
+}
+
+/// Base units of the quote token one base unit of a token is worth, given both USD prices at the
+/// same scale, rounded down
+pub fn unit_price(
+    price: Uint128,
+    decimals: u8,
+    quote_price: Uint128,
+    quote_decimals: u8,
+) -> StdResult<Decimal> {
+    let value = Uint256::from(price).checked_mul(pow10(quote_decimals))?;
+    let quote_value = Uint256::from(quote_price).checked_mul(pow10(decimals))?;
+    let atomics = mul_div_256(
+        value,
+        Decimal256::one().atomics(),
+        quote_value,
+        Rounding::Down,
+    )?;
+    Ok(Decimal::new(to_uint128(atomics)?))
+}
+
+/// USD price of a whole token from the quote base units one of its base units is worth and the
+/// quote's USD price, rounded down. This is the inverse of [`unit_price`].
+pub fn price_from_unit_price(
+    unit_price: Decimal,
+    decimals: u8,
+    quote_price: Uint128,
+    quote_decimals: u8,
+) -> StdResult<Uint128> {
+    let value = Uint256::from(quote_price).checked_mul(pow10(decimals))?;
+    let scale = Uint256::from(Decimal::one().atomics()).checked_mul(pow10(quote_decimals))?;
+    to_uint128(mul_div_256(
+        value,
+        unit_price.atomics().into(),
+        scale,
+        Rounding::Down,
+    )?)
+}
+
+/// Time-weighted average of `(time, price)` observations in time order, each price holding
+/// until the next observation, over the window from `start` to `end`, rounded down. Falls back
+/// to the latest price when none of them held for any time inside the window.
+pub fn time_weighted_average(
+    observations: &[(u64, Uint128)],
+    start: u64,
+    end: u64,
+) -> StdResult<Option<Uint128>> {
+    let mut weighted = Uint256::zero();
+    let mut covered = 0u64;
+    for (i, (time, price)) in observations.iter().enumerate() {
+        let until = observations
+            .get(i + 1)
+            .map_or(end, |(next, _)| *next)
+            .min(end);
+        let from = (*time).max(start);
+        if until > from {
+            weighted = weighted
+                .checked_add(Uint256::from(*price).checked_mul(Uint256::from(until - from))?)?;
+            covered += until - from;
+        }
+    }
+    if covered == 0 {
+        return Ok(observations.last().map(|(_, price)| *price));
+    }
+    Ok(Some(to_uint128(weighted / Uint256::from(covered))?))
+}
+
+/// Middle of the values, or the mean of the two middle ones rounded down for an even count
+pub fn median(mut values: Vec<Uint128>) -> Option<Uint128> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort();
+    let middle = values.len() / 2;
+    if values.len() % 2 == 1 {
+        return Some(values[middle]);
+    }
+    let (low, high) = (values[middle - 1], values[middle]);
+    Some(low + (high - low) / Uint128::new(2))
+}
+
+/// Collateral given to a liquidator repaying `repay_value`, including the bonus, rounded down
+pub fn liquidation_collateral(
+    repay_value: Uint128,
+    bonus: Decimal,
+    price: Uint128,
+    price_decimals: u8,
+    decimals: u8,
+) -> StdResult<Uint128> {
+    let with_bonus = to_uint128(mul_div_256(
+        repay_value.into(),
+        bonus_factor(bonus),
+        Decimal256::one().atomics(),
+        Rounding::Down,
+    )?)?;
+    amount_for_value(with_bonus, decimals, price, price_decimals, Rounding::Down)
+}
+
+/// Value a liquidator has to repay to receive `collateral` including the bonus, rounded up.
+/// This is the inverse of [`liquidation_collateral`], used when the seizure is capped.
+pub fn liquidation_repay(
+    collateral: Uint128,
+    bonus: Decimal,
+    price: Uint128,
+    price_decimals: u8,
+    decimals: u8,
+) -> StdResult<Uint128> {
+    let value = usd_value(collateral, decimals, price, price_decimals, Rounding::Up)?;
+    to_uint128(mul_div_256(
+        value.into(),
+        Decimal256::one().atomics(),
+        bonus_factor(bonus),
+        Rounding::Up,
+    )?)
+}
+
+fn bonus_factor(bonus: Decimal) -> Uint256 {
+    Decimal256::from(Decimal::one() + bonus).atomics()
+}
+
+fn pow10(decimals: u8) -> Uint256 {
+    Uint256::from(10u128).pow(decimals.into())
+}
+
+fn mul_div_256(a: Uint256, b: Uint256, c: Uint256, rounding: Rounding) -> StdResult<Uint256> {
+    if c.is_zero() {
+        return Err(DivideByZeroError::new(a).into());
+    }
+    let product = a.checked_mul(b)?;
+    let quotient = product / c;
+    if rounding == Rounding::Up && !(product % c).is_zero() {
+        return Ok(quotient + Uint256::one());
+    }
+    Ok(quotient)
+}
+
+fn to_uint128(value: Uint256) -> StdResult<Uint128> {
+    Ok(Uint128::try_from(value)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unit_price() {
+        // an 18 decimal token at $0.50 against a 6 decimal token at $1
+        assert_eq!(
+            Decimal::from_ratio(1u128, 2_000_000_000_000u128),
+            unit_price(Uint128::new(500_000), 18, Uint128::new(1_000_000), 6).unwrap()
+        );
+        assert_eq!(
+            Decimal::percent(90),
+            unit_price(Uint128::new(900_000), 6, Uint128::new(1_000_000), 6).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_price_from_unit_price() {
+        // a 6 decimal token worth 2 units of a 6 decimal token at $1
+        assert_eq!(
+            Uint128::new(2_000_000),
+            price_from_unit_price(Decimal::percent(200), 6, Uint128::new(1_000_000), 6).unwrap()
+        );
+        // an 18 decimal token at $0.50 round trips through unit_price
+        let unit = unit_price(Uint128::new(500_000), 18, Uint128::new(1_000_000), 6).unwrap();
+        assert_eq!(
+            Uint128::new(500_000),
+            price_from_unit_price(unit, 18, Uint128::new(1_000_000), 6).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_time_weighted_average() {
+        let observations = [
+            (0, Uint128::new(100)),
+            (100, Uint128::new(200)),
+            (150, Uint128::new(400)),
+        ];
+        assert_eq!(None, time_weighted_average(&[], 0, 100).unwrap());
+        // 100 for 50s, 200 for 50s, 400 for 50s
+        assert_eq!(
+            Some(Uint128::new(233)),
+            time_weighted_average(&observations, 50, 200).unwrap()
+        );
+        // only the time the observations cover counts
+        assert_eq!(
+            Some(Uint128::new(100)),
+            time_weighted_average(&observations[..1], 0, 1000).unwrap()
+        );
+        assert_eq!(
+            Some(Uint128::new(300)),
+            time_weighted_average(&observations[1..], 0, 200).unwrap()
+        );
+        // an observation made at the end of the window has not held yet
+        assert_eq!(
+            Some(Uint128::new(100)),
+            time_weighted_average(&observations, 0, 100).unwrap()
+        );
+        assert_eq!(
+            Some(Uint128::new(400)),
+            time_weighted_average(&observations, 150, 150).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_median() {
+        let values = |values: &[u128]| values.iter().map(|v| Uint128::new(*v)).collect();
+        assert_eq!(None, median(vec![]));
+        assert_eq!(Some(Uint128::new(7)), median(values(&[7])));
+        assert_eq!(Some(Uint128::new(5)), median(values(&[9, 1, 5])));
+        // even counts take the mean of the middle two, rounded down
+        assert_eq!(Some(Uint128::new(4)), median(values(&[1, 4, 5, 100])));
+        assert_eq!(
+            Some(Uint128::MAX - Uint128::one()),
+            median(vec![Uint128::MAX, Uint128::MAX - Uint128::new(2)])
+        );
+    }
+
+    #[test]
+    fn test_mul_div_rounding() {
+        let (a, b, c) = (Uint128::new(10), Uint128::new(10), Uint128::new(3));
+        assert_eq!(Uint128::new(33), mul_div(a, b, c, Rounding::Down).unwrap());
+        assert_eq!(Uint128::new(34), mul_div(a, b, c, Rounding::Up).unwrap());
+
+        // exact results are not bumped
+        let c = Uint128::new(4);
+        assert_eq!(Uint128::new(25), mul_div(a, b, c, Rounding::Up).unwrap());
+
+        // the intermediate product does not overflow
+        let max = Uint128::MAX;
+        assert_eq!(max, mul_div(max, max, max, Rounding::Down).unwrap());
+
+        assert!(mul_div(a, b, Uint128::zero(), Rounding::Down).is_err());
+        assert!(mul_div(max, max, Uint128::one(), Rounding::Down).is_err());
+    }
+
+    #[test]
+    fn test_mul_decimal_tiny_amounts() {
+        let one = Uint128::one();
+        assert_eq!(
+            Uint128::zero(),
+            mul_decimal(one, Decimal::percent(75), Rounding::Down).unwrap()
+        );
+        assert_eq!(
+            one,
+            mul_decimal(one, Decimal::percent(75), Rounding::Up).unwrap()
+        );
+        assert_eq!(
+            Uint128::zero(),
+            mul_decimal(Uint128::zero(), Decimal::percent(75), Rounding::Up).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_shares_with_zero_supply() {
+        let amount = Uint128::new(1_000);
+        let zero = Uint128::zero();
+
+        // first deposit is one to one
+        assert_eq!(
+            amount,
+            amount_to_shares(amount, zero, zero, Rounding::Down).unwrap()
+        );
+        assert_eq!(
+            zero,
+            shares_to_amount(amount, zero, zero, Rounding::Down).unwrap()
+        );
+
+        // shares left over in a pool that lost everything cannot dilute a new deposit
+        assert!(amount_to_shares(amount, amount, zero, Rounding::Down).is_err());
+    }
+
+    #[test]
+    fn test_share_conversion_favours_pool() {
+        // 300 shares backed by 400 tokens
+        let (shares, value) = (Uint128::new(300), Uint128::new(400));
+
+        // depositing 1 token is worth 0.75 shares, minted as 0
+        let minted = amount_to_shares(Uint128::one(), shares, value, Rounding::Down).unwrap();
+        assert_eq!(Uint128::zero(), minted);
+
+        // 1 share is worth 1.33 tokens, paid out as 1
+        let paid = shares_to_amount(Uint128::one(), shares, value, Rounding::Down).unwrap();
+        assert_eq!(Uint128::one(), paid);
+
+        // a round trip never returns more than was deposited
+        let deposit = Uint128::new(1_234_567);
+        let minted = amount_to_shares(deposit, shares, value, Rounding::Down).unwrap();
+        let redeemed =
+            shares_to_amount(minted, shares + minted, value + deposit, Rounding::Down).unwrap();
+        assert!(redeemed <= deposit);
+    }
+
+    #[test]
+    fn test_compound_index() {
+        let index = Decimal::one();
+        let rate = Decimal::percent(10);
+
+        assert_eq!(index, compound_index(index, rate, 0).unwrap());
+        assert_eq!(
+            Decimal::percent(110),
+            compound_index(index, rate, SECONDS_PER_YEAR).unwrap()
+        );
+
+        // a single second still moves the index, rounded up
+        let next = compound_index(index, rate, 1).unwrap();
+        assert!(next > index);
+        assert_eq!(
+            Decimal::from_atomics(1_000_000_003_170_979_199u128, 18).unwrap(),
+            next
+        );
+
+        // two half years compound above a single full year
+        let half = SECONDS_PER_YEAR / 2;
+        let twice = compound_index(compound_index(index, rate, half).unwrap(), rate, half).unwrap();
+        assert_eq!(Decimal::from_ratio(11025u128, 10000u128), twice);
+    }
+
+    #[test]
+    fn test_debt_at_index_rounds_up() {
+        let from = Decimal::one();
+        let to = Decimal::from_ratio(3u128, 2u128);
+        assert_eq!(
+            Uint128::new(2),
+            debt_at_index(Uint128::one(), from, to).unwrap()
+        );
+        assert_eq!(
+            Uint128::new(150),
+            debt_at_index(Uint128::new(100), from, to).unwrap()
+        );
+        assert_eq!(
+            Uint128::zero(),
+            debt_at_index(Uint128::zero(), from, to).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_usd_value_across_decimals() {
+        // one whole token at $0.333333 is worth the same whatever its decimals
+        let price = Uint128::new(333_333);
+        let expected = Uint128::new(333_333_000_000_000_000);
+        let six = usd_value(Uint128::new(1_000_000), 6, price, 6, Rounding::Down).unwrap();
+        assert_eq!(expected, six);
+        let eighteen = usd_value(Uint128::new(10u128.pow(18)), 18, price, 6, Rounding::Down);
+        assert_eq!(expected, eighteen.unwrap());
+
+        // a single wei of an 18 decimal token is worth less than the smallest value
+        let wei = usd_value(Uint128::one(), 18, price, 6, Rounding::Down).unwrap();
+        assert_eq!(Uint128::zero(), wei);
+        let wei = usd_value(Uint128::one(), 18, price, 6, Rounding::Up).unwrap();
+        assert_eq!(Uint128::one(), wei);
+
+        // converting back lands on the original amount
+        let amount = amount_for_value(expected, 18, price, 6, Rounding::Down).unwrap();
+        assert_eq!(Uint128::new(10u128.pow(18)), amount);
+        let amount = amount_for_value(expected, 6, price, 6, Rounding::Down).unwrap();
+        assert_eq!(Uint128::new(1_000_000), amount);
+    }
+
+    #[test]
+    fn test_liquidation_amounts() {
+        let bonus = Decimal::percent(5);
+        // collateral with 6 decimals at $2
+        let price = Uint128::new(2_000_000);
+        let dollars = |amount: u128| Uint128::new(amount * 10u128.pow(18));
+
+        // repaying $100 earns $105 worth of collateral
+        let seized = liquidation_collateral(dollars(100), bonus, price, 6, 6).unwrap();
+        assert_eq!(Uint128::new(52_500_000), seized);
+
+        // capping the seizure at 52 tokens requires repaying at least $99.05
+        let repay = liquidation_repay(Uint128::new(52_000_000), bonus, price, 6, 6).unwrap();
+        assert_eq!(Uint128::new(99_047_619_047_619_047_620), repay);
+
+        // the same value of an 18 decimal token
+        let seized = liquidation_collateral(dollars(100), bonus, price, 6, 18).unwrap();
+        assert_eq!(Uint128::new(52_500_000_000_000_000_000), seized);
+
+        // tiny repayments cannot seize anything
+        let seized = liquidation_collateral(Uint128::one(), bonus, price, 6, 6).unwrap();
+        assert_eq!(Uint128::zero(), seized);
+        assert!(liquidation_collateral(Uint128::one(), bonus, Uint128::zero(), 6, 6).is_err());
+    }
+}
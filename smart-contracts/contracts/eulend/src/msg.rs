@@ -1,28 +1,346 @@
-use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::{Uint128, Binary, Coin};
-use std::time::Duration;
+use crate::dex::SwapStep;
+use crate::state::{
+    BadDebt, CollateralAsset, DexConfig, EModeCategory, FeeConfig, LiquidationConfig, OracleConfig,
+    PegConfig, Price, SafetyModuleConfig, UtilizationThresholds,
+};
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{Addr, Attribute, Coin, Decimal, Uint128};
 
 #[cw_serde]
-pub struct InstantiateMsg {}
+pub struct InstantiateMsg {
+    pub owner: String,
+    /// Every borrowable asset gets a pool of its own
+    pub borrow_assets: Vec<BorrowAssetConfig>,
+    pub collateral_assets: Vec<CollateralAsset>,
+    pub oracle: OracleConfig,
+    pub fees: FeeConfig,
+    pub utilization_thresholds: UtilizationThresholds,
+    pub liquidation: LiquidationConfig,
+    pub safety_module: SafetyModuleConfig,
+    pub dex: Option<DexConfig>,
+}
 
 #[cw_serde]
+#[allow(clippy::large_enum_variant)]
 pub enum ExecuteMsg {
     CreateAccount {},
-    Borrow {borrow_amount: Uint128, collateral_denom: String},
-    Repay {withdraw_denom: String, withdraw_amount: Uint128},
+    Borrow {
+        borrow_denom: String,
+        borrow_amount: Uint128,
+        collateral_denom: String,
+    },
+    /// Pays down the debt in the attached coin's denom, refunding anything above what is owed
+    Repay {},
+    /// Settles the full debt in the attached coin's denom including interest accrued up to the
+    /// current block
+    RepayAll {},
+    /// Pays down another account's debt with the attached coin without touching its collateral
+    RepayFor {
+        borrower: String,
+    },
     DeleteAccount {},
+    /// Supplies the attached coin to the pool of its denom
     ProvideLiquidity {},
-    ChangePoolUtilization {id : Uint128}
+    /// Adds the attached coin to the insurance fund of its pool, which covers bad debt once the
+    /// pool's reserves run out
+    FundInsurance {},
+    /// Stakes the attached coin in the safety module of its pool, earning part of the pool's
+    /// reserves and covering its bad debt
+    Stake {},
+    /// Starts the cooldown after which `shares` of the sender's stake can be unstaked, replacing
+    /// any earlier request
+    RequestUnstake {
+        pool_id: String,
+        shares: Uint128,
+    },
+    /// Pays out the shares requested once their cooldown is over, as long as the unstake window
+    /// that follows it has not closed
+    Unstake {
+        pool_id: String,
+    },
+    /// Adds every attached coin to the sender's collateral
+    DepositCollateral {},
+    /// Returns collateral to the sender as long as the account stays within its borrow limit
+    WithdrawCollateral {
+        denom: String,
+        amount: Uint128,
+    },
+    /// Owner only. Borrow and collateral assets are added or replaced by denom, never removed
+    UpdateConfig {
+        owner: Option<String>,
+        borrow_assets: Option<Vec<BorrowAssetConfig>>,
+        /// Added or replaced by denom, keeping their decimals and, while deposited, whether they
+        /// carry a debt ceiling
+        collateral_assets: Option<Vec<CollateralAsset>>,
+        /// Added or replaced by id
+        emode_categories: Option<Vec<EModeCategory>>,
+        oracle: Option<OracleConfig>,
+        fees: Option<FeeConfig>,
+        utilization_thresholds: Option<UtilizationThresholds>,
+        liquidation: Option<LiquidationConfig>,
+        safety_module: Option<SafetyModuleConfig>,
+        dex: Option<DexConfig>,
+    },
+    /// Repays part of an unhealthy account's debt in the attached coin's denom in exchange for
+    /// its collateral at a discount, which grows from zero the longer the account stays unhealthy
+    /// and the deeper it is under water
+    Liquidate {
+        borrower: String,
+        collateral_denom: String,
+    },
+    /// Starts the liquidation auction of an account the first time it is seen unhealthy, or ends
+    /// it once the account is healthy again. An auction not refreshed or liquidated within its
+    /// duration starts over, as the account may have recovered in between.
+    RefreshLiquidation {
+        borrower: String,
+    },
+    /// Sells enough of an unhealthy account's collateral into the liquidation queue to repay
+    /// `amount` of its debt in `debt_denom`, plus the bonus. The queue's proceeds repay the debt
+    /// and anything above it goes back to the borrower.
+    LiquidateWithQueue {
+        borrower: String,
+        collateral_denom: String,
+        debt_denom: String,
+        amount: Uint128,
+    },
+    /// Values the sender's positions under an e-mode category, or the standard parameters if
+    /// none. Every collateral and debt of the account has to belong to the category.
+    SetEMode {
+        category: Option<u8>,
+    },
+    /// Whitelisted feeders only. `price` is the USD price of one whole token scaled by the
+    /// oracle's price decimals. The median of the fresh submissions becomes the price once they
+    /// reach the quorum, unless it is too far from the current one and quarantined.
+    UpdatePrice {
+        token: String,
+        price: Uint128,
+    },
+    /// Borrows `borrow_denom` and swaps it along `swap_route` for `collateral_denom`, which is
+    /// deposited so the account's debt reaches `target_ltv` of its collateral value. The swap may
+    /// pay no less than its value at oracle prices minus the DEX's max slippage.
+    Leverage {
+        collateral_denom: String,
+        borrow_denom: String,
+        target_ltv: Decimal,
+        swap_route: Vec<SwapStep>,
+    },
+    /// Sells `amount` of the sender's `from_denom` collateral along `dex_route` and deposits the
+    /// proceeds, at least `min_out`, as `to_denom` collateral. The account has to stay within its
+    /// borrow limit.
+    SwapCollateral {
+        from_denom: String,
+        to_denom: String,
+        amount: Uint128,
+        min_out: Uint128,
+        dex_route: Vec<SwapStep>,
+    },
 }
 
 #[cw_serde]
 pub enum QueryMsg {
-    GetAccount {address: String},
+    GetAccount {
+        address: String,
+    },
+    GetCollaterals {
+        address: String,
+    },
+    GetConfig {},
+    GetPool {
+        id: String,
+    },
+    /// Usage and caps of a collateral or borrow asset
+    GetMarket {
+        denom: String,
+    },
+    GetPrice {
+        denom: String,
+    },
+    GetFeederPrices {
+        denom: String,
+    },
+    GetSafetyModule {
+        pool_id: String,
+    },
+    GetStaker {
+        address: String,
+        pool_id: String,
+    },
+    /// Every bad debt the pool has written off, oldest first
+    GetBadDebt {
+        pool_id: String,
+    },
+    /// Where the account's liquidation auction stands in the current block
+    GetLiquidation {
+        address: String,
+    },
+    /// Most the address can borrow from the pool without exceeding its borrow limit, the pool's
+    /// cash or any cap
+    MaxBorrowable {
+        address: String,
+        pool_id: String,
+    },
+    /// Most of the collateral the address can withdraw while staying within its borrow limit
+    MaxWithdrawable {
+        address: String,
+        denom: String,
+    },
+    /// Outcome of the address borrowing in the current block, attaching `collateral` if set
+    SimulateBorrow {
+        address: String,
+        borrow_denom: String,
+        borrow_amount: Uint128,
+        collateral: Option<Coin>,
+    },
+    /// Outcome of the address paying down its own debt with `amount`
+    SimulateRepay {
+        address: String,
+        amount: Coin,
+    },
+    SimulateWithdrawCollateral {
+        address: String,
+        denom: String,
+        amount: Uint128,
+    },
+    /// Outcome of `liquidator` repaying `amount` of the borrower's debt for its `collateral_denom`
+    SimulateLiquidation {
+        liquidator: String,
+        borrower: String,
+        collateral_denom: String,
+        amount: Coin,
+    },
+}
+
+#[cw_serde]
+pub struct BorrowAssetConfig {
+    pub denom: String,
+    pub decimals: u8,
+    /// Annual interest rate charged by the asset's pool
+    pub borrow_rate: Decimal,
+    pub supply_cap: Option<Uint128>,
+    pub borrow_cap: Option<Uint128>,
+    /// Whether accounts backed by isolated collateral may borrow it
+    pub isolation_borrowable: bool,
+    /// Price a stable asset is meant to hold, pausing borrows of it when it strays
+    pub peg: Option<PegConfig>,
+}
+
+#[cw_serde]
+pub struct AccountResponse {
+    pub address: String,
+    pub emode_category: Option<u8>,
+    /// Debt in every borrowed asset including interest accrued up to the current block
+    pub debts: Vec<Coin>,
+    /// USD values with 18 decimals
+    pub debt_value: Uint128,
+    pub borrow_limit: Uint128,
+    pub liquidation_limit: Uint128,
+}
+
+#[cw_serde]
+pub struct LiquidationResponse {
+    /// Block time the account was first seen unhealthy, if it still is
+    pub started_at: Option<u64>,
+    /// Share of each collateral's liquidation bonus currently given to liquidators
+    pub progress: Decimal,
+}
+
+#[cw_serde]
+pub struct PriceResponse {
+    pub denom: String,
+    /// Latest accepted price
+    pub price: Uint128,
+    /// Time-weighted average over the oracle's window, which positions are valued at
+    pub twap: Uint128,
+    pub updated_at: u64,
+    /// Older than the oracle's max age, so borrowing and liquidations against it are paused
+    pub stale: bool,
+    pub quarantined: Option<Price>,
+}
+
+#[cw_serde]
+pub struct MaxAmountResponse {
+    pub denom: String,
+    pub amount: Uint128,
 }
 
+#[cw_serde]
+pub struct SimulationResponse {
+    /// Error the operation would fail with, none if it would go through
+    pub error: Option<String>,
+    /// Liquidation limit over debt value of the account afterwards, none without debt
+    pub health_factor: Option<Decimal>,
+    /// Coins the sender would be sent, such as borrowed funds, refunds or seized collateral
+    pub received: Vec<Coin>,
+    /// Interest the operation would settle on the account's debts, the protocol's only charge
+    pub fees: Vec<Coin>,
+    /// Collateral a liquidator would receive beyond the value of the debt repaid, set for
+    /// liquidations only
+    pub liquidation_bonus: Option<Coin>,
+    /// Attributes the operation would emit, including repaid amounts and liquidation bonuses
+    pub attributes: Vec<Attribute>,
+}
 
+#[cw_serde]
+pub struct FeederPrice {
+    pub feeder: Addr,
+    pub price: Option<Price>,
+    /// Within the oracle's max age, so it counts towards the quorum
+    pub fresh: bool,
+}
 
+#[cw_serde]
+pub struct FeederPricesResponse {
+    pub denom: String,
+    pub quorum: u32,
+    pub prices: Vec<FeederPrice>,
+}
 
+#[cw_serde]
+pub struct StakerResponse {
+    pub address: String,
+    pub pool_id: String,
+    pub shares: Uint128,
+    /// Tokens the shares are worth, including the stakers' share of reserves so far
+    pub value: Uint128,
+    pub unstaking: Uint128,
+    pub cooldown_end: Option<u64>,
+}
 
+#[cw_serde]
+pub struct BadDebtResponse {
+    pub pool_id: String,
+    pub total: Uint128,
+    pub history: Vec<BadDebt>,
+}
 
+#[cw_serde]
+pub struct MarketResponse {
+    pub denom: String,
+    /// Deposited as collateral across all accounts
+    pub total_collateral: Uint128,
+    pub collateral_cap: Option<Uint128>,
+    /// Supplied to the pool by liquidity providers, including interest earned
+    pub total_supplied: Uint128,
+    pub supply_cap: Option<Uint128>,
+    pub total_borrowed: Uint128,
+    pub borrow_cap: Option<Uint128>,
+    /// Set aside to cover the pool's bad debt
+    pub insurance_fund: Uint128,
+    /// Debt backed by the asset as isolated collateral, in USD with 18 decimals
+    pub isolated_debt: Uint128,
+    pub debt_ceiling: Option<Uint128>,
+}
 
+#[cw_serde]
+pub struct ConfigResponse {
+    pub owner: Addr,
+    pub borrow_assets: Vec<BorrowAssetConfig>,
+    pub collateral_assets: Vec<CollateralAsset>,
+    pub emode_categories: Vec<EModeCategory>,
+    pub oracle: OracleConfig,
+    pub fees: FeeConfig,
+    pub utilization_thresholds: UtilizationThresholds,
+    pub liquidation: LiquidationConfig,
+    pub safety_module: SafetyModuleConfig,
+    pub dex: Option<DexConfig>,
+}
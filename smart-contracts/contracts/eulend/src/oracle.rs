@@ -0,0 +1,159 @@
+use crate::error::ContractError;
+use crate::state::Price;
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{Addr, Decimal, QuerierWrapper, Uint128, Uint256};
+
+// Band reference rates carry 18 decimals
+const BAND_RATE_DECIMALS: u32 = 18;
+
+/// Query of a Pyth-style price feed contract
+#[cw_serde]
+pub enum PythQueryMsg {
+    PriceFeed { id: String },
+}
+
+#[cw_serde]
+pub struct PriceFeedResponse {
+    pub price_feed: PythPriceFeed,
+}
+
+#[cw_serde]
+pub struct PythPriceFeed {
+    pub id: String,
+    pub price: PythPrice,
+}
+
+/// `price * 10^expo` USD, published at `publish_time` in seconds
+#[cw_serde]
+pub struct PythPrice {
+    pub price: i64,
+    pub conf: u64,
+    pub expo: i32,
+    pub publish_time: i64,
+}
+
+/// Query of a Band-style standard reference contract
+#[cw_serde]
+pub enum BandQueryMsg {
+    GetReferenceData {
+        base_symbol: String,
+        quote_symbol: String,
+    },
+}
+
+/// `rate` is the quote per base scaled by 10^18
+#[cw_serde]
+pub struct ReferenceData {
+    pub rate: Uint256,
+    pub last_updated_base: u64,
+    pub last_updated_quote: u64,
+}
+
+/// Query of a DEX pool for its time-weighted average price over the last `window` seconds
+#[cw_serde]
+pub enum DexQueryMsg {
+    Twap {
+        base_denom: String,
+        quote_denom: String,
+        window: u64,
+    },
+}
+
+/// `price` is base units of the quote asset per base unit of the base asset
+#[cw_serde]
+pub struct TwapResponse {
+    pub price: Decimal,
+}
+
+// Pyth price converted to the oracle's price decimals, rounded down
+pub fn pyth_price(
+    querier: QuerierWrapper,
+    contract: &Addr,
+    id: &str,
+    denom: &str,
+    price_decimals: u8,
+) -> Result<Price, ContractError> {
+    let response: PriceFeedResponse =
+        querier.query_wasm_smart(contract, &PythQueryMsg::PriceFeed { id: id.to_string() })?;
+    let price = response.price_feed.price;
+    if price.price <= 0 || price.publish_time < 0 {
+        return Err(invalid_price(denom));
+    }
+    let amount = Uint128::from(price.price as u64);
+    let exponent = price_decimals as i32 + price.expo;
+    let scale = Uint128::new(10)
+        .checked_pow(exponent.unsigned_abs())
+        .map_err(|_| invalid_price(denom))?;
+    let scaled = if exponent >= 0 {
+        amount
+            .checked_mul(scale)
+            .map_err(|_| invalid_price(denom))?
+    } else {
+        amount / scale
+    };
+    if scaled.is_zero() {
+        return Err(invalid_price(denom));
+    }
+    Ok(Price {
+        price: scaled,
+        updated_at: price.publish_time as u64,
+    })
+}
+
+// Band USD rate converted to the oracle's price decimals, rounded down. It is as old as the
+// older of its two legs.
+pub fn band_price(
+    querier: QuerierWrapper,
+    contract: &Addr,
+    symbol: &str,
+    denom: &str,
+    price_decimals: u8,
+) -> Result<Price, ContractError> {
+    let data: ReferenceData = querier.query_wasm_smart(
+        contract,
+        &BandQueryMsg::GetReferenceData {
+            base_symbol: symbol.to_string(),
+            quote_symbol: "USD".to_string(),
+        },
+    )?;
+    let scaled = data
+        .rate
+        .checked_mul(Uint256::from(10u128).pow(price_decimals.into()))
+        .map_err(|_| invalid_price(denom))?
+        / Uint256::from(10u128).pow(BAND_RATE_DECIMALS);
+    let price = Uint128::try_from(scaled).map_err(|_| invalid_price(denom))?;
+    if price.is_zero() {
+        return Err(invalid_price(denom));
+    }
+    Ok(Price {
+        price,
+        updated_at: data.last_updated_base.min(data.last_updated_quote),
+    })
+}
+
+pub fn dex_twap(
+    querier: QuerierWrapper,
+    contract: &Addr,
+    denom: &str,
+    quote_denom: &str,
+    window: u64,
+) -> Result<Decimal, ContractError> {
+    let response: TwapResponse = querier.query_wasm_smart(
+        contract,
+        &DexQueryMsg::Twap {
+            base_denom: denom.to_string(),
+            quote_denom: quote_denom.to_string(),
+            window,
+        },
+    )?;
+    if response.price.is_zero() {
+        return Err(invalid_price(denom));
+    }
+    Ok(response.price)
+}
+
+fn invalid_price(denom: &str) -> ContractError {
+    ContractError::InvalidPrice {
+        denom: denom.to_string(),
+    }
+}
@@ -0,0 +1,94 @@
+use cosmwasm_std::{Order, Record, Storage};
+use std::collections::BTreeMap;
+
+/// Storage that keeps writes to itself on top of a read-only base, so execute handlers can be
+/// run from a query without changing anything
+pub struct SimulatedStorage<'a> {
+    base: &'a dyn Storage,
+    // written values by key, `None` for removed keys
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> SimulatedStorage<'a> {
+    pub fn new(base: &'a dyn Storage) -> Self {
+        SimulatedStorage {
+            base,
+            writes: BTreeMap::new(),
+        }
+    }
+}
+
+impl Storage for SimulatedStorage<'_> {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        match self.writes.get(key) {
+            Some(value) => value.clone(),
+            None => self.base.get(key),
+        }
+    }
+
+    fn range<'b>(
+        &'b self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = Record> + 'b> {
+        let mut records: BTreeMap<Vec<u8>, Vec<u8>> =
+            self.base.range(start, end, Order::Ascending).collect();
+        let in_range = |key: &[u8]| {
+            !matches!(start, Some(start) if key < start) && !matches!(end, Some(end) if key >= end)
+        };
+        for (key, value) in self.writes.iter().filter(|(key, _)| in_range(key)) {
+            match value {
+                Some(value) => records.insert(key.clone(), value.clone()),
+                None => records.remove(key),
+            };
+        }
+        match order {
+            Order::Ascending => Box::new(records.into_iter()),
+            Order::Descending => Box::new(records.into_iter().rev()),
+        }
+    }
+
+    fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.writes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.writes.insert(key.to_vec(), None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::MemoryStorage;
+
+    #[test]
+    fn test_writes_overlay_base() {
+        let mut base = MemoryStorage::new();
+        base.set(b"a", b"1");
+        base.set(b"b", b"2");
+        base.set(b"c", b"3");
+
+        let mut storage = SimulatedStorage::new(&base);
+        storage.set(b"b", b"20");
+        storage.remove(b"c");
+        storage.set(b"d", b"4");
+        assert_eq!(Some(b"20".to_vec()), storage.get(b"b"));
+        assert_eq!(None, storage.get(b"c"));
+
+        let records = |order| storage.range(Some(b"b"), None, order).collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (b"b".to_vec(), b"20".to_vec()),
+                (b"d".to_vec(), b"4".to_vec())
+            ],
+            records(Order::Ascending)
+        );
+        assert_eq!(b"d".to_vec(), records(Order::Descending)[0].0);
+
+        // the base is left untouched
+        assert_eq!(Some(b"3".to_vec()), base.get(b"c"));
+        assert_eq!(None, base.get(b"d"));
+    }
+}
@@ -1,21 +1,287 @@
+use cosmwasm_std::{Addr, Decimal, Uint128};
+use cw_storage_plus::{Item, Map};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use cosmwasm_std::{Addr, Uint128};
-use cw_storage_plus::{Item, Map};
+use std::fmt;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Config {
+    pub owner: Addr,
+    pub oracle: OracleConfig,
+    pub fees: FeeConfig,
+    pub utilization_thresholds: UtilizationThresholds,
+    pub liquidation: LiquidationConfig,
+    pub safety_module: SafetyModuleConfig,
+    pub dex: Option<DexConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BorrowAsset {
+    pub denom: String,
+    pub decimals: u8,
+    // most the pool accepts from liquidity providers, unlimited if not set
+    pub supply_cap: Option<Uint128>,
+    // most that can be borrowed from the pool at once, unlimited if not set
+    pub borrow_cap: Option<Uint128>,
+    // whether accounts in isolation mode may borrow it, meant for stable assets
+    pub isolation_borrowable: bool,
+    // price a stable asset is meant to hold, none for assets without a peg
+    pub peg: Option<PegConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PegConfig {
+    // price on peg, scaled like the oracle's prices
+    pub target: Uint128,
+    // share of the target the price may stray before new borrows of the asset are paused
+    pub max_deviation: Decimal,
+    // values debt at no less than the target, so a depeg never makes accounts look healthier
+    // than they were on peg
+    pub floor_debt_value: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct CollateralAsset {
+    pub denom: String,
+    // decimals of the token's base unit, used to value it against other assets
+    pub decimals: u8,
+    // share of the collateral value that can be borrowed against
+    pub max_ltv: Decimal,
+    // debt to collateral value ratio above which the account can be liquidated
+    pub liquidation_threshold: Decimal,
+    // discount on the collateral given to liquidators
+    pub liquidation_bonus: Decimal,
+    // most that can be deposited across all accounts, unlimited if not set
+    pub supply_cap: Option<Uint128>,
+    // setting a ceiling isolates the asset: it cannot be combined with other collateral and
+    // the debt it backs, in USD with 18 decimals, is capped across all accounts
+    pub debt_ceiling: Option<Uint128>,
+}
+
+// Correlated assets that get more favourable risk parameters when an account only holds them
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct EModeCategory {
+    pub id: u8,
+    pub label: String,
+    pub max_ltv: Decimal,
+    pub liquidation_threshold: Decimal,
+    pub liquidation_bonus: Decimal,
+    // denom whose price values every asset of the category, each asset's own price if not set
+    pub price_source: Option<String>,
+    // collateral and borrow denoms that belong to the category
+    pub assets: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct OracleConfig {
+    // addresses allowed to post prices for assets without a feed of their own
+    pub feeders: Vec<Addr>,
+    // fresh submissions needed before their median becomes the price
+    pub quorum: u32,
+    // feeders and quorum of individual assets
+    pub feeds: Vec<PriceFeed>,
+    // assets priced by querying another contract instead of the feeders
+    pub sources: Vec<AssetPriceSource>,
+    // prices are USD per whole token scaled by 10^price_decimals
+    pub price_decimals: u8,
+    // seconds a price can be used for after it is posted
+    pub max_age: u64,
+    // largest move from the last accepted price a single update may make. Outliers are
+    // quarantined until a later update lands close to them.
+    pub max_deviation: Option<Decimal>,
+    // seconds the time-weighted average price positions are valued at looks back over, zero to
+    // value them at the spot price
+    pub twap_window: u64,
+    // accepted prices kept per denom for the average
+    pub max_observations: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PriceFeed {
+    pub denom: String,
+    pub feeders: Vec<Addr>,
+    pub quorum: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema, Default)]
+pub struct Observations {
+    // accepted prices stamped with the time they took effect, the oldest at `next`
+    pub prices: Vec<Price>,
+    // slot the next observation is written to
+    pub next: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct AssetPriceSource {
+    pub denom: String,
+    pub source: PriceSource,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceSource {
+    // Pyth-style price feed contract
+    Pyth {
+        contract: Addr,
+        price_feed_id: String,
+    },
+    // Band-style standard reference contract, read against USD
+    Band {
+        contract: Addr,
+        symbol: String,
+    },
+    // time-weighted average price of a DEX pool against an asset with a price of its own
+    DexTwap {
+        contract: Addr,
+        quote_denom: String,
+        window: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Price {
+    // USD per whole token scaled by 10^price_decimals
+    pub price: Uint128,
+    // block time in seconds the price was posted
+    pub updated_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct FeeConfig {
+    // share of the interest paid by borrowers that is kept as protocol reserves
+    pub reserve_factor: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LiquidationConfig {
+    // seconds for the liquidation bonus to grow from zero to the collateral's maximum
+    pub auction_duration: u64,
+    // liquidation queue contract seized collateral can be sold into, if any
+    pub queue: Option<Addr>,
+    // largest shortfall of a queue sale's proceeds against the seized collateral's value net of
+    // the liquidation bonus, below 100%
+    pub max_queue_slippage: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SafetyModuleConfig {
+    // seconds between asking to unstake and being able to, at most a year
+    pub cooldown: u64,
+    // seconds after the cooldown during which a request can be carried out before it lapses, at
+    // most a year
+    pub unstake_window: u64,
+    // most of a safety module's stake a single bad debt can take, below 100%
+    pub max_slash: Decimal,
+    // share of the reserves accrued by a pool that goes to its stakers
+    pub reserve_share: Decimal,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct DexConfig {
+    // DEX contract swaps are routed through
+    pub contract: Addr,
+    // largest shortfall of a swap's output against its value at oracle prices, below 100%
+    pub max_slippage: Decimal,
+}
+
+// Stake backing a pool against bad debt after its reserves and insurance fund
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct SafetyModule {
+    pub pool_id: String,
+    // tokens staked plus rewards, minus slashing
+    pub total_staked: Uint128,
+    pub total_shares: Uint128,
+    // pool reserves when stakers were last paid their share
+    pub last_reserves: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Staker {
+    pub address: String,
+    pub pool_id: String,
+    pub shares: Uint128,
+    // shares that can be unstaked once the cooldown ends, still slashable until then
+    pub unstaking: Uint128,
+    // block time in seconds the cooldown ends, if one was started
+    pub cooldown_end: Option<u64>,
+}
+
+// Debt written off because the account had no collateral left to cover it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BadDebt {
+    pub id: u64,
+    pub borrower: String,
+    pub amount: Uint128,
+    // how the loss was absorbed, in this order
+    pub from_reserves: Uint128,
+    pub from_insurance: Uint128,
+    pub from_safety_module: Uint128,
+    // taken out of the liquidity providers' share price
+    pub socialized: Uint128,
+    // block time in seconds of the write-off
+    pub time: u64,
+}
+
+// Collateral sent to the liquidation queue, settled once the queue has paid for it
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingLiquidation {
+    pub borrower: String,
+    pub collateral_denom: String,
+    pub seized: Uint128,
+    pub debt_denom: String,
+    // contract balance of the debt denom before the sale
+    pub balance: Uint128,
+    pub min_proceeds: Uint128,
+}
+
+// Liquidation auction of an unhealthy account
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct LiquidationStart {
+    // block time in seconds the account was first seen unhealthy
+    pub started_at: u64,
+    // block time in seconds it was last seen unhealthy
+    pub observed_at: u64,
+}
+
+// Collateral bought through the DEX, deposited once the DEX has paid out
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct PendingSwap {
+    pub borrower: String,
+    pub collateral_denom: String,
+    // contract balance of the collateral denom before the swap
+    pub balance: Uint128,
+    pub min_output: Uint128,
+}
+
+// Utilization at or above which a pool is labelled medium or high
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct UtilizationThresholds {
+    pub medium: Decimal,
+    pub high: Decimal,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Account {
     pub address: String,
-    pub borrowed_usdc: Uint128,
+    // e-mode category the account's positions are valued under, if any
+    pub emode_category: Option<u8>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Debt {
+    pub principal: Uint128,
+    // pool borrow index at the time `principal` was last updated
+    pub borrow_index: Decimal,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct LiquidityProvider {
     pub address: String,
-    pub liquidity_amount: Uint128,
+    pub pool_id: String,
+    // claim on the pool's liquidity, which grows as interest is paid
+    pub shares: Uint128,
 }
 
-
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Collateral {
     pub address: String,
@@ -30,32 +296,81 @@ pub enum PoolUtilization {
     Low,
 }
 
-
-impl ToString for PoolUtilization {
-    fn to_string(&self) -> String {
+impl fmt::Display for PoolUtilization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            PoolUtilization::Low => "low".to_string(),
-            PoolUtilization::Medium => "medium".to_string(),
-            PoolUtilization::High => "high".to_string(),
+            PoolUtilization::Low => write!(f, "low"),
+            PoolUtilization::Medium => write!(f, "medium"),
+            PoolUtilization::High => write!(f, "high"),
         }
     }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 pub struct Pool {
-    pub id : String,
+    pub id: String,
     pub lend_token: String,
     pub borrow_token: String,
     pub utilization: PoolUtilization,
+    // borrowed share of everything supplied to the pool
+    pub utilization_rate: Decimal,
     pub total_liquidity: Uint128,
+    // shares issued to liquidity providers against total_liquidity
+    pub total_shares: Uint128,
     pub total_borrowed: Uint128,
+    // interest set aside for the protocol, not owed to liquidity providers
+    pub total_reserves: Uint128,
+    // annual interest rate charged to borrowers
+    pub borrow_rate: Decimal,
+    // cumulative interest factor since the pool was created
+    pub borrow_index: Decimal,
+    // block time in seconds of the last interest accrual
+    pub last_accrual: u64,
 }
 
-
-
-// Collaterals by token denomination
-pub const COLLATERAL: Item<Collateral> = Item::new("collateral");
+pub const CONFIG: Item<Config> = Item::new("config");
+pub const PENDING_LIQUIDATION: Item<PendingLiquidation> = Item::new("pending_liquidation");
+pub const PENDING_SWAP: Item<PendingSwap> = Item::new("pending_swap");
+// Assets that can be borrowed, each with its own pool, by token denomination
+pub const BORROW_ASSETS: Map<&str, BorrowAsset> = Map::new("borrow_assets");
+// Risk parameters of every accepted collateral, by token denomination
+pub const COLLATERAL_ASSETS: Map<&str, CollateralAsset> = Map::new("collateral_assets");
+// Liquidation auction of every account seen unhealthy, by account address
+pub const LIQUIDATION_STARTS: Map<&str, LiquidationStart> = Map::new("liquidation_starts");
+// E-mode categories by id
+pub const EMODE_CATEGORIES: Map<u8, EModeCategory> = Map::new("emode_categories");
+// Latest price submitted by each feeder, by token denomination and feeder
+pub const FEEDER_PRICES: Map<(&str, &Addr), Price> = Map::new("feeder_prices");
+// Latest median of the feeders' prices accepted, by token denomination
+pub const PRICES: Map<&str, Price> = Map::new("prices");
+// Ring buffer of recently accepted prices, by token denomination
+pub const OBSERVATIONS: Map<&str, Observations> = Map::new("observations");
+// Latest price held back for deviating too far from the accepted one, by token denomination
+pub const QUARANTINED_PRICES: Map<&str, Price> = Map::new("quarantined_prices");
+// Collateral amounts by (account address, token denomination)
+pub const COLLATERALS: Map<(&str, &str), Uint128> = Map::new("collaterals");
+// Collateral deposited across all accounts, by token denomination
+pub const TOTAL_COLLATERALS: Map<&str, Uint128> = Map::new("total_collaterals");
+// Debt backed by each isolated collateral, in USD with 18 decimals, by token denomination
+pub const ISOLATED_DEBT: Map<&str, Uint128> = Map::new("isolated_debt");
+// Isolated collateral whose debt ceiling an account's debt counts against, by account address.
+// It outlives the collateral, which liquidations can take before the debt is settled.
+pub const ISOLATED_ACCOUNTS: Map<&str, String> = Map::new("isolated_accounts");
+// Funds covering bad debt once a pool's reserves run out, by borrow denomination
+pub const INSURANCE_FUNDS: Map<&str, Uint128> = Map::new("insurance_funds");
+// Safety modules by pool id
+pub const SAFETY_MODULES: Map<&str, SafetyModule> = Map::new("safety_modules");
+// Stakes by (staker address, pool id)
+pub const STAKERS: Map<(&str, &str), Staker> = Map::new("stakers");
+// Bad debt written off by (pool id, bad debt id)
+pub const BAD_DEBTS: Map<(&str, u64), BadDebt> = Map::new("bad_debts");
+// Bad debt written off over a pool's lifetime, by pool id
+pub const TOTAL_BAD_DEBT: Map<&str, Uint128> = Map::new("total_bad_debt");
 pub const ACCOUNTS: Map<&str, Account> = Map::new("accounts");
-pub const ESCROW: Uint128 = Uint128::zero();
-pub const LIQUIDITY_PROVIDERS: Item<LiquidityProvider> = Item::new("liquidity_providers");
+// Debts by (account address, borrow denomination)
+pub const DEBTS: Map<(&str, &str), Debt> = Map::new("debts");
+// Liquidity positions by (provider address, pool id)
+pub const LIQUIDITY_PROVIDERS: Map<(&str, &str), LiquidityProvider> =
+    Map::new("liquidity_providers");
+// Pools by id, which is the denomination they lend out
 pub const POOLS: Map<&str, Pool> = Map::new("pools");
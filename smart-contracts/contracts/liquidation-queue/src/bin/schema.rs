@@ -0,0 +1,11 @@
+use cosmwasm_schema::write_api;
+
+use liquidation_queue::msg::{ExecuteMsg, InstantiateMsg};
+
+fn main() {
+    write_api! {
+        instantiate: InstantiateMsg,
+        execute: ExecuteMsg,
+
+    }
+}
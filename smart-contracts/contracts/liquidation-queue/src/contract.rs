@@ -0,0 +1,867 @@
+use crate::error::ContractError;
+use crate::msg::{BidPoolResponse, ConfigResponse, ExecuteMsg, InstantiateMsg, QueryMsg};
+use crate::state::{
+    Bid, BidPool, Config, BIDDER_BIDS, BIDS, BID_POOLS, CONFIG, EPOCH_SCALE_SUMS, NEXT_BID_ID,
+};
+#[cfg(not(feature = "library"))]
+use cosmwasm_std::entry_point;
+use cosmwasm_std::{
+    to_json_binary, BankMsg, Binary, Coin, Decimal, Decimal256, Deps, DepsMut, Empty, Env,
+    MessageInfo, Order, Response, StdError, StdResult, Storage, Uint128, Uint256,
+};
+use cw2::set_contract_version;
+use cw_storage_plus::Bound;
+
+// version info for migration info
+const CONTRACT_NAME: &str = "crates.io:liquidation-queue";
+const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// a premium of 100% would give the collateral away
+const MAX_PREMIUM_SLOT: u8 = 99;
+// most premium slots a single liquidation is filled from, which keeps its gas bounded
+const MAX_FILLED_SLOTS: usize = 10;
+// a slot's product is scaled up by this factor whenever it falls below its inverse
+const SCALE_FACTOR: u128 = 1_000_000_000;
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn instantiate(
+    deps: DepsMut,
+    _env: Env,
+    _info: MessageInfo,
+    msg: InstantiateMsg,
+) -> Result<Response, ContractError> {
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    let config = Config {
+        owner: deps.api.addr_validate(&msg.owner)?,
+        market: msg
+            .market
+            .map(|market| deps.api.addr_validate(&market))
+            .transpose()?,
+        stable_denom: msg.stable_denom,
+        max_premium_slot: validate_max_premium_slot(msg.max_premium_slot)?,
+        min_bid_amount: msg.min_bid_amount,
+    };
+    CONFIG.save(deps.storage, &config)?;
+    NEXT_BID_ID.save(deps.storage, &1)?;
+
+    Ok(Response::new()
+        .add_attribute("method", "instantiate")
+        .add_attribute("owner", config.owner))
+}
+
+fn validate_max_premium_slot(slot: u8) -> Result<u8, ContractError> {
+    if slot > MAX_PREMIUM_SLOT {
+        return Err(ContractError::InvalidConfig {
+            reason: "premium slots cannot reach 100%".to_string(),
+        });
+    }
+    Ok(slot)
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn execute(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    msg: ExecuteMsg,
+) -> Result<Response, ContractError> {
+    match msg {
+        ExecuteMsg::SubmitBid {
+            collateral_denom,
+            premium_slot,
+        } => execute::submit_bid(deps, info, collateral_denom, premium_slot),
+        ExecuteMsg::RetractBid { bid_id, amount } => {
+            execute::retract_bid(deps, info, bid_id, amount)
+        }
+        ExecuteMsg::ClaimLiquidations { collateral_denom } => {
+            execute::claim_liquidations(deps, info, collateral_denom)
+        }
+        ExecuteMsg::ExecuteLiquidation { price } => execute::execute_liquidation(deps, info, price),
+        ExecuteMsg::UpdateConfig {
+            owner,
+            market,
+            max_premium_slot,
+            min_bid_amount,
+        } => execute::update_config(deps, info, owner, market, max_premium_slot, min_bid_amount),
+    }
+}
+
+pub mod execute {
+    use super::*;
+
+    pub fn submit_bid(
+        deps: DepsMut,
+        info: MessageInfo,
+        collateral_denom: String,
+        premium_slot: u8,
+    ) -> Result<Response, ContractError> {
+        let config = CONFIG.load(deps.storage)?;
+        if premium_slot > config.max_premium_slot {
+            return Err(ContractError::InvalidPremiumSlot {
+                slot: premium_slot,
+                max: config.max_premium_slot,
+            });
+        }
+        let amount = match info.funds.as_slice() {
+            [] => return Err(ContractError::NoFunds {}),
+            [coin] if coin.denom == config.stable_denom && !coin.amount.is_zero() => coin.amount,
+            _ => return Err(ContractError::WrongToken {}),
+        };
+        if amount < config.min_bid_amount {
+            return Err(ContractError::BidTooSmall {
+                min: config.min_bid_amount,
+            });
+        }
+
+        let mut pool = load_bid_pool(deps.storage, &collateral_denom, premium_slot)?;
+        let id = NEXT_BID_ID.load(deps.storage)?;
+        NEXT_BID_ID.save(deps.storage, &(id + 1))?;
+        let bid = Bid {
+            id,
+            bidder: info.sender,
+            collateral_denom,
+            premium_slot,
+            amount,
+            pending_collateral: Uint128::zero(),
+            product_snapshot: pool.product,
+            sum_snapshot: pool.sum,
+            epoch_snapshot: pool.epoch,
+            scale_snapshot: pool.scale,
+        };
+        BIDS.save(deps.storage, id, &bid)?;
+        BIDDER_BIDS.save(deps.storage, (&bid.bidder, id), &Empty {})?;
+        pool.total_bid += amount;
+        BID_POOLS.save(deps.storage, (&bid.collateral_denom, premium_slot), &pool)?;
+
+        Ok(Response::new()
+            .add_attribute("method", "submit_bid")
+            .add_attribute("bidder", bid.bidder)
+            .add_attribute("bid_id", id.to_string())
+            .add_attribute("collateral_denom", bid.collateral_denom)
+            .add_attribute("premium_slot", premium_slot.to_string())
+            .add_attribute("amount", amount))
+    }
+
+    pub fn retract_bid(
+        deps: DepsMut,
+        info: MessageInfo,
+        bid_id: u64,
+        amount: Option<Uint128>,
+    ) -> Result<Response, ContractError> {
+        let config = CONFIG.load(deps.storage)?;
+        let mut bid = load_bid(deps.storage, bid_id)?;
+        if bid.bidder != info.sender {
+            return Err(ContractError::Unauthorized {});
+        }
+        let mut pool = load_bid_pool(deps.storage, &bid.collateral_denom, bid.premium_slot)?;
+        settle_bid(deps.storage, &mut bid, &pool)?;
+        let amount = amount.unwrap_or(bid.amount);
+        if amount.is_zero() || amount > bid.amount {
+            return Err(ContractError::InsufficientFunds {});
+        }
+
+        bid.amount -= amount;
+        pool.total_bid = pool.total_bid.checked_sub(amount).map_err(StdError::from)?;
+        BID_POOLS.save(
+            deps.storage,
+            (&bid.collateral_denom, bid.premium_slot),
+            &pool,
+        )?;
+        save_bid(deps.storage, &bid)?;
+
+        Ok(Response::new()
+            .add_message(BankMsg::Send {
+                to_address: info.sender.to_string(),
+                amount: vec![Coin {
+                    denom: config.stable_denom,
+                    amount,
+                }],
+            })
+            .add_attribute("method", "retract_bid")
+            .add_attribute("bidder", info.sender)
+            .add_attribute("bid_id", bid_id.to_string())
+            .add_attribute("amount", amount))
+    }
+
+    pub fn claim_liquidations(
+        deps: DepsMut,
+        info: MessageInfo,
+        collateral_denom: String,
+    ) -> Result<Response, ContractError> {
+        let ids = BIDDER_BIDS
+            .prefix(&info.sender)
+            .keys(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        let mut claimed = Uint128::zero();
+        for id in ids {
+            let mut bid = BIDS.load(deps.storage, id)?;
+            if bid.collateral_denom != collateral_denom {
+                continue;
+            }
+            let pool = load_bid_pool(deps.storage, &bid.collateral_denom, bid.premium_slot)?;
+            settle_bid(deps.storage, &mut bid, &pool)?;
+            claimed += bid.pending_collateral;
+            bid.pending_collateral = Uint128::zero();
+            save_bid(deps.storage, &bid)?;
+        }
+        if claimed.is_zero() {
+            return Err(ContractError::NothingToClaim {});
+        }
+
+        Ok(Response::new()
+            .add_message(BankMsg::Send {
+                to_address: info.sender.to_string(),
+                amount: vec![Coin {
+                    denom: collateral_denom.clone(),
+                    amount: claimed,
+                }],
+            })
+            .add_attribute("method", "claim_liquidations")
+            .add_attribute("bidder", info.sender)
+            .add_attribute("collateral_denom", collateral_denom)
+            .add_attribute("claimed", claimed))
+    }
+
+    pub fn execute_liquidation(
+        deps: DepsMut,
+        info: MessageInfo,
+        price: Decimal,
+    ) -> Result<Response, ContractError> {
+        let config = CONFIG.load(deps.storage)?;
+        if config.market.as_ref() != Some(&info.sender) {
+            return Err(ContractError::Unauthorized {});
+        }
+        let collateral = match info.funds.as_slice() {
+            [] => return Err(ContractError::NoFunds {}),
+            [coin] if coin.denom != config.stable_denom && !coin.amount.is_zero() => coin.clone(),
+            _ => return Err(ContractError::WrongToken {}),
+        };
+        if price.is_zero() {
+            return Err(ContractError::InvalidConfig {
+                reason: "collateral price cannot be zero".to_string(),
+            });
+        }
+
+        // lower premiums are filled first, each slot at once across all of its bids
+        let pools = BID_POOLS
+            .prefix(&collateral.denom)
+            .range(
+                deps.storage,
+                None,
+                Some(Bound::inclusive(config.max_premium_slot)),
+                Order::Ascending,
+            )
+            .filter(|item| !matches!(item, Ok((_, pool)) if pool.total_bid.is_zero()))
+            .take(MAX_FILLED_SLOTS)
+            .collect::<StdResult<Vec<_>>>()?;
+        let mut remaining = collateral.amount;
+        let mut proceeds = Uint128::zero();
+        for (slot, mut pool) in pools {
+            if remaining.is_zero() {
+                break;
+            }
+            let slot_price = price * (Decimal::one() - Decimal::percent(slot.into()));
+            let bought = pool
+                .total_bid
+                .checked_div_floor(slot_price)
+                .map_err(|_| ContractError::InsufficientBids {
+                    denom: collateral.denom.clone(),
+                })?
+                .min(remaining);
+            if bought.is_zero() {
+                continue;
+            }
+            // rounding up keeps the seller whole and never exceeds what the slot holds
+            let paid = bought.mul_ceil(slot_price).min(pool.total_bid);
+
+            remaining -= bought;
+            proceeds += paid;
+            fill_pool(
+                deps.storage,
+                &collateral.denom,
+                slot,
+                &mut pool,
+                paid,
+                bought,
+            )?;
+            BID_POOLS.save(deps.storage, (&collateral.denom, slot), &pool)?;
+        }
+        if !remaining.is_zero() {
+            return Err(ContractError::InsufficientBids {
+                denom: collateral.denom,
+            });
+        }
+
+        let mut res = Response::new()
+            .add_attribute("method", "execute_liquidation")
+            .add_attribute("collateral", collateral.to_string())
+            .add_attribute("proceeds", proceeds);
+        if !proceeds.is_zero() {
+            res = res.add_message(BankMsg::Send {
+                to_address: info.sender.to_string(),
+                amount: vec![Coin {
+                    denom: config.stable_denom,
+                    amount: proceeds,
+                }],
+            });
+        }
+        Ok(res)
+    }
+
+    pub fn update_config(
+        deps: DepsMut,
+        info: MessageInfo,
+        owner: Option<String>,
+        market: Option<String>,
+        max_premium_slot: Option<u8>,
+        min_bid_amount: Option<Uint128>,
+    ) -> Result<Response, ContractError> {
+        let mut config = CONFIG.load(deps.storage)?;
+        if info.sender != config.owner {
+            return Err(ContractError::Unauthorized {});
+        }
+
+        if let Some(owner) = owner {
+            config.owner = deps.api.addr_validate(&owner)?;
+        }
+        if let Some(market) = market {
+            config.market = Some(deps.api.addr_validate(&market)?);
+        }
+        // bids above a lowered maximum are no longer filled, so their bidders can only retract them
+        if let Some(slot) = max_premium_slot {
+            config.max_premium_slot = validate_max_premium_slot(slot)?;
+        }
+        // bids placed before a raised minimum stay as they are
+        if let Some(amount) = min_bid_amount {
+            config.min_bid_amount = amount;
+        }
+        CONFIG.save(deps.storage, &config)?;
+
+        Ok(Response::new().add_attribute("method", "update_config"))
+    }
+}
+
+fn load_bid(storage: &dyn Storage, id: u64) -> Result<Bid, ContractError> {
+    BIDS.may_load(storage, id)?
+        .ok_or(ContractError::BidNotFound { id })
+}
+
+// Stores the bid, dropping it once it is spent and claimed
+fn save_bid(storage: &mut dyn Storage, bid: &Bid) -> StdResult<()> {
+    if bid.amount.is_zero() && bid.pending_collateral.is_zero() {
+        BIDS.remove(storage, bid.id);
+        BIDDER_BIDS.remove(storage, (&bid.bidder, bid.id));
+        return Ok(());
+    }
+    BIDS.save(storage, bid.id, bid)
+}
+
+fn load_bid_pool(storage: &dyn Storage, denom: &str, slot: u8) -> StdResult<BidPool> {
+    Ok(BID_POOLS
+        .may_load(storage, (denom, slot))?
+        .unwrap_or_default())
+}
+
+// Spends `paid` of the slot's stable on `bought` collateral, shared by its bids pro rata
+fn fill_pool(
+    storage: &mut dyn Storage,
+    denom: &str,
+    slot: u8,
+    pool: &mut BidPool,
+    paid: Uint128,
+    bought: Uint128,
+) -> StdResult<()> {
+    let total = Uint256::from(pool.total_bid);
+    // collateral bought per unit of stable bid, measured against the current product
+    let gain = Uint256::from(bought).checked_mul(pool.product.atomics())? / total;
+    pool.sum = pool.sum.checked_add(Decimal256::new(gain))?;
+    let left = pool.total_bid - paid;
+    let product = pool.product.atomics().checked_mul(left.into())?;
+
+    pool.total_bid = left;
+    if product / total >= Uint256::from(SCALE_FACTOR) {
+        pool.product = Decimal256::new(product / total);
+        return Ok(());
+    }
+    EPOCH_SCALE_SUMS.save(storage, (denom, slot, (pool.epoch, pool.scale)), &pool.sum)?;
+    let scaled = product.checked_mul(SCALE_FACTOR.into())? / total;
+    if scaled.is_zero() {
+        // the bids are bought out, or too nearly to track, so the slot starts over. Any dust left
+        // goes to the bids of the next epoch.
+        *pool = BidPool {
+            total_bid: left,
+            epoch: pool.epoch + 1,
+            ..BidPool::default()
+        };
+        return Ok(());
+    }
+    pool.product = Decimal256::new(scaled);
+    pool.sum = Decimal256::zero();
+    pool.scale += 1;
+    Ok(())
+}
+
+// Brings the bid up to date with the fills of its slot since it was last settled, rounding what
+// it is owed down
+fn settle_bid(storage: &dyn Storage, bid: &mut Bid, pool: &BidPool) -> StdResult<()> {
+    let sum_at = |epoch: u64, scale: u64| -> StdResult<Decimal256> {
+        if (epoch, scale) == (pool.epoch, pool.scale) {
+            return Ok(pool.sum);
+        }
+        Ok(EPOCH_SCALE_SUMS
+            .may_load(
+                storage,
+                (&bid.collateral_denom, bid.premium_slot, (epoch, scale)),
+            )?
+            .unwrap_or_default())
+    };
+    let amount = Uint256::from(bid.amount);
+    let snapshot = bid.product_snapshot.atomics();
+
+    // fills past the next scale are too small next to the earlier ones to matter
+    let gain = sum_at(bid.epoch_snapshot, bid.scale_snapshot)?.atomics()
+        - bid.sum_snapshot.atomics()
+        + sum_at(bid.epoch_snapshot, bid.scale_snapshot + 1)?.atomics()
+            / Uint256::from(SCALE_FACTOR);
+    let bought = amount.checked_mul(gain)? / snapshot;
+    let remaining = if bid.epoch_snapshot != pool.epoch {
+        Uint256::zero()
+    } else {
+        match pool.scale - bid.scale_snapshot {
+            0 => amount.checked_mul(pool.product.atomics())? / snapshot,
+            1 => {
+                amount.checked_mul(pool.product.atomics())? / snapshot / Uint256::from(SCALE_FACTOR)
+            }
+            _ => Uint256::zero(),
+        }
+    };
+
+    bid.amount = remaining.try_into()?;
+    bid.pending_collateral += Uint128::try_from(bought)?;
+    bid.product_snapshot = pool.product;
+    bid.sum_snapshot = pool.sum;
+    bid.epoch_snapshot = pool.epoch;
+    bid.scale_snapshot = pool.scale;
+    Ok(())
+}
+
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, _env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        QueryMsg::GetConfig {} => to_json_binary(&query::get_config(deps)?),
+        QueryMsg::GetBid { bid_id } => to_json_binary(&query::get_bid(deps, bid_id)?),
+        QueryMsg::GetBidsByUser { bidder } => {
+            to_json_binary(&query::get_bids_by_user(deps, bidder)?)
+        }
+        QueryMsg::GetBidPools { collateral_denom } => {
+            to_json_binary(&query::get_bid_pools(deps, collateral_denom)?)
+        }
+    }
+}
+
+pub mod query {
+    use super::*;
+
+    pub fn get_config(deps: Deps) -> StdResult<ConfigResponse> {
+        let config = CONFIG.load(deps.storage)?;
+        Ok(ConfigResponse {
+            owner: config.owner,
+            market: config.market,
+            stable_denom: config.stable_denom,
+            max_premium_slot: config.max_premium_slot,
+            min_bid_amount: config.min_bid_amount,
+        })
+    }
+
+    pub fn get_bid(deps: Deps, bid_id: u64) -> StdResult<Bid> {
+        let mut bid = BIDS.load(deps.storage, bid_id)?;
+        let pool = load_bid_pool(deps.storage, &bid.collateral_denom, bid.premium_slot)?;
+        settle_bid(deps.storage, &mut bid, &pool)?;
+        Ok(bid)
+    }
+
+    pub fn get_bids_by_user(deps: Deps, bidder: String) -> StdResult<Vec<Bid>> {
+        let bidder = deps.api.addr_validate(&bidder)?;
+        BIDDER_BIDS
+            .prefix(&bidder)
+            .keys(deps.storage, None, None, Order::Ascending)
+            .map(|id| get_bid(deps, id?))
+            .collect()
+    }
+
+    pub fn get_bid_pools(deps: Deps, collateral_denom: String) -> StdResult<Vec<BidPoolResponse>> {
+        BID_POOLS
+            .prefix(&collateral_denom)
+            .range(deps.storage, None, None, Order::Ascending)
+            .filter(|item| !matches!(item, Ok((_, pool)) if pool.total_bid.is_zero()))
+            .map(|item| {
+                let (premium_slot, pool) = item?;
+                Ok(BidPoolResponse {
+                    premium_slot,
+                    total_bid: pool.total_bid,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cosmwasm_std::testing::{
+        mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage,
+    };
+    use cosmwasm_std::{coins, Addr, CosmosMsg, OwnedDeps};
+
+    const BIDDER1: &str = "archway1t00mqwm46hmvkgj4ysyh0ykyjln3yw2fvt92wj";
+    const BIDDER2: &str = "archway1ehuphj3j9ml5stwan46syfv8rj9uw49mm7a5vy";
+    const OWNER: &str = "archway1owner";
+    const MARKET: &str = "archway1market";
+
+    fn setup() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies();
+        let msg = InstantiateMsg {
+            owner: OWNER.to_string(),
+            market: Some(MARKET.to_string()),
+            stable_denom: "usdc".to_string(),
+            max_premium_slot: 30,
+            min_bid_amount: Uint128::new(10),
+        };
+        instantiate(deps.as_mut(), mock_env(), mock_info(OWNER, &[]), msg).unwrap();
+        deps
+    }
+
+    fn bid(deps: DepsMut, bidder: &str, amount: u128, premium_slot: u8) -> u64 {
+        let res = execute::submit_bid(
+            deps,
+            mock_info(bidder, &coins(amount, "usdc")),
+            "atom".to_string(),
+            premium_slot,
+        )
+        .unwrap();
+        res.attributes[2].value.parse().unwrap()
+    }
+
+    fn sent(msg: &CosmosMsg) -> (&str, Coin) {
+        match msg {
+            CosmosMsg::Bank(BankMsg::Send { to_address, amount }) => {
+                (to_address.as_str(), amount[0].clone())
+            }
+            msg => panic!("unexpected message: {:?}", msg),
+        }
+    }
+
+    #[test]
+    fn test_submit_and_retract_bid() {
+        let mut deps = setup();
+
+        let err = execute::submit_bid(
+            deps.as_mut(),
+            mock_info(BIDDER1, &coins(100, "usdc")),
+            "atom".to_string(),
+            31,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InvalidPremiumSlot { slot: 31, max: 30 } => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let err = execute::submit_bid(
+            deps.as_mut(),
+            mock_info(BIDDER1, &coins(100, "atom")),
+            "atom".to_string(),
+            5,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::WrongToken {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        let id = bid(deps.as_mut(), BIDDER1, 100, 5);
+        bid(deps.as_mut(), BIDDER2, 50, 5);
+        bid(deps.as_mut(), BIDDER2, 70, 2);
+        let pools = query::get_bid_pools(deps.as_ref(), "atom".to_string()).unwrap();
+        assert_eq!(
+            vec![
+                BidPoolResponse {
+                    premium_slot: 2,
+                    total_bid: Uint128::new(70),
+                },
+                BidPoolResponse {
+                    premium_slot: 5,
+                    total_bid: Uint128::new(150),
+                },
+            ],
+            pools
+        );
+
+        // only the bidder can take the bid back
+        let err =
+            execute::retract_bid(deps.as_mut(), mock_info(BIDDER2, &[]), id, None).unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        let res = execute::retract_bid(
+            deps.as_mut(),
+            mock_info(BIDDER1, &[]),
+            id,
+            Some(Uint128::new(40)),
+        )
+        .unwrap();
+        assert_eq!((BIDDER1, Coin::new(40, "usdc")), sent(&res.messages[0].msg));
+        assert_eq!(
+            Uint128::new(60),
+            BIDS.load(&deps.storage, id).unwrap().amount
+        );
+
+        // retracting the rest removes the bid altogether
+        execute::retract_bid(deps.as_mut(), mock_info(BIDDER1, &[]), id, None).unwrap();
+        assert!(!BIDS.has(&deps.storage, id));
+        let bids = query::get_bids_by_user(deps.as_ref(), BIDDER1.to_string()).unwrap();
+        assert!(bids.is_empty());
+        let pools = query::get_bid_pools(deps.as_ref(), "atom".to_string()).unwrap();
+        assert_eq!(Uint128::new(50), pools[1].total_bid);
+    }
+
+    #[test]
+    fn test_execute_liquidation() {
+        let mut deps = setup();
+        let cheap = bid(deps.as_mut(), BIDDER1, 100, 10);
+        let first = bid(deps.as_mut(), BIDDER2, 50, 2);
+        let second = bid(deps.as_mut(), BIDDER1, 100, 2);
+
+        let liquidate = |deps: DepsMut, sender: &str, amount: u128| {
+            execute::execute_liquidation(
+                deps,
+                mock_info(sender, &coins(amount, "atom")),
+                Decimal::percent(50),
+            )
+        };
+
+        // only the market sells into the queue
+        let err = liquidate(deps.as_mut(), BIDDER1, 100).unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // at a 2% premium atom costs $0.49, so the 2% slot buys all 200 atom for 98 usdc,
+        // rounded up, and its bids share the sale in proportion to their stable
+        let res = liquidate(deps.as_mut(), MARKET, 200).unwrap();
+        assert_eq!(("proceeds", "98"), res.attributes[2]);
+        assert_eq!((MARKET, Coin::new(98, "usdc")), sent(&res.messages[0].msg));
+
+        let first_bid = query::get_bid(deps.as_ref(), first).unwrap();
+        assert_eq!(Uint128::new(17), first_bid.amount);
+        assert_eq!(Uint128::new(66), first_bid.pending_collateral);
+        let second_bid = query::get_bid(deps.as_ref(), second).unwrap();
+        assert_eq!(Uint128::new(34), second_bid.amount);
+        assert_eq!(Uint128::new(133), second_bid.pending_collateral);
+        // the 10% bid is untouched while cheaper ones remain
+        let cheap_bid = query::get_bid(deps.as_ref(), cheap).unwrap();
+        assert_eq!(Uint128::new(100), cheap_bid.amount);
+        let pools = query::get_bid_pools(deps.as_ref(), "atom".to_string()).unwrap();
+        assert_eq!(Uint128::new(52), pools[0].total_bid);
+
+        let res =
+            execute::claim_liquidations(deps.as_mut(), mock_info(BIDDER2, &[]), "atom".to_string())
+                .unwrap();
+        assert_eq!((BIDDER2, Coin::new(66, "atom")), sent(&res.messages[0].msg));
+        let err =
+            execute::claim_liquidations(deps.as_mut(), mock_info(BIDDER2, &[]), "atom".to_string())
+                .unwrap_err();
+        match err {
+            ContractError::NothingToClaim {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // a bigger sale buys out the 2% slot and spills over into the 10% slot at $0.45
+        let res = liquidate(deps.as_mut(), MARKET, 150).unwrap();
+        assert_eq!(("proceeds", "72"), res.attributes[2]);
+        let pools = query::get_bid_pools(deps.as_ref(), "atom".to_string()).unwrap();
+        assert_eq!(1, pools.len());
+        let cheap_bid = query::get_bid(deps.as_ref(), cheap).unwrap();
+        assert_eq!(Uint128::new(80), cheap_bid.amount);
+        assert_eq!(Uint128::new(44), cheap_bid.pending_collateral);
+
+        // spent bids leave the queue once claimed
+        let res =
+            execute::claim_liquidations(deps.as_mut(), mock_info(BIDDER2, &[]), "atom".to_string())
+                .unwrap();
+        assert_eq!((BIDDER2, Coin::new(34, "atom")), sent(&res.messages[0].msg));
+        assert!(!BIDS.has(&deps.storage, first));
+        let res =
+            execute::claim_liquidations(deps.as_mut(), mock_info(BIDDER1, &[]), "atom".to_string())
+                .unwrap();
+        assert_eq!(
+            (BIDDER1, Coin::new(247, "atom")),
+            sent(&res.messages[0].msg)
+        );
+        assert!(!BIDS.has(&deps.storage, second));
+
+        // the 80 usdc left cannot buy 600 atom
+        let err = liquidate(deps.as_mut(), MARKET, 600).unwrap_err();
+        match err {
+            ContractError::InsufficientBids { denom } => assert_eq!("atom", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_update_config() {
+        let mut deps = setup();
+        let err = execute::update_config(
+            deps.as_mut(),
+            mock_info(BIDDER1, &[]),
+            None,
+            None,
+            None,
+            None,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::Unauthorized {} => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let err = execute::update_config(
+            deps.as_mut(),
+            mock_info(OWNER, &[]),
+            None,
+            None,
+            Some(100),
+            None,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InvalidConfig { .. } => {}
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        execute::update_config(
+            deps.as_mut(),
+            mock_info(OWNER, &[]),
+            None,
+            Some(BIDDER2.to_string()),
+            Some(20),
+            Some(Uint128::new(50)),
+        )
+        .unwrap();
+        let config = query::get_config(deps.as_ref()).unwrap();
+        assert_eq!(Some(Addr::unchecked(BIDDER2)), config.market);
+        assert_eq!(20, config.max_premium_slot);
+        assert_eq!(Uint128::new(50), config.min_bid_amount);
+    }
+
+    #[test]
+    fn test_bids_above_lowered_max_premium() {
+        let mut deps = setup();
+        let id = bid(deps.as_mut(), BIDDER1, 100, 25);
+        execute::update_config(
+            deps.as_mut(),
+            mock_info(OWNER, &[]),
+            None,
+            None,
+            Some(20),
+            None,
+        )
+        .unwrap();
+
+        // the 25% bid is out of reach of liquidations
+        let err = execute::execute_liquidation(
+            deps.as_mut(),
+            mock_info(MARKET, &coins(10, "atom")),
+            Decimal::percent(50),
+        )
+        .unwrap_err();
+        match err {
+            ContractError::InsufficientBids { denom } => assert_eq!("atom", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        assert_eq!(
+            Uint128::new(100),
+            BIDS.load(&deps.storage, id).unwrap().amount
+        );
+
+        let res = execute::retract_bid(deps.as_mut(), mock_info(BIDDER1, &[]), id, None).unwrap();
+        assert_eq!(
+            (BIDDER1, Coin::new(100, "usdc")),
+            sent(&res.messages[0].msg)
+        );
+    }
+
+    #[test]
+    fn test_min_bid_and_filled_slots() {
+        let mut deps = setup();
+        let err = execute::submit_bid(
+            deps.as_mut(),
+            mock_info(BIDDER1, &coins(9, "usdc")),
+            "atom".to_string(),
+            0,
+        )
+        .unwrap_err();
+        match err {
+            ContractError::BidTooSmall { min } => assert_eq!(Uint128::new(10), min),
+            e => panic!("unexpected error: {:?}", e),
+        }
+
+        // however many bids a slot holds it is filled in one step, and only the ten cheapest
+        // slots are reached. Their 200 usdc buy 205 atom at $1 less each slot's premium.
+        let queue = || {
+            let mut deps = setup();
+            for slot in 0..12 {
+                bid(deps.as_mut(), BIDDER1, 10, slot);
+                bid(deps.as_mut(), BIDDER2, 10, slot);
+            }
+            deps
+        };
+        let liquidate = |deps: DepsMut, amount: u128| {
+            execute::execute_liquidation(
+                deps,
+                mock_info(MARKET, &coins(amount, "atom")),
+                Decimal::one(),
+            )
+        };
+        let err = liquidate(queue().as_mut(), 206).unwrap_err();
+        match err {
+            ContractError::InsufficientBids { denom } => assert_eq!("atom", denom),
+            e => panic!("unexpected error: {:?}", e),
+        }
+        let mut deps = queue();
+        let res = liquidate(deps.as_mut(), 205).unwrap();
+        assert_eq!(("proceeds", "200"), res.attributes[2]);
+        let pools = query::get_bid_pools(deps.as_ref(), "atom".to_string()).unwrap();
+        assert_eq!(2, pools.len());
+        assert_eq!(10, pools[0].premium_slot);
+    }
+
+    #[test]
+    fn test_nearly_emptied_slot_stays_precise() {
+        let mut deps = setup();
+        let first = bid(deps.as_mut(), BIDDER1, 1_000_000_000_000, 0);
+        let liquidate = |deps: DepsMut, amount: u128| {
+            execute::execute_liquidation(
+                deps,
+                mock_info(MARKET, &coins(amount, "atom")),
+                Decimal::one(),
+            )
+            .unwrap();
+        };
+
+        // leaving a single unit of the bid drops the slot's product to a millionth of a
+        // billionth, so it is scaled up before it loses precision
+        liquidate(deps.as_mut(), 999_999_999_999);
+        let second = bid(deps.as_mut(), BIDDER2, 1_000_000_000_000, 0);
+        liquidate(deps.as_mut(), 1_000_000_000_000);
+
+        let first_bid = query::get_bid(deps.as_ref(), first).unwrap();
+        assert_eq!(Uint128::zero(), first_bid.amount);
+        assert_eq!(Uint128::new(999_999_999_999), first_bid.pending_collateral);
+        let second_bid = query::get_bid(deps.as_ref(), second).unwrap();
+        assert_eq!(Uint128::zero(), second_bid.amount);
+        assert_eq!(Uint128::new(999_999_999_999), second_bid.pending_collateral);
+    }
+}
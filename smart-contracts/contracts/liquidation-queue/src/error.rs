@@ -0,0 +1,38 @@
+use cosmwasm_std::{StdError, Uint128};
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum ContractError {
+    #[error("{0}")]
+    Std(#[from] StdError),
+
+    #[error("Unauthorized")]
+    Unauthorized {},
+
+    #[error("Insufficient funds")]
+    InsufficientFunds {},
+
+    #[error("Wrong token")]
+    WrongToken {},
+
+    #[error("No funds sent")]
+    NoFunds {},
+
+    #[error("Invalid config: {reason}")]
+    InvalidConfig { reason: String },
+
+    #[error("Bids have to be at least {min}")]
+    BidTooSmall { min: Uint128 },
+
+    #[error("Premium slot {slot} is above the maximum of {max}")]
+    InvalidPremiumSlot { slot: u8, max: u8 },
+
+    #[error("Bid {id} does not exist")]
+    BidNotFound { id: u64 },
+
+    #[error("Not enough bids to buy the {denom} on sale")]
+    InsufficientBids { denom: String },
+
+    #[error("No liquidated collateral to claim")]
+    NothingToClaim {},
+}
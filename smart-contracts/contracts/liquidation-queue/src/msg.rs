@@ -0,0 +1,73 @@
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{Addr, Decimal, Uint128};
+
+#[cw_serde]
+pub struct InstantiateMsg {
+    pub owner: String,
+    /// Can be set later, since the market usually needs the queue's address first
+    pub market: Option<String>,
+    pub stable_denom: String,
+    /// Bids pick a premium slot from zero up to this many percent
+    pub max_premium_slot: u8,
+    /// Smallest bid in stable base units
+    pub min_bid_amount: Uint128,
+}
+
+#[cw_serde]
+pub enum ExecuteMsg {
+    /// Bids the attached stable for `collateral_denom` at a discount of `premium_slot` percent
+    SubmitBid {
+        collateral_denom: String,
+        premium_slot: u8,
+    },
+    /// Takes back stable the bid has not spent yet, all of it if no amount is given
+    RetractBid {
+        bid_id: u64,
+        amount: Option<Uint128>,
+    },
+    /// Sends the sender the collateral their bids on `collateral_denom` bought
+    ClaimLiquidations { collateral_denom: String },
+    /// Market only. Sells the attached collateral to the lowest premium slots first, pro rata
+    /// across the bids in a slot, and pays the sender in stable. `price` is the stable base units
+    /// one base unit of collateral is worth. The sale fails if the ten cheapest slots with bids
+    /// cannot take all of it.
+    ExecuteLiquidation { price: Decimal },
+    /// Owner only
+    UpdateConfig {
+        owner: Option<String>,
+        market: Option<String>,
+        max_premium_slot: Option<u8>,
+        min_bid_amount: Option<Uint128>,
+    },
+}
+
+#[cw_serde]
+pub enum QueryMsg {
+    GetConfig {},
+    /// Bids are reported with what earlier liquidations took from and gave them
+    GetBid {
+        bid_id: u64,
+    },
+    GetBidsByUser {
+        bidder: String,
+    },
+    /// Stable waiting in every non-empty slot of a collateral, lowest premium first
+    GetBidPools {
+        collateral_denom: String,
+    },
+}
+
+#[cw_serde]
+pub struct ConfigResponse {
+    pub owner: Addr,
+    pub market: Option<Addr>,
+    pub stable_denom: String,
+    pub max_premium_slot: u8,
+    pub min_bid_amount: Uint128,
+}
+
+#[cw_serde]
+pub struct BidPoolResponse {
+    pub premium_slot: u8,
+    pub total_bid: Uint128,
+}
@@ -0,0 +1,71 @@
+use cosmwasm_std::{Addr, Decimal256, Empty, Uint128};
+use cw_storage_plus::{Item, Map};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Config {
+    pub owner: Addr,
+    // money market allowed to sell collateral into the queue, none until it is set up
+    pub market: Option<Addr>,
+    // denom bids are placed in and liquidations are paid out in
+    pub stable_denom: String,
+    // highest premium slot, each slot being a one percent discount on the collateral price
+    pub max_premium_slot: u8,
+    // smallest bid accepted, so the queue cannot be padded with dust
+    pub min_bid_amount: Uint128,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct Bid {
+    pub id: u64,
+    pub bidder: Addr,
+    pub collateral_denom: String,
+    pub premium_slot: u8,
+    // stable left to buy collateral with as of the snapshot below
+    pub amount: Uint128,
+    // collateral bought by the bid up to the snapshot that the bidder has not claimed yet
+    pub pending_collateral: Uint128,
+    // the slot's product, sum, epoch and scale when the bid was last settled
+    pub product_snapshot: Decimal256,
+    pub sum_snapshot: Decimal256,
+    pub epoch_snapshot: u64,
+    pub scale_snapshot: u64,
+}
+
+// Stable bid in a premium slot, filled pro rata across its bids. A bid's remaining stable is its
+// amount scaled by how far `product` fell since it was settled, and the collateral it bought is
+// its amount times the growth of `sum` over its product snapshot. A slot that is bought out
+// starts a new epoch, and `product` is scaled up in steps whenever it grows too small to be
+// precise.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
+pub struct BidPool {
+    pub total_bid: Uint128,
+    pub product: Decimal256,
+    pub sum: Decimal256,
+    pub epoch: u64,
+    pub scale: u64,
+}
+
+impl Default for BidPool {
+    fn default() -> Self {
+        BidPool {
+            total_bid: Uint128::zero(),
+            product: Decimal256::one(),
+            sum: Decimal256::zero(),
+            epoch: 0,
+            scale: 0,
+        }
+    }
+}
+
+pub const CONFIG: Item<Config> = Item::new("config");
+pub const NEXT_BID_ID: Item<u64> = Item::new("next_bid_id");
+pub const BIDS: Map<u64, Bid> = Map::new("bids");
+// Bids by (bidder, bid id)
+pub const BIDDER_BIDS: Map<(&Addr, u64), Empty> = Map::new("bidder_bids");
+// Stable waiting in each slot by (collateral denom, premium slot)
+pub const BID_POOLS: Map<(&str, u8), BidPool> = Map::new("bid_pools");
+// Sums of the epochs and scales a slot has moved past by (collateral denom, premium slot,
+// (epoch, scale))
+pub const EPOCH_SCALE_SUMS: Map<(&str, u8, (u64, u64)), Decimal256> = Map::new("epoch_scale_sums");